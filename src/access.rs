@@ -0,0 +1,155 @@
+use serde::{Deserialize, Deserializer};
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A range of addresses such as `10.0.0.0/8` or `fd00::/8`. A bare address is
+/// a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual stack sockets see IPv4 peers as IPv4-mapped IPv6 addresses
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None),
+        };
+
+        let network: IpAddr = network
+            .parse()
+            .map_err(|_| format!("invalid address in {:?}", s))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid prefix length in {:?}", s))?,
+            None => max_prefix,
+        };
+
+        // Peers are compared as IPv4 where they can be, see `contains`
+        match network.to_canonical() {
+            IpAddr::V4(mapped) if network.is_ipv6() => Ok(Self {
+                network: IpAddr::V4(mapped),
+                prefix: prefix
+                    .checked_sub(96)
+                    .ok_or_else(|| format!("prefix too short for an IPv4 range in {:?}", s))?,
+            }),
+            _ => Ok(Self { network, prefix }),
+        }
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let cidr = String::deserialize(deserializer)?;
+        cidr.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Which peers a service talks to. A peer in a `deny` range is rejected, as is
+/// one outside every `allow` range unless `allow` is empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessList {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip));
+        allowed && !self.deny.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(cidr("10.1.2.3/8").to_string(), "10.1.2.3/8");
+        assert_eq!(cidr("192.168.0.1").to_string(), "192.168.0.1/32");
+        assert_eq!(cidr("fd00::/8").to_string(), "fd00::/8");
+        assert_eq!(cidr("::ffff:10.0.0.0/104").to_string(), "10.0.0.0/8");
+
+        for invalid in [
+            "",
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "x/8",
+            "::ffff:10.0.0.0/64",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn contains_addresses() {
+        let lab = cidr("10.20.0.0/16");
+        assert!(lab.contains(ip("10.20.0.1")));
+        assert!(lab.contains(ip("10.20.255.255")));
+        assert!(!lab.contains(ip("10.21.0.1")));
+        assert!(lab.contains(ip("::ffff:10.20.3.4")));
+        assert!(!lab.contains(ip("fd00::1")));
+
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("fd00::/8").contains(ip("fd12:3456::1")));
+        assert!(!cidr("fd00::/8").contains(ip("fe80::1")));
+        assert!(cidr("::1").contains(ip("::1")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let everyone = AccessList::default();
+        assert!(everyone.permits(ip("203.0.113.9")));
+
+        let access = AccessList {
+            allow: vec![cidr("10.20.0.0/16"), cidr("::1")],
+            deny: vec![cidr("10.20.99.0/24")],
+        };
+        assert!(access.permits(ip("10.20.1.1")));
+        assert!(access.permits(ip("::1")));
+        assert!(!access.permits(ip("10.20.99.1")));
+        assert!(!access.permits(ip("127.0.0.1")));
+    }
+}
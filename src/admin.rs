@@ -0,0 +1,414 @@
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+use tracing_subscriber::{reload, EnvFilter};
+
+/// Longest command the admin interface will read.
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+
+const HELP: &str = "\
+connections [SERVICE]  list open connections
+sessions               list open LRCP sessions
+kill ID                close a connection or session as if the peer had gone away
+log [DIRECTIVES]       show or replace the log filter, e.g. info,protohackers_rs::line_reversal=debug
+help                   show this
+";
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::default);
+
+/// The connections and LRCP sessions open in the whole process.
+pub fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+#[derive(Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, Arc<Connection>>>,
+    sessions: Mutex<BTreeMap<u64, Arc<Session>>>,
+}
+
+/// A client connected to one of the stream services.
+#[derive(Debug)]
+pub struct Connection {
+    pub service: &'static str,
+    pub peer: SocketAddr,
+    pub started: Instant,
+    pub bytes_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub killed: CancellationToken,
+}
+
+/// An LRCP session, counted in the transport's own positions.
+#[derive(Debug)]
+pub struct Session {
+    pub session: u32,
+    pub peer: SocketAddr,
+    pub started: Instant,
+    pub bytes_received: AtomicU32,
+    pub bytes_sent: AtomicU32,
+    pub bytes_acked: AtomicU32,
+    pub killed: CancellationToken,
+}
+
+/// Something the registry lists.
+pub trait Entry: Sized {
+    fn entries(registry: &Registry) -> &Mutex<BTreeMap<u64, Arc<Self>>>;
+}
+
+impl Entry for Connection {
+    fn entries(registry: &Registry) -> &Mutex<BTreeMap<u64, Arc<Self>>> {
+        &registry.connections
+    }
+}
+
+impl Entry for Session {
+    fn entries(registry: &Registry) -> &Mutex<BTreeMap<u64, Arc<Self>>> {
+        &registry.sessions
+    }
+}
+
+/// Keeps an entry listed until it is dropped.
+pub struct Registered<T: Entry> {
+    registry: &'static Registry,
+    id: u64,
+    entry: Arc<T>,
+}
+
+impl<T: Entry> Registered<T> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl<T: Entry> Deref for Registered<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.entry
+    }
+}
+
+impl<T: Entry> Drop for Registered<T> {
+    fn drop(&mut self) {
+        T::entries(self.registry).lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Registry {
+    fn register<T: Entry>(&'static self, entry: T) -> Registered<T> {
+        // Ids are shared by connections and sessions, so `kill` needs no type
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Arc::new(entry);
+        T::entries(self).lock().unwrap().insert(id, entry.clone());
+
+        Registered {
+            registry: self,
+            id,
+            entry,
+        }
+    }
+
+    pub fn add_connection(
+        &'static self,
+        service: &'static str,
+        peer: SocketAddr,
+    ) -> Registered<Connection> {
+        self.register(Connection {
+            service,
+            peer,
+            started: Instant::now(),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            killed: CancellationToken::new(),
+        })
+    }
+
+    pub fn add_session(&'static self, session: u32, peer: SocketAddr) -> Registered<Session> {
+        self.register(Session {
+            session,
+            peer,
+            started: Instant::now(),
+            bytes_received: AtomicU32::new(0),
+            bytes_sent: AtomicU32::new(0),
+            bytes_acked: AtomicU32::new(0),
+            killed: CancellationToken::new(),
+        })
+    }
+
+    pub fn connections(&self) -> Vec<(u64, Arc<Connection>)> {
+        let connections = self.connections.lock().unwrap();
+        connections.iter().map(|(&id, c)| (id, c.clone())).collect()
+    }
+
+    pub fn sessions(&self) -> Vec<(u64, Arc<Session>)> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.iter().map(|(&id, s)| (id, s.clone())).collect()
+    }
+
+    /// Kills the connection or session with `id`, returning whether there was one.
+    pub fn kill(&self, id: u64) -> bool {
+        if let Some(connection) = self.connections.lock().unwrap().get(&id) {
+            connection.killed.cancel();
+            return true;
+        }
+        if let Some(session) = self.sessions.lock().unwrap().get(&id) {
+            session.killed.cancel();
+            return true;
+        }
+        false
+    }
+}
+
+/// The process's log filter, which the admin interface can swap at runtime.
+pub trait LogFilter: Send + Sync {
+    fn current(&self) -> String;
+
+    /// Replaces the whole filter with `directives`, in `RUST_LOG` syntax.
+    fn replace(&self, directives: &str) -> Result<()>;
+}
+
+impl<S: 'static> LogFilter for reload::Handle<EnvFilter, S> {
+    fn current(&self) -> String {
+        self.with_current(ToString::to_string).unwrap_or_default()
+    }
+
+    fn replace(&self, directives: &str) -> Result<()> {
+        self.reload(EnvFilter::try_new(directives)?)?;
+        Ok(())
+    }
+}
+
+/// Serves the admin line protocol: one command per line, answered with its
+/// output and then `ok` or `error: <reason>`. Anyone who can connect can kill
+/// connections, so bind it somewhere private.
+pub async fn serve(
+    listener: TcpListener,
+    log_filter: Arc<dyn LogFilter>,
+    shutdown: Shutdown,
+) -> Result<()> {
+    info!("Serving admin interface on {}...", listener.local_addr()?);
+
+    while let Some(accepted) = shutdown.or_cancel(listener.accept()).await {
+        let (stream, address) = accepted?;
+        let log_filter = log_filter.clone();
+        shutdown.spawn(move |shutdown| async move {
+            if let Err(e) = handle_connection(stream, &*log_filter, shutdown).await {
+                error!(peer = %address, "Admin connection error: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_connection<S>(
+    stream: S,
+    log_filter: &dyn LogFilter,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+
+    while let Some(num_bytes) = shutdown
+        .or_cancel((&mut reader).take(MAX_LINE_LENGTH).read_line(&mut line))
+        .await
+    {
+        if num_bytes? == 0 {
+            break;
+        }
+        if !line.ends_with('\n') && line.len() as u64 >= MAX_LINE_LENGTH {
+            anyhow::bail!("Command is over {} bytes", MAX_LINE_LENGTH);
+        }
+
+        let response = match run_command(line.trim(), log_filter) {
+            Ok(output) => output + "ok\n",
+            Err(e) => format!("error: {}\n", e),
+        };
+        writer.write_all(response.as_bytes()).await?;
+        line.clear();
+    }
+
+    Ok(())
+}
+
+/// Runs one command, returning its output.
+fn run_command(command: &str, log_filter: &dyn LogFilter) -> Result<String> {
+    match command.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => Ok(String::new()),
+        ["help"] => Ok(HELP.to_string()),
+        ["connections"] => Ok(list_connections(None)),
+        ["connections", service] => Ok(list_connections(Some(service))),
+        ["sessions"] => Ok(list_sessions()),
+
+        ["kill", id] => {
+            let id = id
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid id {:?}", id))?;
+            if !registry().kill(id) {
+                anyhow::bail!("no connection or session {}", id);
+            }
+            info!("Killed {} from the admin interface", id);
+            Ok(String::new())
+        }
+
+        ["log"] => Ok(format!("{}\n", log_filter.current())),
+        ["log", directives] => {
+            log_filter.replace(directives)?;
+            info!("Log filter is now {}", directives);
+            Ok(String::new())
+        }
+
+        _ => anyhow::bail!("unknown command {:?}, try help", command),
+    }
+}
+
+fn list_connections(service: Option<&str>) -> String {
+    let mut output = format!(
+        "{:<8}{:<20}{:<48}{:>10}{:>12}{:>12}\n",
+        "ID", "SERVICE", "PEER", "AGE", "RECEIVED", "SENT"
+    );
+    for (id, connection) in registry().connections() {
+        if service.is_some_and(|s| s != connection.service) {
+            continue;
+        }
+        let _ = writeln!(
+            output,
+            "{:<8}{:<20}{:<48}{:>10}{:>12}{:>12}",
+            id,
+            connection.service,
+            connection.peer,
+            age(connection.started.elapsed()),
+            connection.bytes_received.load(Ordering::Relaxed),
+            connection.bytes_sent.load(Ordering::Relaxed),
+        );
+    }
+    output
+}
+
+fn list_sessions() -> String {
+    let mut output = format!(
+        "{:<8}{:<12}{:<48}{:>10}{:>12}{:>12}{:>12}\n",
+        "ID", "SESSION", "PEER", "AGE", "RECEIVED", "SENT", "ACKED"
+    );
+    for (id, session) in registry().sessions() {
+        let _ = writeln!(
+            output,
+            "{:<8}{:<12}{:<48}{:>10}{:>12}{:>12}{:>12}",
+            id,
+            session.session,
+            session.peer,
+            age(session.started.elapsed()),
+            session.bytes_received.load(Ordering::Relaxed),
+            session.bytes_sent.load(Ordering::Relaxed),
+            session.bytes_acked.load(Ordering::Relaxed),
+        );
+    }
+    output
+}
+
+fn age(elapsed: Duration) -> String {
+    format!("{:.1}s", elapsed.as_secs_f64())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn entries_are_listed_until_dropped() {
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let connection = registry().add_connection("admin_list_test", peer);
+        let session = registry().add_session(12345, peer);
+        assert_ne!(connection.id(), session.id());
+
+        let listed = |id| registry().connections().iter().any(|(c, _)| *c == id);
+        assert!(listed(connection.id()));
+        assert!(list_sessions().contains("12345"));
+
+        assert!(registry().kill(session.id()));
+        assert!(session.killed.is_cancelled());
+        assert!(!connection.killed.is_cancelled());
+
+        let id = connection.id();
+        drop(connection);
+        assert!(!listed(id));
+        assert!(!registry().kill(id));
+    }
+
+    #[tokio::test]
+    async fn commands() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let (_filter, log_filter) =
+            reload::Layer::<_, tracing_subscriber::Registry>::new(EnvFilter::new("info"));
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(serve(listener, Arc::new(log_filter), shutdown.clone()));
+
+        let connection = registry().add_connection("admin_test", "127.0.0.1:1234".parse()?);
+        connection.bytes_received.store(42, Ordering::Relaxed);
+
+        let mut client = BufReader::new(TcpStream::connect(address).await?);
+        let mut command = async |command: &str| -> Result<Vec<String>> {
+            client
+                .write_all(format!("{}\n", command).as_bytes())
+                .await?;
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                client.read_line(&mut line).await?;
+                let done = line == "ok\n" || line.starts_with("error: ");
+                lines.push(line.trim_end().to_string());
+                if done {
+                    return Ok(lines);
+                }
+            }
+        };
+
+        let output = command("connections admin_test").await?;
+        assert_eq!(output.len(), 3, "{:?}", output);
+        assert!(output[0].starts_with("ID"));
+        assert!(output[1].starts_with(&connection.id().to_string()));
+        assert!(output[1].contains("127.0.0.1:1234"));
+        assert!(output[1].split_whitespace().any(|column| column == "42"));
+
+        let kill = format!("kill {}", connection.id());
+        assert_eq!(command(&kill).await?, ["ok"]);
+        assert!(connection.killed.is_cancelled());
+        assert_eq!(
+            command("kill 0").await?,
+            ["error: no connection or session 0"]
+        );
+
+        assert_eq!(command("log").await?, ["info", "ok"]);
+        assert_eq!(command("log warn,protohackers_rs=debug").await?, ["ok"]);
+        assert_eq!(command("log").await?, ["protohackers_rs=debug,warn", "ok"]);
+        assert!(command("log =[")
+            .await?
+            .last()
+            .unwrap()
+            .starts_with("error: "));
+
+        assert!(command("explode").await?[0].starts_with("error: unknown command"));
+
+        shutdown.trigger();
+        server.await?
+    }
+}
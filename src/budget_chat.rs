@@ -0,0 +1,287 @@
+use crate::limiter::{Limit, Limiter, Verdict};
+use crate::metrics::metrics;
+use crate::service::{serve, Listener, ServerHandle, Service, ServiceFuture, StreamListener};
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::ToSocketAddrs,
+    sync::broadcast,
+};
+use tracing::{error, info, instrument};
+
+const CHANNEL_SIZE: usize = 100;
+const MAX_LINE_LENGTH: usize = 1000;
+
+type UserId = usize;
+
+#[derive(Debug, Clone)]
+struct Event {
+    // The user that caused the event, who should not receive it
+    from: UserId,
+    text: String,
+}
+
+struct Room {
+    next_id: AtomicUsize,
+    users: Mutex<BTreeMap<UserId, String>>,
+    tx: broadcast::Sender<Event>,
+}
+
+impl Room {
+    fn new() -> Self {
+        let (tx, _rx) = broadcast::channel(CHANNEL_SIZE);
+        Self {
+            next_id: AtomicUsize::new(0),
+            users: Mutex::new(BTreeMap::new()),
+            tx,
+        }
+    }
+
+    /// Adds a user to the room, returning their id, the names of the users that
+    /// were already present and a receiver for everything that happens from now on.
+    fn join(&self, name: &str) -> (UserId, Vec<String>, broadcast::Receiver<Event>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut users = self.users.lock().unwrap();
+        let present = users.values().cloned().collect();
+        users.insert(id, name.to_string());
+
+        let rx = self.tx.subscribe();
+        self.broadcast(id, format!("* {} has entered the room", name));
+
+        (id, present, rx)
+    }
+
+    fn leave(&self, id: UserId) {
+        if let Some(name) = self.users.lock().unwrap().remove(&id) {
+            self.broadcast(id, format!("* {} has left the room", name));
+        }
+    }
+
+    fn broadcast(&self, from: UserId, text: String) {
+        // Sending only fails when nobody is listening, which is fine
+        let _ = self.tx.send(Event { from, text });
+    }
+}
+
+pub struct BudgetChat;
+
+impl Service for BudgetChat {
+    fn name(&self) -> &'static str {
+        "budget_chat"
+    }
+
+    fn default_port(&self) -> u16 {
+        10003
+    }
+
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        Box::pin(async move { run(listener.into_tcp()?, limiter, shutdown).await })
+    }
+}
+
+/// Starts the server on `bind` in the background.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&BudgetChat, bind).await
+}
+
+pub async fn run(
+    listener: StreamListener,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    info!(
+        "Running budget chat server on {}...",
+        listener.local_addr()?
+    );
+    let room = Arc::new(Room::new());
+
+    serve(
+        "budget_chat",
+        listener,
+        limiter,
+        shutdown,
+        move |stream, peer, limit, shutdown| {
+            handle_connection(stream, peer, room.clone(), limit, shutdown)
+        },
+    )
+    .await
+}
+
+#[instrument(parent = None, name = "connection", skip_all, fields(service = "budget_chat", %peer), err(Display))]
+async fn handle_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    room: Arc<Room>,
+    limit: Limit,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    info!("Accepted connection");
+    let (read_half, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(read_half);
+
+    writer
+        .write_all(b"Welcome to budgetchat! What shall I call you?\n")
+        .await?;
+
+    let mut buf = Vec::new();
+    let Some(name) = shutdown.or_cancel(read_line(&mut reader, &mut buf)).await else {
+        return Ok(());
+    };
+
+    let name = match name? {
+        Some(name) if is_valid_name(&name) => name,
+        name => {
+            info!("Rejecting name {:?}", name);
+            metrics().parse_error("budget_chat");
+            writer.write_all(b"* Illegal name, goodbye\n").await?;
+            return Ok(());
+        }
+    };
+
+    metrics().request("budget_chat", "join");
+    let (id, present, mut rx) = room.join(&name);
+    info!("{} joined as {}", name, id);
+    writer
+        .write_all(format!("* The room contains: {}\n", present.join(", ")).as_bytes())
+        .await?;
+
+    let result = chat(
+        id,
+        &name,
+        &room,
+        &mut reader,
+        &mut writer,
+        &mut rx,
+        &limit,
+        &shutdown,
+    )
+    .await;
+    room.leave(id);
+    info!("{} left", name);
+
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn chat<R, W>(
+    id: UserId,
+    name: &str,
+    room: &Room,
+    reader: &mut R,
+    writer: &mut W,
+    rx: &mut broadcast::Receiver<Event>,
+    limit: &Limit,
+    shutdown: &Shutdown,
+) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::new();
+    loop {
+        tokio::select! {
+            line = read_line(reader, &mut buf) => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+
+                match limit.message().await {
+                    Verdict::Allow => {}
+                    Verdict::Drop => continue,
+                    Verdict::Disconnect => return Ok(()),
+                }
+
+                metrics().request("budget_chat", "message");
+                room.broadcast(id, format!("[{}] {}", name, line));
+            }
+
+            event = rx.recv() => {
+                match event {
+                    Ok(event) if event.from != id => {
+                        writer.write_all(event.text.as_bytes()).await?;
+                        writer.write_u8(b'\n').await?;
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        error!("{} missed {} messages", name, n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                }
+            }
+
+            _ = shutdown.triggered() => return Ok(()),
+        }
+    }
+}
+
+/// Reads the next line, without its newline, or `None` at the end of the
+/// stream. Lines over `MAX_LINE_LENGTH` bytes are an error rather than being
+/// buffered whole. Safe to cancel, as the bytes read so far are kept in `buf`.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> Result<Option<String>> {
+    let limit = (MAX_LINE_LENGTH + 1).saturating_sub(buf.len()) as u64;
+    reader.take(limit).read_until(b'\n', buf).await?;
+    if buf.len() > MAX_LINE_LENGTH && !buf.ends_with(b"\n") {
+        anyhow::bail!("Line is over {} bytes", MAX_LINE_LENGTH);
+    }
+    if buf.is_empty() {
+        return Ok(None);
+    }
+
+    let mut line = String::from_utf8(std::mem::take(buf))?;
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn valid_names() {
+        assert!(is_valid_name("bob"));
+        assert!(is_valid_name("Alice1999"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("bob smith"));
+        assert!(!is_valid_name("bob!"));
+    }
+
+    #[test]
+    fn join_lists_present_users_and_announces() {
+        let room = Room::new();
+        let (alice, present, mut alice_rx) = room.join("alice");
+        assert!(present.is_empty());
+
+        let (bob, present, _bob_rx) = room.join("bob");
+        assert_eq!(present, vec!["alice".to_string()]);
+
+        let event = alice_rx.try_recv().unwrap();
+        assert_eq!(event.from, alice);
+        let event = alice_rx.try_recv().unwrap();
+        assert_eq!(event.from, bob);
+        assert_eq!(event.text, "* bob has entered the room");
+
+        room.leave(bob);
+        let event = alice_rx.try_recv().unwrap();
+        assert_eq!(event.text, "* bob has left the room");
+    }
+}
@@ -0,0 +1,238 @@
+use crate::limiter::{Limit, Limiter, Verdict};
+use crate::metrics::metrics;
+use crate::service::{serve, Listener, ServerHandle, Service, ServiceFuture, StreamListener};
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::ToSocketAddrs,
+};
+use tracing::{info, instrument};
+
+use self::{
+    command::Command,
+    storage::{Entry, Storage},
+};
+
+mod command;
+mod storage;
+
+/// Largest file a client may PUT. Anything bigger is refused before any of it
+/// is buffered.
+const MAX_FILE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CodeStorage {
+    pub max_file_size: usize,
+}
+
+impl Default for CodeStorage {
+    fn default() -> Self {
+        Self {
+            max_file_size: MAX_FILE_SIZE,
+        }
+    }
+}
+
+impl Service for CodeStorage {
+    fn name(&self) -> &'static str {
+        "code_storage"
+    }
+
+    fn default_port(&self) -> u16 {
+        10010
+    }
+
+    fn configure(&mut self, options: toml::Table) -> anyhow::Result<()> {
+        let options: Self = options.try_into()?;
+        anyhow::ensure!(
+            options.max_file_size > 0,
+            "max_file_size must be above zero"
+        );
+        *self = options;
+        Ok(())
+    }
+
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        let max_file_size = self.max_file_size;
+        Box::pin(async move { run(listener.into_tcp()?, max_file_size, limiter, shutdown).await })
+    }
+}
+
+/// Starts the server on `bind` in the background, with the default options.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&CodeStorage::default(), bind).await
+}
+
+pub async fn run(
+    listener: StreamListener,
+    max_file_size: usize,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    info!(
+        "Running code storage server on {}...",
+        listener.local_addr()?
+    );
+    let storage = Arc::new(Mutex::new(Storage::default()));
+
+    serve(
+        "code_storage",
+        listener,
+        limiter,
+        shutdown,
+        move |stream, peer, limit, shutdown| {
+            handle_connection(
+                stream,
+                peer,
+                storage.clone(),
+                max_file_size,
+                limit,
+                shutdown,
+            )
+        },
+    )
+    .await
+}
+
+#[instrument(parent = None, name = "connection", skip_all, fields(service = "code_storage", %peer), err(Display))]
+async fn handle_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    storage: Arc<Mutex<Storage>>,
+    max_file_size: usize,
+    limit: Limit,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    info!("Accepted connection");
+    let (read_half, mut writer) = tokio::io::split(stream);
+    // Commands are lines, but PUT is followed by a counted body, so both kinds
+    // of read go through the same buffer.
+    let mut reader = BufReader::new(read_half);
+    let mut line = Vec::new();
+
+    loop {
+        writer.write_all(b"READY\n").await?;
+
+        line.clear();
+        match shutdown
+            .or_cancel(reader.read_until(b'\n', &mut line))
+            .await
+        {
+            None | Some(Ok(0)) => return Ok(()),
+            Some(result) => result?,
+        };
+
+        let line = String::from_utf8_lossy(&line);
+        info!("Received {:?}", line.trim_end());
+
+        let command = match Command::parse(&line) {
+            Ok(command) => command,
+            Err(e) => {
+                metrics().parse_error("code_storage");
+                writer.write_all(format!("ERR {}\n", e).as_bytes()).await?;
+                if e.is_fatal() {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+
+        // Neither buffered nor read past, so the connection can't go on
+        if let Command::Put { length, .. } = &command {
+            if *length > max_file_size {
+                info!("Refusing a {} byte file", length);
+                writer.write_all(b"ERR file too large\n").await?;
+                return Ok(());
+            }
+        }
+
+        match limit.message().await {
+            Verdict::Allow => {}
+            Verdict::Drop => {
+                // A dropped PUT's body still has to be read past
+                if let Command::Put { length, .. } = &command {
+                    let mut body = (&mut reader).take(*length as u64);
+                    tokio::io::copy(&mut body, &mut tokio::io::sink()).await?;
+                }
+                continue;
+            }
+            Verdict::Disconnect => return Ok(()),
+        }
+
+        metrics().request("code_storage", command.method());
+        let response = match command {
+            Command::Help => b"OK usage: HELP|GET|PUT|LIST\n".to_vec(),
+
+            Command::Put { file, length } => {
+                let mut data = vec![0u8; length];
+                reader.read_exact(&mut data).await?;
+
+                if data.iter().all(|b| is_text(*b)) {
+                    let revision = storage.lock().unwrap().put(&file, data);
+                    format!("OK r{}\n", revision).into_bytes()
+                } else {
+                    b"ERR text files only\n".to_vec()
+                }
+            }
+
+            Command::Get { file, revision } => get(&storage.lock().unwrap(), &file, revision),
+
+            Command::List { dir } => {
+                let entries = storage.lock().unwrap().list(&dir);
+                let mut response = format!("OK {}\n", entries.len());
+                for entry in entries {
+                    match entry {
+                        Entry::File { name, revision } => {
+                            response.push_str(&format!("{} r{}\n", name, revision))
+                        }
+                        Entry::Dir { name } => response.push_str(&format!("{} DIR\n", name)),
+                    }
+                }
+                response.into_bytes()
+            }
+        };
+
+        writer.write_all(&response).await?;
+    }
+}
+
+fn get(storage: &Storage, file: &str, revision: Option<String>) -> Vec<u8> {
+    if !storage.exists(file) {
+        return b"ERR no such file\n".to_vec();
+    }
+
+    let revision = match revision {
+        None => None,
+        Some(revision) => {
+            match revision
+                .strip_prefix('r')
+                .unwrap_or(&revision)
+                .parse::<usize>()
+            {
+                Ok(revision) => Some(revision),
+                Err(_) => return b"ERR no such revision\n".to_vec(),
+            }
+        }
+    };
+
+    match storage.get(file, revision) {
+        Some(data) => {
+            let mut response = format!("OK {}\n", data.len()).into_bytes();
+            response.extend_from_slice(data);
+            response
+        }
+        None => b"ERR no such revision\n".to_vec(),
+    }
+}
+
+fn is_text(byte: u8) -> bool {
+    byte.is_ascii_graphic() || matches!(byte, b' ' | b'\n' | b'\t' | b'\r')
+}
@@ -0,0 +1,195 @@
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Put {
+        file: String,
+        length: usize,
+    },
+    Get {
+        file: String,
+        revision: Option<String>,
+    },
+    List {
+        dir: String,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Usage(&'static str),
+    IllegalMethod(String),
+    IllegalFileName,
+    IllegalDirName,
+}
+
+impl CommandError {
+    /// Whether the connection has to be closed after reporting the error.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, CommandError::IllegalMethod(_))
+    }
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::IllegalMethod(method) => write!(f, "illegal method: {}", method),
+            CommandError::IllegalFileName => write!(f, "illegal file name"),
+            CommandError::IllegalDirName => write!(f, "illegal dir name"),
+        }
+    }
+}
+
+const PUT_USAGE: &str = "PUT file length newline data";
+const GET_USAGE: &str = "GET file [revision]";
+const LIST_USAGE: &str = "LIST dir";
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, CommandError> {
+        let mut words = line.split_whitespace();
+        let method = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+
+        match method.to_ascii_uppercase().as_str() {
+            "HELP" => Ok(Command::Help),
+
+            "PUT" => match args[..] {
+                [file, length] => {
+                    let length = length.parse().map_err(|_| CommandError::Usage(PUT_USAGE))?;
+                    if !is_valid_file_name(file) {
+                        return Err(CommandError::IllegalFileName);
+                    }
+                    Ok(Command::Put {
+                        file: file.to_string(),
+                        length,
+                    })
+                }
+                _ => Err(CommandError::Usage(PUT_USAGE)),
+            },
+
+            "GET" => match args[..] {
+                [file] | [file, _] => {
+                    if !is_valid_file_name(file) {
+                        return Err(CommandError::IllegalFileName);
+                    }
+                    Ok(Command::Get {
+                        file: file.to_string(),
+                        revision: args.get(1).map(|r| r.to_string()),
+                    })
+                }
+                _ => Err(CommandError::Usage(GET_USAGE)),
+            },
+
+            "LIST" => match args[..] {
+                [dir] => {
+                    if !is_valid_dir_name(dir) {
+                        return Err(CommandError::IllegalDirName);
+                    }
+                    Ok(Command::List {
+                        dir: dir.to_string(),
+                    })
+                }
+                _ => Err(CommandError::Usage(LIST_USAGE)),
+            },
+
+            _ => Err(CommandError::IllegalMethod(method.to_string())),
+        }
+    }
+
+    /// The command's name, for metrics.
+    pub fn method(&self) -> &'static str {
+        match self {
+            Command::Help => "HELP",
+            Command::Put { .. } => "PUT",
+            Command::Get { .. } => "GET",
+            Command::List { .. } => "LIST",
+        }
+    }
+}
+
+fn is_valid_file_name(name: &str) -> bool {
+    is_valid_dir_name(name) && !name.ends_with('/')
+}
+
+fn is_valid_dir_name(name: &str) -> bool {
+    name.starts_with('/')
+        && !name.contains("//")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("help"), Ok(Command::Help));
+        assert_eq!(
+            Command::parse("PUT /test.txt 14"),
+            Ok(Command::Put {
+                file: "/test.txt".to_string(),
+                length: 14
+            })
+        );
+        assert_eq!(
+            Command::parse("get /test.txt r2"),
+            Ok(Command::Get {
+                file: "/test.txt".to_string(),
+                revision: Some("r2".to_string())
+            })
+        );
+        assert_eq!(
+            Command::parse("LIST /"),
+            Ok(Command::List {
+                dir: "/".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            Command::parse("PUT /test.txt").unwrap_err().to_string(),
+            "usage: PUT file length newline data"
+        );
+        assert_eq!(
+            Command::parse("GET").unwrap_err().to_string(),
+            "usage: GET file [revision]"
+        );
+        assert_eq!(
+            Command::parse("LIST / /").unwrap_err().to_string(),
+            "usage: LIST dir"
+        );
+        assert_eq!(
+            Command::parse("PUT test.txt 1"),
+            Err(CommandError::IllegalFileName)
+        );
+        assert_eq!(
+            Command::parse("LIST /a*b"),
+            Err(CommandError::IllegalDirName)
+        );
+
+        let error = Command::parse("DELETE /test.txt").unwrap_err();
+        assert!(error.is_fatal());
+        assert_eq!(error.to_string(), "illegal method: DELETE");
+    }
+
+    #[test]
+    fn file_names() {
+        for name in ["/a", "/a/b.txt", "/A-Z_0.9", "/..."] {
+            assert!(is_valid_file_name(name), "{}", name);
+        }
+
+        for name in ["", "a", "/", "/a/", "//a", "/a//b", "/a b", "/a*", "/ä"] {
+            assert!(!is_valid_file_name(name), "{}", name);
+        }
+
+        assert!(is_valid_dir_name("/"));
+        assert!(is_valid_dir_name("/a/"));
+        assert!(!is_valid_dir_name("a/"));
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+
+/// Every revision of every file, keyed by full path. Directories only exist
+/// implicitly as the prefixes of file paths.
+#[derive(Default)]
+pub struct Storage {
+    files: BTreeMap<String, Vec<Vec<u8>>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    File { name: String, revision: usize },
+    Dir { name: String },
+}
+
+impl Storage {
+    /// Stores a new revision of the file and returns its number. Storing the
+    /// same content as the latest revision does not create a new one.
+    pub fn put(&mut self, file: &str, data: Vec<u8>) -> usize {
+        let revisions = self.files.entry(file.to_string()).or_default();
+        if revisions.last() != Some(&data) {
+            revisions.push(data);
+        }
+        revisions.len()
+    }
+
+    pub fn get(&self, file: &str, revision: Option<usize>) -> Option<&[u8]> {
+        let revisions = self.files.get(file)?;
+        let revision = revision.unwrap_or(revisions.len());
+        revisions
+            .get(revision.checked_sub(1)?)
+            .map(|data| data.as_slice())
+    }
+
+    pub fn exists(&self, file: &str) -> bool {
+        self.files.contains_key(file)
+    }
+
+    /// Lists the files and directories directly inside `dir`, sorted by name.
+    pub fn list(&self, dir: &str) -> Vec<Entry> {
+        let prefix = match dir.ends_with('/') {
+            true => dir.to_string(),
+            false => format!("{}/", dir),
+        };
+
+        let mut entries: BTreeMap<String, Entry> = BTreeMap::new();
+        for (path, revisions) in self.files.range(prefix.clone()..) {
+            let Some(rest) = path.strip_prefix(&prefix) else {
+                break;
+            };
+
+            match rest.split_once('/') {
+                Some((name, _)) => {
+                    let name = format!("{}/", name);
+                    entries.insert(name.clone(), Entry::Dir { name });
+                }
+                None => {
+                    entries.insert(
+                        rest.to_string(),
+                        Entry::File {
+                            name: rest.to_string(),
+                            revision: revisions.len(),
+                        },
+                    );
+                }
+            }
+        }
+
+        entries.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn revisions() {
+        let mut storage = Storage::default();
+        assert_eq!(storage.put("/a", b"one\n".to_vec()), 1);
+        assert_eq!(storage.put("/a", b"two\n".to_vec()), 2);
+        assert_eq!(storage.put("/a", b"two\n".to_vec()), 2);
+
+        assert_eq!(storage.get("/a", None), Some(b"two\n".as_slice()));
+        assert_eq!(storage.get("/a", Some(1)), Some(b"one\n".as_slice()));
+        assert_eq!(storage.get("/a", Some(0)), None);
+        assert_eq!(storage.get("/a", Some(3)), None);
+        assert_eq!(storage.get("/b", None), None);
+    }
+
+    #[test]
+    fn list_directories() {
+        let mut storage = Storage::default();
+        storage.put("/test.txt", b"hi\n".to_vec());
+        storage.put("/test.txt", b"hello\n".to_vec());
+        storage.put("/dir/a", b"a\n".to_vec());
+        storage.put("/dir/sub/b", b"b\n".to_vec());
+        storage.put("/dirt", b"c\n".to_vec());
+
+        assert_eq!(
+            storage.list("/"),
+            vec![
+                Entry::Dir {
+                    name: "dir/".to_string()
+                },
+                Entry::File {
+                    name: "dirt".to_string(),
+                    revision: 1
+                },
+                Entry::File {
+                    name: "test.txt".to_string(),
+                    revision: 2
+                },
+            ]
+        );
+
+        assert_eq!(storage.list("/dir"), storage.list("/dir/"));
+        assert_eq!(
+            storage.list("/dir"),
+            vec![
+                Entry::File {
+                    name: "a".to_string(),
+                    revision: 1
+                },
+                Entry::Dir {
+                    name: "sub/".to_string()
+                },
+            ]
+        );
+        assert!(storage.list("/nothing").is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+use crate::access::Cidr;
+use crate::limiter::Limits;
+use crate::supervisor::RestartPolicy;
+use crate::tls::TlsConfig;
+use anyhow::Context;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+
+/// How long connections get to finish after a shutdown signal.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The contents of `protohackers.toml`. Top level settings apply to every
+/// service, and each `[services.<name>]` table can override them.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: Option<IpAddr>,
+    #[serde(deserialize_with = "level")]
+    pub log_level: Option<LevelFilter>,
+    pub log_format: LogFormat,
+    #[serde(deserialize_with = "seconds")]
+    pub drain_timeout: Duration,
+    pub restart: RestartPolicy,
+    pub limits: Limits,
+    /// Address ranges allowed to connect, everyone if empty.
+    pub allow: Vec<Cidr>,
+    /// Address ranges turned away, even if allowed.
+    pub deny: Vec<Cidr>,
+    /// Whether TCP services sit behind a load balancer sending the PROXY protocol.
+    pub proxy_protocol: bool,
+    /// Where to serve Prometheus metrics over HTTP, if anywhere.
+    pub metrics: Option<SocketAddr>,
+    /// Where to serve the admin line protocol, if anywhere.
+    pub admin: Option<SocketAddr>,
+    pub services: BTreeMap<String, ServiceConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: None,
+            log_level: None,
+            log_format: LogFormat::default(),
+            drain_timeout: DRAIN_TIMEOUT,
+            restart: RestartPolicy::default(),
+            limits: Limits::default(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            proxy_protocol: false,
+            metrics: None,
+            admin: None,
+            services: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ServiceConfig {
+    pub enabled: bool,
+    pub bind: Option<IpAddr>,
+    pub port: Option<u16>,
+    #[serde(deserialize_with = "level")]
+    pub log_level: Option<LevelFilter>,
+    /// Replaces the top level limits as a whole.
+    pub limits: Option<Limits>,
+    /// Replace the top level lists.
+    pub allow: Option<Vec<Cidr>>,
+    pub deny: Option<Vec<Cidr>>,
+    pub proxy_protocol: Option<bool>,
+    /// Terminates TLS for a TCP service.
+    pub tls: Option<TlsConfig>,
+    /// Also listens on a Unix socket at this path.
+    pub unix_socket: Option<PathBuf>,
+    /// Everything else is specific to the service, see `Service::configure`.
+    #[serde(flatten)]
+    pub options: toml::Table,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            bind: None,
+            port: None,
+            log_level: None,
+            limits: None,
+            allow: None,
+            deny: None,
+            proxy_protocol: None,
+            tls: None,
+            unix_socket: None,
+            options: toml::Table::new(),
+        }
+    }
+}
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of every enclosing span
+    Json,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+/// Reads a log level such as `"info"`.
+fn level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<LevelFilter>, D::Error> {
+    let level = String::deserialize(deserializer)?;
+    level.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Reads a duration given as a number of seconds.
+pub fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let seconds = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(seconds).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::limiter::Action;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            r#"
+            bind = "127.0.0.1"
+            log_level = "warn"
+            log_format = "json"
+            drain_timeout = 30
+            metrics = "127.0.0.1:9100"
+            admin = "127.0.0.1:9101"
+            deny = ["192.0.2.0/24", "2001:db8::/32"]
+            proxy_protocol = true
+
+            [restart]
+            initial_backoff = 0.5
+            max_failures = 3
+
+            [limits]
+            max_connections = 10
+            messages_per_second = 100
+
+            [services.smoke_test]
+            enabled = false
+            proxy_protocol = false
+            tls = { cert = "certs/smoke_test.pem", key = "certs/smoke_test.key" }
+
+            [services.line_reversal]
+            port = 4000
+            log_level = "DEBUG"
+            retransmission_timeout = 0.5
+            allow = ["10.20.0.0/16"]
+            unix_socket = "/run/protohackers/line_reversal.sock"
+
+            [services.line_reversal.limits]
+            messages_per_second = 1000
+            action = "drop"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.bind, Some("127.0.0.1".parse().unwrap()));
+        assert_eq!(config.log_level, Some(LevelFilter::WARN));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.drain_timeout, Duration::from_secs(30));
+        assert_eq!(config.metrics, Some("127.0.0.1:9100".parse().unwrap()));
+        assert_eq!(config.admin, Some("127.0.0.1:9101".parse().unwrap()));
+        assert!(config.allow.is_empty());
+        assert_eq!(config.deny.len(), 2);
+        assert_eq!(config.deny[1].to_string(), "2001:db8::/32");
+        assert!(config.proxy_protocol);
+        assert_eq!(config.restart.initial_backoff, Duration::from_millis(500));
+        assert_eq!(config.restart.max_backoff, Duration::from_secs(60));
+        assert_eq!(config.restart.max_failures, 3);
+        assert_eq!(config.limits.max_connections, Some(10));
+        assert_eq!(config.limits.messages_per_second, Some(100.0));
+        assert_eq!(config.limits.action, Action::Delay);
+        assert!(!config.services["smoke_test"].enabled);
+        assert_eq!(config.services["smoke_test"].proxy_protocol, Some(false));
+        let tls = config.services["smoke_test"].tls.as_ref().unwrap();
+        assert_eq!(tls.cert, Path::new("certs/smoke_test.pem"));
+        assert_eq!(tls.key, Path::new("certs/smoke_test.key"));
+
+        let line_reversal = &config.services["line_reversal"];
+        assert!(line_reversal.enabled);
+        assert_eq!(line_reversal.port, Some(4000));
+        assert_eq!(line_reversal.log_level, Some(LevelFilter::DEBUG));
+        let limits = line_reversal.limits.unwrap();
+        assert_eq!(limits.max_connections, None);
+        assert_eq!(limits.action, Action::Drop);
+        assert!(!line_reversal.options.contains_key("limits"));
+        assert_eq!(
+            line_reversal.allow,
+            Some(vec!["10.20.0.0/16".parse().unwrap()])
+        );
+        assert_eq!(line_reversal.deny, None);
+        assert_eq!(line_reversal.tls, None);
+        assert_eq!(
+            line_reversal.unix_socket.as_deref(),
+            Some(Path::new("/run/protohackers/line_reversal.sock"))
+        );
+        assert_eq!(
+            line_reversal.options.get("retransmission_timeout"),
+            Some(&toml::Value::Float(0.5))
+        );
+    }
+
+    #[test]
+    fn invalid_config() {
+        assert!(Config::parse("port = 4000").is_err());
+        assert!(Config::parse("[restart]\nmax_restarts = 3").is_err());
+        assert!(Config::parse("[services.smoke_test]\nport = 100000").is_err());
+        assert!(Config::parse("[services.smoke_test]\nlog_level = \"loud\"").is_err());
+        assert!(Config::parse("[limits]\naction = \"ignore\"").is_err());
+        assert!(Config::parse("[limits]\nmax_messages = 10").is_err());
+        assert!(Config::parse("[limits]\nmessages_per_second = 0").is_err());
+        assert!(Config::parse("[limits]\nmessages_per_second = -1.0").is_err());
+        assert!(Config::parse("[limits]\nmessages_per_second = nan").is_err());
+        assert!(Config::parse("[limits]\nmessages_per_second = inf").is_err());
+        assert!(Config::parse("[limits]\nburst = 0.5").is_err());
+        assert!(Config::parse("[limits]\nburst = inf").is_err());
+        assert!(Config::parse("[services.smoke_test.limits]\nburst = nan").is_err());
+        assert!(Config::parse("allow = [\"10.0.0.0/40\"]").is_err());
+        assert!(Config::parse("[services.smoke_test]\ndeny = \"10.0.0.0/8\"").is_err());
+        assert!(Config::parse("[services.smoke_test]\ntls = { cert = \"cert.pem\" }").is_err());
+    }
+}
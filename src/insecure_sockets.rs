@@ -1,34 +1,94 @@
-mod protocol;
-mod session;
+use crate::limiter::{Limit, Limiter, Verdict};
+use crate::metrics::metrics;
+use crate::service::{serve, Listener, ServerHandle, Service, ServiceFuture, StreamListener};
+use crate::shutdown::Shutdown;
 use anyhow::Result;
-use log::info;
 use std::net::SocketAddr;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::ToSocketAddrs;
+use tracing::{info, instrument};
+
+pub use self::stream::CipherStream;
+
+mod protocol;
+mod session;
+mod stream;
 
-pub async fn run(port: &str) -> anyhow::Result<()> {
-    let addr = format!("0.0.0.0:{}", port);
-    info!("Running insecure sockets server on {}...", &addr);
-    let listener = TcpListener::bind(&addr).await?;
+pub struct InsecureSockets;
+
+impl Service for InsecureSockets {
+    fn name(&self) -> &'static str {
+        "insecure_sockets"
+    }
 
-    loop {
-        let (stream, address) = listener.accept().await?;
+    fn default_port(&self) -> u16 {
+        10008
+    }
 
-        tokio::spawn(async move {
-            handle_connection(stream, address)
-                .await
-                .expect("Connection handler error")
-        });
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        Box::pin(async move { run(listener.into_tcp()?, limiter, shutdown).await })
     }
 }
 
-async fn handle_connection(stream: TcpStream, address: SocketAddr) -> Result<()> {
-    info!("Accepted connection from {}", address);
-    let mut session = session::Session::new(stream).await?;
+/// Starts the server on `bind` in the background.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&InsecureSockets, bind).await
+}
+
+pub async fn run(
+    listener: StreamListener,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    info!(
+        "Running insecure sockets server on {}...",
+        listener.local_addr()?
+    );
+
+    serve(
+        "insecure_sockets",
+        listener,
+        limiter,
+        shutdown,
+        handle_connection,
+    )
+    .await
+}
+
+#[instrument(parent = None, name = "connection", skip_all, fields(service = "insecure_sockets", %peer), err(Display))]
+pub async fn handle_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    limit: Limit,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    info!("Accepted connection");
+    let Some(session) = shutdown.or_cancel(session::Session::new(stream)).await else {
+        return Ok(());
+    };
+    let mut session = session.inspect_err(|_| metrics().cipher_rejections.inc())?;
 
-    loop {
-        let line = session.read_line().await?;
-        let response = session::handle_message(&line)?;
-        info!("Sending response to address: {} -> {}", response, address);
+    while let Some(line) = shutdown
+        .or_cancel(session.read_line())
+        .await
+        .transpose()?
+        .flatten()
+    {
+        match limit.message().await {
+            Verdict::Allow => {}
+            Verdict::Drop => continue,
+            Verdict::Disconnect => break,
+        }
+
+        metrics().request("insecure_sockets", "jobs");
+        let response = session::handle_message(&line)
+            .inspect_err(|_| metrics().parse_error("insecure_sockets"))?;
+        info!("Sending response: {}", response);
         session.write_line(response).await?;
     }
+
+    Ok(())
 }
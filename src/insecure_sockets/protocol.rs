@@ -46,34 +46,51 @@ impl Cipher {
     }
 
     pub fn decode_byte(&mut self, input: u8) -> u8 {
+        let byte = self.decode_at(input, self.incoming_position as u8);
+        self.incoming_position += 1;
+        byte
+    }
+
+    pub fn encode_byte(&mut self, byte: u8) -> u8 {
+        let byte = self.encode_at(byte, self.outgoing_position as u8);
+        self.outgoing_position += 1;
+        byte
+    }
+
+    /// A cipher that leaves every byte unchanged at every position. Clients
+    /// using one must be disconnected.
+    pub fn is_noop(&self) -> bool {
+        (0..=u8::MAX)
+            .all(|position| (0..=u8::MAX).all(|byte| self.encode_at(byte, position) == byte))
+    }
+
+    fn decode_at(&self, input: u8, position: u8) -> u8 {
         let mut byte = input;
         for operation in self.cipher.iter().rev() {
             match operation {
                 Operation::ReverseBits => byte = byte.reverse_bits(),
                 Operation::Xor { n } => byte ^= n,
-                Operation::XorPosition => byte ^= self.incoming_position as u8,
+                Operation::XorPosition => byte ^= position,
                 Operation::Add { n } => byte = byte.wrapping_sub(*n),
-                Operation::AddPosition => byte = byte.wrapping_sub(self.incoming_position as u8),
+                Operation::AddPosition => byte = byte.wrapping_sub(position),
                 Operation::CipherEnd => {}
             }
         }
-        self.incoming_position += 1;
         byte
     }
 
-    pub fn encode_byte(&mut self, byte: u8) -> u8 {
-        let mut byte = byte;
+    fn encode_at(&self, input: u8, position: u8) -> u8 {
+        let mut byte = input;
         for operation in self.cipher.iter() {
             match operation {
                 Operation::ReverseBits => byte = byte.reverse_bits(),
                 Operation::Xor { n } => byte ^= n,
-                Operation::XorPosition => byte ^= self.outgoing_position as u8,
+                Operation::XorPosition => byte ^= position,
                 Operation::Add { n } => byte = byte.wrapping_add(*n),
-                Operation::AddPosition => byte = byte.wrapping_add(self.outgoing_position as u8),
+                Operation::AddPosition => byte = byte.wrapping_add(position),
                 Operation::CipherEnd => {}
             }
         }
-        self.outgoing_position += 1;
         byte
     }
 
@@ -105,6 +122,11 @@ impl Cipher {
     }
 }
 
+/// Whether a cipher spec operation is followed by an argument byte.
+pub fn takes_argument(operation: u8) -> bool {
+    operation == XOR[0] || operation == ADD[0]
+}
+
 fn parse_cipher_spec(bytes: &[u8]) -> Result<Vec<Operation>> {
     let (_input, operations) = multi::many1(parse_operation)(bytes)
         .map_err(|_| anyhow::anyhow!("Failed to parse cipher spec"))?;
@@ -286,6 +308,24 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn noop_ciphers() {
+        for spec in [
+            &[0x00][..],
+            &[0x02, 0x00, 0x00],
+            &[0x02, 0xab, 0x02, 0xab, 0x00],
+            &[0x01, 0x01, 0x00],
+            &[0x02, 0xa0, 0x02, 0x0b, 0x02, 0xab, 0x00],
+            &[0x04, 0x80, 0x04, 0x80, 0x00],
+        ] {
+            assert!(Cipher::new(spec).unwrap().is_noop(), "{:?}", spec);
+        }
+
+        for spec in [&[0x02, 0x01, 0x00][..], &[0x05, 0x00], &[0x03, 0x00]] {
+            assert!(!Cipher::new(spec).unwrap().is_noop(), "{:?}", spec);
+        }
+    }
+
     #[test]
     fn test_applying_operations() {
         let message = b"hello";
@@ -1,8 +1,7 @@
 #![allow(dead_code)]
 
-use super::protocol::Cipher;
+use super::stream::CipherStream;
 use anyhow::Result;
-use log::info;
 use nom::{
     bytes::complete::{is_not, tag},
     multi::{many1, separated_list1},
@@ -10,18 +9,11 @@ use nom::{
     IResult,
 };
 use std::fmt::{Display, Formatter};
-use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
-};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::info;
 
-pub struct Session {
-    reader: BufReader<OwnedReadHalf>,
-    writer: OwnedWriteHalf,
-    cipher: Cipher,
+pub struct Session<S> {
+    stream: BufReader<CipherStream<S>>,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -38,7 +30,7 @@ impl Ord for Job {
 
 impl PartialOrd for Job {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.copies.cmp(&other.copies))
+        Some(self.cmp(other))
     }
 }
 
@@ -54,47 +46,33 @@ impl Display for Job {
     }
 }
 
-impl Session {
-    pub async fn new(stream: TcpStream) -> Result<Self> {
-        let (read_half, write_half) = stream.into_split();
-        let mut reader = BufReader::new(read_half);
-        let mut buffer = Vec::new();
-        let _bytes_read = reader.read_until(0x00, &mut buffer).await?;
-        info!("Read buffer: {:?}", buffer);
-
-        let cipher = Cipher::new(&buffer)?;
-        info!("New cipher: {:?}", cipher);
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+    pub async fn new(stream: S) -> Result<Self> {
+        let stream = CipherStream::accept(stream).await?;
 
         Ok(Self {
-            reader,
-            writer: write_half,
-            cipher,
+            stream: BufReader::new(stream),
         })
     }
 
-    pub async fn read_line(&mut self) -> Result<String> {
-        let mut line = String::new();
-        info!("Reading line with cipher: {:?}", self.cipher);
-        loop {
-            let byte = self.reader.read_u8().await?;
-            let decoded_byte = self.cipher.decode_byte(byte);
-
-            if decoded_byte == b'\n' {
-                break;
-            } else {
-                line.push(decoded_byte as char);
-            }
+    /// Reads the next decoded line, or `None` once the client has gone.
+    pub async fn read_line(&mut self) -> Result<Option<String>> {
+        let mut line = Vec::new();
+        if self.stream.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(None);
         }
+
+        let line = String::from_utf8_lossy(&line)
+            .trim_end_matches('\n')
+            .to_string();
         info!("Received line: {}", line);
-        Ok(line)
+        Ok(Some(line))
     }
 
     pub async fn write_line(&mut self, mut line: String) -> Result<()> {
         line.push('\n');
-        let encoded_bytes = self.cipher.encode(line.as_bytes())?;
-
-        self.writer.write_all(&encoded_bytes).await?;
-        self.writer.flush().await?;
+        self.stream.write_all(line.as_bytes()).await?;
+        self.stream.flush().await?;
         Ok(())
     }
 }
@@ -168,6 +146,29 @@ mod test {
         assert!(jobs[3].toy == "test toy");
     }
 
+    #[tokio::test]
+    async fn session_over_cipher() -> Result<()> {
+        let (client, server) = tokio::io::duplex(64);
+        let server = tokio::spawn(async move {
+            let mut session = Session::new(server).await?;
+            let line = session.read_line().await?.unwrap();
+            session.write_line(handle_message(&line)?).await?;
+            anyhow::Ok(session.read_line().await?)
+        });
+
+        let mut client =
+            BufReader::new(CipherStream::connect(client, &[0x02, 0x01, 0x01, 0x00]).await?);
+        client.write_all(b"4x dog,5x car\n").await?;
+        client.flush().await?;
+        let mut response = String::new();
+        client.read_line(&mut response).await?;
+        assert_eq!(response, "5x car\n");
+
+        drop(client);
+        assert_eq!(server.await??, None);
+        Ok(())
+    }
+
     #[test]
     fn test_handle_message() {
         let message = "10x toy car,15x dog on a string,4x inflatable motorcycle";
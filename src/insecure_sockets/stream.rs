@@ -0,0 +1,191 @@
+use super::protocol::{takes_argument, Cipher};
+use anyhow::Result;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::info;
+
+/// Longest cipher spec a client may send.
+const MAX_SPEC_LENGTH: usize = 80;
+
+/// Applies an insecure sockets cipher to everything read from and written to
+/// the inner stream. Positions are tracked separately in each direction, so the
+/// same type works for both ends of a connection.
+#[derive(Debug)]
+pub struct CipherStream<S> {
+    inner: S,
+    cipher: Cipher,
+    // Encoded bytes the inner stream hasn't taken yet
+    pending: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> CipherStream<S> {
+    /// Server side: reads the cipher spec the client sends first, rejecting
+    /// ciphers that leave the data unchanged.
+    pub async fn accept(mut inner: S) -> Result<Self> {
+        let spec = read_cipher_spec(&mut inner).await?;
+        let cipher = Cipher::new(&spec)?;
+        info!("New cipher: {:?}", cipher);
+
+        if cipher.is_noop() {
+            anyhow::bail!("Cipher spec is a no-op");
+        }
+
+        Ok(Self::new(inner, cipher))
+    }
+
+    /// Client side: sends the cipher spec, then encodes everything after it.
+    pub async fn connect(mut inner: S, spec: &[u8]) -> Result<Self> {
+        let cipher = Cipher::new(spec)?;
+        inner.write_all(spec).await?;
+        Ok(Self::new(inner, cipher))
+    }
+}
+
+impl<S> CipherStream<S> {
+    fn new(inner: S, cipher: Cipher) -> Self {
+        Self {
+            inner,
+            cipher,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CipherStream<S> {
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Reads a cipher spec up to and including its terminating zero byte. Reads
+/// one byte at a time so nothing after the spec is consumed.
+async fn read_cipher_spec<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut spec = Vec::new();
+
+    loop {
+        if spec.len() >= MAX_SPEC_LENGTH {
+            anyhow::bail!("Cipher spec is too long");
+        }
+
+        let operation = reader.read_u8().await?;
+        spec.push(operation);
+
+        if takes_argument(operation) {
+            spec.push(reader.read_u8().await?);
+        } else if operation == 0x00 {
+            return Ok(spec);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CipherStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        for byte in &mut buf.filled_mut()[start..] {
+            *byte = this.cipher.decode_byte(*byte);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CipherStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Encoding moves the position on, so bytes are only encoded once and
+        // then held until the inner stream takes them.
+        ready!(this.poll_write_pending(cx))?;
+
+        let encoded: Vec<u8> = buf.iter().map(|b| this.cipher.encode_byte(*b)).collect();
+        this.pending = encoded;
+        let _ = this.poll_write_pending(cx)?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn echo_through_cipher() -> Result<()> {
+        let (client, server) = tokio::io::duplex(64);
+
+        let server = tokio::spawn(async move {
+            let stream = CipherStream::accept(server).await?;
+            let (mut reader, mut writer) = tokio::io::split(stream);
+            tokio::io::copy(&mut reader, &mut writer).await?;
+            anyhow::Ok(())
+        });
+
+        let spec = [0x02, 0x7b, 0x05, 0x01, 0x00];
+        let mut client = CipherStream::connect(client, &spec).await?;
+        let mut reply = [0u8; 11];
+
+        client.write_all(b"hello ").await?;
+        client.write_all(b"world").await?;
+        client.read_exact(&mut reply).await?;
+        assert_eq!(&reply, b"hello world");
+
+        client.shutdown().await?;
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake() -> Result<()> {
+        // Arguments may be zero without ending the spec
+        let mut input: &[u8] = &[0x02, 0x00, 0x04, 0x00, 0x03, 0x00, b'x'];
+        assert_eq!(
+            read_cipher_spec(&mut input).await?,
+            [0x02, 0x00, 0x04, 0x00, 0x03, 0x00]
+        );
+        assert_eq!(input, b"x");
+
+        let (mut client, server) = tokio::io::duplex(64);
+        client.write_all(&[0x02, 0xa0, 0x02, 0xa0, 0x00]).await?;
+        assert!(CipherStream::accept(server).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+use crate::limiter::{Limit, Limiter, Verdict};
+use crate::metrics::metrics;
+use crate::service::{serve, Listener, ServerHandle, Service, ServiceFuture, StreamListener};
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::ToSocketAddrs,
+    sync::Notify,
+};
+use tracing::{info, instrument};
+
+use self::queue::{AbortError, ClientId, JobId, Queues};
+
+mod queue;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "request", rename_all = "lowercase")]
+enum Request {
+    Put {
+        queue: String,
+        job: Map<String, Value>,
+        pri: u64,
+    },
+    Get {
+        queues: Vec<String>,
+        #[serde(default)]
+        wait: bool,
+    },
+    Delete {
+        id: JobId,
+    },
+    Abort {
+        id: JobId,
+    },
+}
+
+impl Request {
+    /// The request type, for metrics.
+    fn method(&self) -> &'static str {
+        match self {
+            Request::Put { .. } => "put",
+            Request::Get { .. } => "get",
+            Request::Delete { .. } => "delete",
+            Request::Abort { .. } => "abort",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum Response {
+    #[serde(rename = "ok")]
+    Created {
+        id: JobId,
+    },
+    #[serde(rename = "ok")]
+    Assigned {
+        id: JobId,
+        job: Map<String, Value>,
+        pri: u64,
+        queue: String,
+    },
+    Ok,
+    NoJob,
+    Error {
+        error: String,
+    },
+}
+
+/// The queues shared by every connection, plus a way to wake up clients
+/// waiting for a job to appear.
+struct Centre {
+    next_client: AtomicUsize,
+    queues: Mutex<Queues>,
+    job_available: Notify,
+}
+
+/// Returns a client's jobs to their queues when the connection goes away,
+/// however that happens.
+struct Worker {
+    id: ClientId,
+    centre: Arc<Centre>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let released = self.centre.queues.lock().unwrap().release(self.id);
+        if released > 0 {
+            info!("Released {} jobs from client {}", released, self.id);
+            self.centre.job_available.notify_waiters();
+        }
+    }
+}
+
+pub struct JobCentre;
+
+impl Service for JobCentre {
+    fn name(&self) -> &'static str {
+        "job_centre"
+    }
+
+    fn default_port(&self) -> u16 {
+        10009
+    }
+
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        Box::pin(async move { run(listener.into_tcp()?, limiter, shutdown).await })
+    }
+}
+
+/// Starts the server on `bind` in the background.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&JobCentre, bind).await
+}
+
+pub async fn run(
+    listener: StreamListener,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    info!("Running job centre server on {}...", listener.local_addr()?);
+    let centre = Arc::new(Centre {
+        next_client: AtomicUsize::new(0),
+        queues: Mutex::new(Queues::default()),
+        job_available: Notify::new(),
+    });
+
+    serve(
+        "job_centre",
+        listener,
+        limiter,
+        shutdown,
+        move |stream, peer, limit, shutdown| {
+            handle_connection(stream, peer, centre.clone(), limit, shutdown)
+        },
+    )
+    .await
+}
+
+#[instrument(parent = None, name = "connection", skip_all, fields(service = "job_centre", %peer), err(Display))]
+async fn handle_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    centre: Arc<Centre>,
+    limit: Limit,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let worker = Worker {
+        id: centre.next_client.fetch_add(1, Ordering::Relaxed),
+        centre,
+    };
+    info!("Accepted client {}", worker.id);
+
+    let (read_half, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(read_half).lines();
+
+    while let Some(line) = shutdown
+        .or_cancel(lines.next_line())
+        .await
+        .transpose()?
+        .flatten()
+    {
+        match limit.message().await {
+            Verdict::Allow => {}
+            Verdict::Drop => continue,
+            Verdict::Disconnect => break,
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                info!("Received {:?}", request);
+                metrics().request("job_centre", request.method());
+                handle_request(request, &worker, &shutdown).await
+            }
+            Err(e) => {
+                metrics().parse_error("job_centre");
+                Response::Error {
+                    error: e.to_string(),
+                }
+            }
+        };
+
+        let mut response = serde_json::to_vec(&response)?;
+        response.push(b'\n');
+        writer.write_all(&response).await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_request(request: Request, worker: &Worker, shutdown: &Shutdown) -> Response {
+    let centre = &worker.centre;
+
+    match request {
+        Request::Put { queue, job, pri } => {
+            let id = centre.queues.lock().unwrap().put(queue, job, pri);
+            centre.job_available.notify_waiters();
+            Response::Created { id }
+        }
+
+        Request::Get { queues, wait } => loop {
+            // Register interest before looking, so a put in between isn't missed
+            let job_available = centre.job_available.notified();
+            tokio::pin!(job_available);
+            job_available.as_mut().enable();
+
+            if let Some(job) = centre.queues.lock().unwrap().get(&queues, worker.id) {
+                return Response::Assigned {
+                    id: job.id,
+                    job: job.job,
+                    pri: job.pri,
+                    queue: job.queue,
+                };
+            }
+
+            if !wait {
+                return Response::NoJob;
+            }
+
+            // A waiting get could outlast any drain timeout
+            if shutdown.or_cancel(job_available).await.is_none() {
+                return Response::Error {
+                    error: "server is shutting down".to_string(),
+                };
+            }
+        },
+
+        Request::Delete { id } => match centre.queues.lock().unwrap().delete(id) {
+            true => Response::Ok,
+            false => Response::NoJob,
+        },
+
+        Request::Abort { id } => {
+            let result = centre.queues.lock().unwrap().abort(id, worker.id);
+            match result {
+                Ok(()) => {
+                    centre.job_available.notify_waiters();
+                    Response::Ok
+                }
+                Err(AbortError::NoJob) => Response::NoJob,
+                Err(AbortError::NotWorking) => Response::Error {
+                    error: format!("not working on job {}", id),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_requests() {
+        let request: Request = serde_json::from_str(
+            r#"{"request":"put","queue":"queue1","job":{"title":"example-job"},"pri":123}"#,
+        )
+        .unwrap();
+        assert!(matches!(request, Request::Put { pri: 123, .. }));
+
+        let request: Request =
+            serde_json::from_str(r#"{"request":"get","queues":["queue1"]}"#).unwrap();
+        assert!(matches!(request, Request::Get { wait: false, .. }));
+
+        for invalid in [
+            r#"{"request":"put","queue":"queue1","job":"not an object","pri":123}"#,
+            r#"{"request":"put","queue":"queue1","job":{},"pri":-1}"#,
+            r#"{"request":"get","queues":"queue1"}"#,
+            r#"{"request":"fly","id":1}"#,
+            r#"{"id":1}"#,
+            "not json",
+        ] {
+            assert!(
+                serde_json::from_str::<Request>(invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn serialize_responses() {
+        let mut job = Map::new();
+        job.insert("title".to_string(), "example-job".into());
+
+        let cases = [
+            (
+                Response::Created { id: 12345 },
+                r#"{"status":"ok","id":12345}"#,
+            ),
+            (
+                Response::Assigned {
+                    id: 12345,
+                    job,
+                    pri: 123,
+                    queue: "queue1".to_string(),
+                },
+                r#"{"status":"ok","id":12345,"job":{"title":"example-job"},"pri":123,"queue":"queue1"}"#,
+            ),
+            (Response::Ok, r#"{"status":"ok"}"#),
+            (Response::NoJob, r#"{"status":"no-job"}"#),
+        ];
+
+        for (response, expected) in cases {
+            assert_eq!(serde_json::to_string(&response).unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use serde_json::{Map, Value};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+pub type ClientId = usize;
+pub type JobId = u64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: JobId,
+    pub queue: String,
+    pub pri: u64,
+    pub job: Map<String, Value>,
+    // The client currently working on the job, if any
+    worker: Option<ClientId>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AbortError {
+    NoJob,
+    NotWorking,
+}
+
+/// Every job known to the server, and the named priority queues of jobs
+/// that are waiting for a worker.
+#[derive(Default)]
+pub struct Queues {
+    next_id: JobId,
+    jobs: HashMap<JobId, Job>,
+    // Highest priority first, then oldest first. Entries for deleted jobs are
+    // left in place and skipped when they reach the top.
+    queues: HashMap<String, BinaryHeap<(u64, Reverse<JobId>)>>,
+}
+
+impl Queues {
+    pub fn put(&mut self, queue: String, job: Map<String, Value>, pri: u64) -> JobId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.enqueue(&queue, pri, id);
+        self.jobs.insert(
+            id,
+            Job {
+                id,
+                queue,
+                pri,
+                job,
+                worker: None,
+            },
+        );
+
+        id
+    }
+
+    /// Assigns the highest priority waiting job from any of the given queues to the client.
+    pub fn get(&mut self, queues: &[String], client: ClientId) -> Option<Job> {
+        let (queue, _) = queues
+            .iter()
+            .filter_map(|queue| self.peek(queue).map(|top| (queue, top)))
+            .max_by_key(|(_, top)| *top)?;
+
+        let (_, Reverse(id)) = self.queues.get_mut(queue)?.pop()?;
+        let job = self.jobs.get_mut(&id)?;
+        job.worker = Some(client);
+
+        Some(job.clone())
+    }
+
+    pub fn delete(&mut self, id: JobId) -> bool {
+        self.jobs.remove(&id).is_some()
+    }
+
+    pub fn abort(&mut self, id: JobId, client: ClientId) -> Result<(), AbortError> {
+        let job = self.jobs.get_mut(&id).ok_or(AbortError::NoJob)?;
+        if job.worker != Some(client) {
+            return Err(AbortError::NotWorking);
+        }
+
+        job.worker = None;
+        let (queue, pri) = (job.queue.clone(), job.pri);
+        self.enqueue(&queue, pri, id);
+
+        Ok(())
+    }
+
+    /// Puts every job the client is working on back in its queue, returning
+    /// how many there were.
+    pub fn release(&mut self, client: ClientId) -> usize {
+        let ids: Vec<JobId> = self
+            .jobs
+            .values()
+            .filter(|job| job.worker == Some(client))
+            .map(|job| job.id)
+            .collect();
+
+        for id in &ids {
+            let _ = self.abort(*id, client);
+        }
+
+        ids.len()
+    }
+
+    fn enqueue(&mut self, queue: &str, pri: u64, id: JobId) {
+        self.queues
+            .entry(queue.to_string())
+            .or_default()
+            .push((pri, Reverse(id)));
+    }
+
+    /// Returns the top entry of the queue, dropping stale entries on the way.
+    fn peek(&mut self, queue: &str) -> Option<(u64, Reverse<JobId>)> {
+        let heap = self.queues.get_mut(queue)?;
+
+        while let Some(&(pri, Reverse(id))) = heap.peek() {
+            match self.jobs.get(&id) {
+                Some(job) if job.worker.is_none() => return Some((pri, Reverse(id))),
+                _ => {
+                    heap.pop();
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn job(n: u64) -> Map<String, Value> {
+        let mut job = Map::new();
+        job.insert("n".to_string(), n.into());
+        job
+    }
+
+    fn names(queues: &[&str]) -> Vec<String> {
+        queues.iter().map(|q| q.to_string()).collect()
+    }
+
+    #[test]
+    fn get_highest_priority_across_queues() {
+        let mut queues = Queues::default();
+        let low = queues.put("q1".to_string(), job(1), 10);
+        let high = queues.put("q2".to_string(), job(2), 20);
+        let other = queues.put("q3".to_string(), job(3), 30);
+
+        let got = queues.get(&names(&["q1", "q2"]), 0).unwrap();
+        assert_eq!(got.id, high);
+        assert_eq!(got.queue, "q2");
+        assert_eq!(got.job, job(2));
+
+        assert_eq!(queues.get(&names(&["q1", "q2"]), 0).unwrap().id, low);
+        assert_eq!(queues.get(&names(&["q1", "q2"]), 0), None);
+        assert_eq!(queues.get(&names(&["q3"]), 0).unwrap().id, other);
+    }
+
+    #[test]
+    fn deleted_jobs_are_skipped() {
+        let mut queues = Queues::default();
+        let id = queues.put("q1".to_string(), job(1), 10);
+        assert!(queues.delete(id));
+        assert!(!queues.delete(id));
+        assert_eq!(queues.get(&names(&["q1"]), 0), None);
+    }
+
+    #[test]
+    fn abort_only_by_worker() {
+        let mut queues = Queues::default();
+        let id = queues.put("q1".to_string(), job(1), 10);
+
+        assert_eq!(queues.abort(id, 0), Err(AbortError::NotWorking));
+        queues.get(&names(&["q1"]), 0).unwrap();
+        assert_eq!(queues.abort(id, 1), Err(AbortError::NotWorking));
+        assert_eq!(queues.abort(id + 1, 0), Err(AbortError::NoJob));
+        assert_eq!(queues.abort(id, 0), Ok(()));
+
+        assert_eq!(queues.get(&names(&["q1"]), 1).unwrap().id, id);
+    }
+
+    #[test]
+    fn release_returns_jobs_to_their_queues() {
+        let mut queues = Queues::default();
+        queues.put("q1".to_string(), job(1), 10);
+        queues.put("q2".to_string(), job(2), 20);
+        queues.get(&names(&["q1"]), 0).unwrap();
+        queues.get(&names(&["q2"]), 0).unwrap();
+
+        assert_eq!(queues.release(1), 0);
+        assert_eq!(queues.release(0), 2);
+        assert_eq!(queues.get(&names(&["q1", "q2"]), 1).unwrap().pri, 20);
+        assert_eq!(queues.get(&names(&["q1", "q2"]), 1).unwrap().pri, 10);
+    }
+}
@@ -1,5 +1,26 @@
+// Services can listen on Unix sockets, so only Unix is supported
+#[cfg(not(unix))]
+compile_error!("protohackers-rs only builds on Unix");
+
+pub mod access;
+pub mod admin;
+pub mod budget_chat;
+pub mod code_storage;
+pub mod config;
 pub mod insecure_sockets;
+pub mod job_centre;
+pub mod limiter;
 pub mod line_reversal;
 pub mod means_to_an_end;
+pub mod metrics;
+pub mod mob_in_the_middle;
+pub mod pest_control;
 pub mod prime_time;
+pub mod proxy_protocol;
+pub mod service;
+pub mod shutdown;
 pub mod smoke_test;
+pub mod speed_daemon;
+pub mod supervisor;
+pub mod tls;
+pub mod unusual_database;
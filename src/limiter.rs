@@ -0,0 +1,519 @@
+use crate::access::AccessList;
+use crate::metrics::metrics;
+use crate::shutdown::Shutdown;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+/// Peers are forgotten once idle, checked whenever this many more are known.
+const PRUNE_INTERVAL: usize = 1024;
+
+/// What happens to a client that goes over one of its limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Ignore the message, or close a connection over the cap straight away.
+    Drop,
+    /// Hold the message, or the new connection, until the client is back
+    /// under its limits. Datagrams can't be held, so they are dropped, and at
+    /// most as many connections as the cap wait, so the rest are closed.
+    #[default]
+    Delay,
+    /// Close the connection, or the LRCP session the datagram is for.
+    Disconnect,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Drop => f.pad("drop"),
+            Action::Delay => f.pad("delay"),
+            Action::Disconnect => f.pad("disconnect"),
+        }
+    }
+}
+
+/// Per IP limits for one service, from the `[limits]` table. Nothing is
+/// limited by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Connections, or LRCP sessions, open at once.
+    pub max_connections: Option<usize>,
+    /// Messages, or datagrams, per second on average.
+    #[serde(deserialize_with = "rate")]
+    pub messages_per_second: Option<f64>,
+    /// Messages allowed in a burst above the average, a second's worth by default.
+    #[serde(deserialize_with = "burst_size")]
+    pub burst: Option<f64>,
+    pub action: Action,
+}
+
+/// What to do with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Drop,
+    Disconnect,
+}
+
+/// Applies a service's `Limits` and `AccessList` to its peers. Clones share
+/// the counts.
+#[derive(Clone, Default)]
+pub struct Limiter {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    service: &'static str,
+    limits: Limits,
+    access: AccessList,
+    peers: Mutex<Peers>,
+    // Wakes connections waiting for a slot
+    released: Notify,
+}
+
+#[derive(Default)]
+struct Peers {
+    peers: HashMap<IpAddr, Peer>,
+    next_prune: usize,
+}
+
+struct Peer {
+    connections: usize,
+    // Connections delayed until one of the others closes
+    waiting: usize,
+    // Goes negative while delayed messages wait their turn
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Limiter {
+    pub fn new(service: &'static str, limits: Limits, access: AccessList) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                service,
+                limits,
+                access,
+                peers: Mutex::default(),
+                released: Notify::new(),
+            }),
+        }
+    }
+
+    /// A limiter that lets everything through.
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.inner.limits
+    }
+
+    pub fn access(&self) -> &AccessList {
+        &self.inner.access
+    }
+
+    /// Whether the access list lets `ip` in. Rejections are logged and counted,
+    /// and the caller closes the connection or ignores the datagram.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.inner.access.permits(ip) {
+            return true;
+        }
+
+        info!(peer = %ip, "Rejected by the access list");
+        metrics()
+            .access_rejections
+            .with_label_values(&[self.inner.service])
+            .inc();
+        false
+    }
+
+    /// Takes one of `ip`'s connection slots, or returns `None` if it has none
+    /// left. With the delay action it waits for a slot to free up instead,
+    /// unless `ip` already has as many connections waiting as it may have open,
+    /// or shutdown starts.
+    pub async fn admit(&self, ip: IpAddr, shutdown: &Shutdown) -> Option<Limit> {
+        let mut waiting = None;
+        loop {
+            let released = self.inner.released.notified();
+            if let Some(limit) = self.try_admit(ip) {
+                return Some(limit);
+            }
+            if self.inner.limits.action != Action::Delay {
+                return None;
+            }
+            if waiting.is_none() {
+                waiting = Some(self.wait(ip)?);
+            }
+            shutdown.or_cancel(released).await?;
+        }
+    }
+
+    /// Counts a connection from `ip` as waiting for a slot, if it has room for
+    /// another. It stops counting when the returned guard is dropped.
+    fn wait(&self, ip: IpAddr) -> Option<Waiting<'_>> {
+        let limits = &self.inner.limits;
+        let mut peers = self.inner.peers.lock().unwrap();
+        let peer = peers.get(ip, limits);
+        if limits
+            .max_connections
+            .is_some_and(|max| peer.waiting >= max)
+        {
+            drop(peers);
+            debug!(peer = %ip, "Too many connections waiting, closing");
+            return None;
+        }
+
+        peer.waiting += 1;
+        Some(Waiting { limiter: self, ip })
+    }
+
+    /// Takes one of `ip`'s connection slots if it has one left.
+    pub fn try_admit(&self, ip: IpAddr) -> Option<Limit> {
+        let limits = &self.inner.limits;
+        if limits.max_connections.is_none() && limits.messages_per_second.is_none() {
+            return Some(Limit::none());
+        }
+
+        let mut peers = self.inner.peers.lock().unwrap();
+        let peer = peers.get(ip, limits);
+        if limits
+            .max_connections
+            .is_some_and(|max| peer.connections >= max)
+        {
+            drop(peers);
+            self.violation(ip, "connections");
+            return None;
+        }
+
+        peer.connections += 1;
+        Some(Limit {
+            limiter: Some(self.clone()),
+            ip,
+        })
+    }
+
+    /// Counts a message from `ip` against its rate. With the delay action a
+    /// message over the rate waits its turn here, and is then allowed.
+    pub async fn message(&self, ip: IpAddr) -> Verdict {
+        match self.take_token(ip) {
+            Ok(()) => Verdict::Allow,
+            Err(wait) => match self.inner.limits.action {
+                Action::Drop => Verdict::Drop,
+                Action::Disconnect => Verdict::Disconnect,
+                Action::Delay => {
+                    tokio::time::sleep(wait).await;
+                    Verdict::Allow
+                }
+            },
+        }
+    }
+
+    /// Counts a datagram from `ip` against its rate. There is nothing to hold
+    /// back a datagram with, so delay drops it like drop does.
+    pub fn datagram(&self, ip: IpAddr) -> Verdict {
+        match self.take_token(ip) {
+            Ok(()) => Verdict::Allow,
+            Err(_) => match self.inner.limits.action {
+                Action::Drop | Action::Delay => Verdict::Drop,
+                Action::Disconnect => Verdict::Disconnect,
+            },
+        }
+    }
+
+    /// Takes a token from `ip`'s bucket, or with the delay action reserves the
+    /// next one and returns how long until it is due.
+    fn take_token(&self, ip: IpAddr) -> Result<(), Duration> {
+        let limits = &self.inner.limits;
+        let Some(rate) = limits.messages_per_second else {
+            return Ok(());
+        };
+
+        let mut peers = self.inner.peers.lock().unwrap();
+        let peer = peers.get(ip, limits);
+        let now = Instant::now();
+        let elapsed = now.duration_since(peer.updated).as_secs_f64();
+        peer.tokens = (peer.tokens + elapsed * rate).min(burst(limits));
+        peer.updated = now;
+
+        if peer.tokens >= 1.0 {
+            peer.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let wait = Duration::from_secs_f64((1.0 - peer.tokens) / rate);
+        if limits.action == Action::Delay {
+            peer.tokens -= 1.0;
+        }
+        drop(peers);
+        self.violation(ip, "messages");
+        Err(wait)
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut peers = self.inner.peers.lock().unwrap();
+        if let Some(peer) = peers.peers.get_mut(&ip) {
+            peer.connections -= 1;
+        }
+        drop(peers);
+        self.inner.released.notify_waiters();
+    }
+
+    fn violation(&self, ip: IpAddr, limit: &str) {
+        let action = self.inner.limits.action.to_string();
+        debug!(peer = %ip, "Over the {} limit, action is {}", limit, action);
+        metrics()
+            .limit_violations
+            .with_label_values(&[self.inner.service, limit, &action])
+            .inc();
+    }
+}
+
+impl Peers {
+    fn get(&mut self, ip: IpAddr, limits: &Limits) -> &mut Peer {
+        if !self.peers.contains_key(&ip) && self.peers.len() >= self.next_prune {
+            self.prune(limits);
+        }
+
+        self.peers.entry(ip).or_insert_with(|| Peer {
+            connections: 0,
+            waiting: 0,
+            tokens: burst(limits),
+            updated: Instant::now(),
+        })
+    }
+
+    /// Forgets peers with nothing open whose buckets have refilled.
+    fn prune(&mut self, limits: &Limits) {
+        let now = Instant::now();
+        self.peers.retain(|_, peer| {
+            let refilled = match limits.messages_per_second {
+                Some(rate) => {
+                    peer.tokens + now.duration_since(peer.updated).as_secs_f64() * rate
+                        >= burst(limits)
+                }
+                None => true,
+            };
+            peer.connections > 0 || peer.waiting > 0 || !refilled
+        });
+        self.next_prune = self.peers.len() + PRUNE_INTERVAL;
+    }
+}
+
+/// Tokens would never come back at a rate of zero, and waits for them would
+/// be infinite.
+fn rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let rate = f64::deserialize(deserializer)?;
+    if !(rate.is_finite() && rate > 0.0) {
+        return Err(D::Error::custom(format!(
+            "messages_per_second must be above zero, not {}",
+            rate
+        )));
+    }
+    Ok(Some(rate))
+}
+
+/// A bucket smaller than one token could never let a message through.
+fn burst_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let burst = f64::deserialize(deserializer)?;
+    if !(burst.is_finite() && burst >= 1.0) {
+        return Err(D::Error::custom(format!(
+            "burst must be at least 1, not {}",
+            burst
+        )));
+    }
+    Ok(Some(burst))
+}
+
+fn burst(limits: &Limits) -> f64 {
+    limits
+        .burst
+        .or(limits.messages_per_second)
+        .unwrap_or(0.0)
+        .max(1.0)
+}
+
+/// A connection waiting in `Limiter::admit`.
+struct Waiting<'a> {
+    limiter: &'a Limiter,
+    ip: IpAddr,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut peers = self.limiter.inner.peers.lock().unwrap();
+        if let Some(peer) = peers.peers.get_mut(&self.ip) {
+            peer.waiting -= 1;
+        }
+    }
+}
+
+/// One connection's slot, given back when it is dropped, through which the
+/// connection's messages are counted.
+pub struct Limit {
+    // None when nothing is limited
+    limiter: Option<Limiter>,
+    ip: IpAddr,
+}
+
+impl Limit {
+    /// A limit that lets everything through, for handlers run on their own.
+    pub fn none() -> Self {
+        Self {
+            limiter: None,
+            ip: IpAddr::from([0, 0, 0, 0]),
+        }
+    }
+
+    /// Counts a message, see `Limiter::message`.
+    pub async fn message(&self) -> Verdict {
+        match &self.limiter {
+            Some(limiter) => limiter.message(self.ip).await,
+            None => Verdict::Allow,
+        }
+    }
+}
+
+impl Drop for Limit {
+    fn drop(&mut self) {
+        if let Some(limiter) = &self.limiter {
+            limiter.release(self.ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+    const OTHER_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED);
+
+    fn limited(action: Action) -> Limiter {
+        Limiter::new(
+            "limiter_test",
+            Limits {
+                max_connections: Some(2),
+                messages_per_second: Some(10.0),
+                burst: Some(3.0),
+                action,
+            },
+            AccessList::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn caps_connections_per_ip() {
+        let limiter = limited(Action::Drop);
+        let shutdown = Shutdown::new();
+        let first = limiter.admit(IP, &shutdown).await.unwrap();
+        let _second = limiter.admit(IP, &shutdown).await.unwrap();
+        assert!(limiter.admit(IP, &shutdown).await.is_none());
+        assert!(limiter.try_admit(OTHER_IP).is_some());
+
+        drop(first);
+        assert!(limiter.try_admit(IP).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay_waits_for_a_connection_slot() {
+        let limiter = limited(Action::Delay);
+        let shutdown = Shutdown::new();
+        let first = limiter.try_admit(IP).unwrap();
+        let second = limiter.try_admit(IP).unwrap();
+
+        let waiting: Vec<_> = (0..2)
+            .map(|_| {
+                let limiter = limiter.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move { limiter.admit(IP, &shutdown).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(waiting.iter().all(|task| !task.is_finished()));
+
+        // Only as many wait as may be open, the rest are closed
+        assert!(limiter.admit(IP, &shutdown).await.is_none());
+
+        drop((first, second));
+        let mut admitted = Vec::new();
+        for task in waiting {
+            admitted.push(task.await.unwrap().unwrap());
+        }
+
+        // Shutdown gives up on waiting
+        shutdown.trigger();
+        assert!(limiter.admit(IP, &shutdown).await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limits_messages() {
+        let limiter = limited(Action::Drop);
+        for _ in 0..3 {
+            assert_eq!(limiter.message(IP).await, Verdict::Allow);
+        }
+        assert_eq!(limiter.message(IP).await, Verdict::Drop);
+        assert_eq!(limiter.message(OTHER_IP).await, Verdict::Allow);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(limiter.message(IP).await, Verdict::Allow);
+        assert_eq!(limiter.message(IP).await, Verdict::Drop);
+
+        let disconnect = limited(Action::Disconnect);
+        for _ in 0..3 {
+            disconnect.message(IP).await;
+        }
+        assert_eq!(disconnect.message(IP).await, Verdict::Disconnect);
+        assert_eq!(disconnect.datagram(IP), Verdict::Disconnect);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay_spaces_out_messages() {
+        let limiter = limited(Action::Delay);
+        let start = Instant::now();
+        for _ in 0..6 {
+            assert_eq!(limiter.message(IP).await, Verdict::Allow);
+        }
+        // The burst goes straight through, then one every 100ms
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(350), "{:?}", elapsed);
+
+        // Datagrams can't wait
+        assert_eq!(limiter.datagram(IP), Verdict::Drop);
+    }
+
+    #[tokio::test]
+    async fn unlimited_allows_everything() {
+        let limiter = Limiter::unlimited();
+        let shutdown = Shutdown::new();
+        let limits: Vec<Limit> = (0..100).map(|_| limiter.try_admit(IP).unwrap()).collect();
+        assert!(limiter.admit(IP, &shutdown).await.is_some());
+        for _ in 0..100 {
+            assert_eq!(limiter.datagram(IP), Verdict::Allow);
+        }
+        assert_eq!(limits[0].message().await, Verdict::Allow);
+        assert_eq!(Limit::none().message().await, Verdict::Allow);
+        assert!(limiter.permits(IP));
+    }
+
+    #[test]
+    fn rejects_peers_outside_the_access_list() {
+        let access = AccessList {
+            allow: vec!["127.0.0.0/8".parse().unwrap()],
+            deny: Vec::new(),
+        };
+        let limiter = Limiter::new("limiter_test", Limits::default(), access);
+        assert!(limiter.permits(IP));
+        assert!(!limiter.permits(OTHER_IP));
+    }
+}
@@ -1,145 +1,149 @@
-#![allow(dead_code)]
-use log::{error, info};
-use std::collections::BTreeMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use crate::limiter::Limiter;
+use crate::metrics;
+use crate::service::{Listener, ServerHandle, Service, ServiceFuture, Transport};
+use crate::shutdown::Shutdown;
 use tokio::{
-    net::UdpSocket,
-    sync::mpsc::{channel, unbounded_channel, Sender},
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{ToSocketAddrs, UdpSocket, UnixDatagram},
 };
+use tracing::{error, info, info_span, Instrument};
 
-use self::{
-    lrcp::LrcpSession,
-    message::{Message, Payload, SessionId},
-};
+use self::lrcp::{LrcpConfig, LrcpListener};
 
-mod lrcp;
+pub mod lrcp;
 mod message;
 
-const BLOCK_SIZE: usize = 1024;
-const CHANNEL_SIZE: usize = 100;
-
-type Sessions = BTreeMap<SessionId, Session>;
-pub struct Session {
-    pub tx: Sender<Message>,
-    pub address: SocketAddr,
+#[derive(Default)]
+pub struct LineReversal {
+    pub config: LrcpConfig,
 }
 
-pub async fn run(port: &str) -> anyhow::Result<()> {
-    let addr = format!("0.0.0.0:{}", port);
-    let socket = Arc::new(UdpSocket::bind(&addr).await?);
-    info!("Running Line Reversal server on {}...", &addr);
+impl Service for LineReversal {
+    fn name(&self) -> &'static str {
+        "line_reversal"
+    }
 
-    let (_tx, mut rx) = unbounded_channel::<Message>();
+    fn default_port(&self) -> u16 {
+        10007
+    }
 
-    let mut sessions: BTreeMap<SessionId, Session> = BTreeMap::new();
+    fn transport(&self) -> Transport {
+        Transport::Udp
+    }
 
-    loop {
-        tokio::select! {
-            (message, address) = read_message(&socket) => {
-                info!("Received packet from address: {}", address);
-                handle_client_message(message, address, socket.clone(), &mut sessions).await;
-            },
+    fn serves_unix_sockets(&self) -> bool {
+        true
+    }
 
-            resp = rx.recv() => {
-                info!("Received packet from main channel: {:?}", resp);
-                let message = resp.expect("Failed to receive packet from main channel");
-                handle_response(message, &socket, &mut sessions).await;
-            }
-        }
+    fn configure(&mut self, options: toml::Table) -> anyhow::Result<()> {
+        let config: LrcpConfig = options.try_into()?;
+        config.validate()?;
+        self.config = config;
+        Ok(())
     }
-}
 
-async fn read_message(socket: &UdpSocket) -> (Message, SocketAddr) {
-    loop {
-        let mut buf = [0u8; 1024];
-        let (num_bytes, src) = socket
-            .recv_from(&mut buf)
-            .await
-            .expect("Failed to receive packet");
-
-        match Message::parse(&buf[..num_bytes]) {
-            Ok(packet) => return (packet, src),
-            Err(e) => {
-                error!("Failed to parse packet: {}", e);
-            }
-        }
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        let config = self.config;
+        Box::pin(async move {
+            let (socket, unix) = listener.into_datagram_sockets()?;
+            run(socket, unix, config, limiter, shutdown).await
+        })
     }
 }
 
-async fn handle_client_message(
-    message: Message,
-    addr: SocketAddr,
-    socket: Arc<UdpSocket>,
-    sessions: &mut Sessions,
-) {
-    info!("Handing client message: {:?}", &message);
-    match message.payload {
-        Payload::Connect => {
-            // If the session exists, ignore the message
-            if let Some(_session) = sessions.get(&message.session) {
-                return;
-            }
-
-            // Create a new session
-            info!("Creating a new session for {:?}", message.session);
-            let (packet_tx, packet_rx) = channel::<Message>(CHANNEL_SIZE);
-            let session = Session {
-                tx: packet_tx,
-                address: addr,
-            };
-            sessions.insert(message.session.clone(), session);
-
-            // Spawn a new task to handle the session
-            tokio::spawn(async move {
-                let mut client = LrcpSession::new(message.session, socket.clone(), addr, packet_rx);
-                client.run().await;
-            });
-        }
+/// Starts the server on `bind` in the background, with the default options.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&LineReversal::default(), bind).await
+}
 
-        _ => {
-            // If the session doesn't exist, ignore the message
-            if let Some(session) = sessions.get(&message.session) {
-                if let Err(e) = session.tx.send(message).await {
-                    error!("Failed to send packet to session: {}", e);
+pub async fn run(
+    socket: UdpSocket,
+    unix: Option<UnixDatagram>,
+    config: LrcpConfig,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    info!(
+        "Running Line Reversal server on {}...",
+        socket.local_addr()?
+    );
+    let mut listener = LrcpListener::from_sockets(socket, unix, config, limiter)?;
+
+    while let Some(accepted) = shutdown.or_cancel(listener.accept()).await {
+        let (stream, address) = accepted?;
+        let span = info_span!(
+            parent: None,
+            "connection",
+            service = "line_reversal",
+            peer = %address,
+            session = stream.session_id(),
+        );
+        let stream = metrics::track("line_reversal", address, stream);
+
+        shutdown.spawn(move |shutdown| {
+            async move {
+                if let Err(e) = reverse_lines(stream, shutdown).await {
+                    error!("Connection error: {}", e);
                 }
-            } else {
-                error!("Session doesn't exist: {:?}", message.session);
             }
-        }
+            .instrument(span)
+        });
     }
-}
 
-pub async fn handle_response(message: Message, socket: &UdpSocket, sessions: &mut Sessions) {
-    match message.payload {
-        Payload::Close => {
-            // If the session doesn't exist, ignore the message
-            if let Some(session) = sessions.remove(&message.session) {
-                respond(socket, message, session.address).await;
-            } else {
-                error!("Session doesn't exist: {:?}", message.session);
-            }
-        }
+    // Sessions close once their handlers are done and their data is acked
+    listener.close().await;
+    Ok(())
+}
 
-        _ => {
-            // If the session doesn't exist, ignore the message
-            if let Some(session) = sessions.get(&message.session) {
-                respond(socket, message, session.address).await;
-            } else {
-                error!("Session doesn't exist: {:?}", message.session);
-            }
-        }
+async fn reverse_lines<S>(stream: S, shutdown: Shutdown) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = shutdown
+        .or_cancel(lines.next_line())
+        .await
+        .transpose()?
+        .flatten()
+    {
+        writer.write_all(reverse_line(&line).as_bytes()).await?;
     }
+
+    Ok(())
 }
 
-pub async fn respond(socket: &UdpSocket, message: Message, addr: SocketAddr) {
-    let bytes = message.to_packet();
-    match socket.send_to(&bytes, addr).await {
-        Ok(_num_bytes) => {
-            info!("Sent packet to {}", addr);
-        }
-        Err(e) => {
-            error!("Failed to send packet: {}", e);
+fn reverse_line(line: &str) -> String {
+    let mut reversed_line: String = line.trim_end().chars().rev().collect();
+    reversed_line.push('\n');
+    reversed_line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_zero_options() {
+        for key in [
+            "block_size",
+            "channel_size",
+            "connection_timeout",
+            "retransmission_timeout",
+        ] {
+            let options: toml::Table = toml::from_str(&format!("{} = 0", key)).unwrap();
+            let error = LineReversal::default().configure(options).unwrap_err();
+            assert_eq!(error.to_string(), format!("{} must be above zero", key));
         }
+
+        let options: toml::Table = toml::from_str("retransmission_timeout = 0.5").unwrap();
+        assert!(LineReversal::default().configure(options).is_ok());
+    }
+
+    #[test]
+    fn reverses_lines() {
+        assert_eq!(reverse_line("hello"), "olleh\n");
+        assert_eq!(reverse_line("a b\r"), "b a\n");
     }
 }
@@ -1,244 +1,925 @@
-use log::info;
-use std::net::SocketAddr;
-use tokio::sync::mpsc::Receiver;
-
-use super::message::{Message, Payload, SessionId};
+use crate::admin::{self, Registered};
+use crate::config::seconds;
+use crate::limiter::{Action, Limit, Limiter, Verdict};
+use crate::metrics::metrics;
+use crate::service::UNIX_PEER;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::sync::RwLock;
-use tokio::net::UdpSocket;
-
+use std::task::{Context, Poll};
 use std::time::Duration;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, DuplexStream, ReadBuf},
+    net::{ToSocketAddrs, UdpSocket, UnixDatagram},
+    sync::mpsc::{
+        channel, error::TrySendError, unbounded_channel, Receiver, Sender, UnboundedSender,
+    },
+    task::JoinHandle,
+    time::{interval_at, Instant},
+};
+use tracing::{error, info, info_span, Instrument};
 
-/// If we receive no data from a connection for this long, we will close it.
+use super::message::{Message, Payload, SessionId};
+
+/// Largest datagram we will read from the socket.
+const BLOCK_SIZE: usize = 1024;
+/// Packets waiting for a session, and streams waiting to be accepted.
+const CHANNEL_SIZE: usize = 100;
+/// If we have sent data and heard no ack for this long, we will close the session.
 const CONNECTION_TIMEOUT: Duration = Duration::from_secs(20);
 /// If we don't receive an ack of a data packet after this amount of time,
 /// we will send it again.
 const RETRANSMISSION_TIMEOUT: Duration = Duration::from_secs(3);
 
-pub struct LrcpSession {
+/// Tuning for an LRCP listener, defaulting to the constants above. Timeouts
+/// are given in seconds in the config file.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LrcpConfig {
+    pub block_size: usize,
+    pub channel_size: usize,
+    #[serde(deserialize_with = "seconds")]
+    pub connection_timeout: Duration,
+    #[serde(deserialize_with = "seconds")]
+    pub retransmission_timeout: Duration,
+}
+
+impl LrcpConfig {
+    /// Checks nothing is zero, which the sockets and timers can't work with.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (key, zero) in [
+            ("block_size", self.block_size == 0),
+            ("channel_size", self.channel_size == 0),
+            ("connection_timeout", self.connection_timeout.is_zero()),
+            (
+                "retransmission_timeout",
+                self.retransmission_timeout.is_zero(),
+            ),
+        ] {
+            anyhow::ensure!(!zero, "{} must be above zero", key);
+        }
+        Ok(())
+    }
+}
+
+impl Default for LrcpConfig {
+    fn default() -> Self {
+        Self {
+            block_size: BLOCK_SIZE,
+            channel_size: CHANNEL_SIZE,
+            connection_timeout: CONNECTION_TIMEOUT,
+            retransmission_timeout: RETRANSMISSION_TIMEOUT,
+        }
+    }
+}
+
+/// Application bytes per data packet. Escaping can double this, which still
+/// keeps packets under the 1000 byte limit.
+const MAX_CHUNK_SIZE: usize = 450;
+/// Stop reading from the application while this much sent data is unacked.
+const MAX_UNACKED: usize = 64 * 1024;
+/// Buffer between a session and its application.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Accepts LRCP sessions on a UDP socket, and optionally a Unix datagram
+/// socket, yielding each as a byte stream.
+pub struct LrcpListener {
+    local_addr: SocketAddr,
+    incoming: Receiver<(LrcpStream, SocketAddr)>,
+    socket_tasks: Vec<JoinHandle<()>>,
+}
+
+impl LrcpListener {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::from_socket(
+            UdpSocket::bind(addr).await?,
+            LrcpConfig::default(),
+            Limiter::unlimited(),
+        )
+    }
+
+    /// Serves LRCP on `socket`. Each peer's sessions count as its connections
+    /// and its packets as its messages, see `run_socket`.
+    pub fn from_socket(
+        socket: UdpSocket,
+        config: LrcpConfig,
+        limiter: Limiter,
+    ) -> io::Result<Self> {
+        Self::from_sockets(socket, None, config, limiter)
+    }
+
+    /// Like `from_socket`, also serving LRCP on `unix` for clients on this
+    /// host. Those go by `UNIX_PEER` to the application, and the limiter lets
+    /// them through.
+    pub fn from_sockets(
+        socket: UdpSocket,
+        unix: Option<UnixDatagram>,
+        config: LrcpConfig,
+        limiter: Limiter,
+    ) -> io::Result<Self> {
+        let local_addr = socket.local_addr()?;
+        let (incoming_tx, incoming) = channel(config.channel_size);
+        let sockets =
+            std::iter::once(PacketSocket::Udp(socket)).chain(unix.map(PacketSocket::Unix));
+        let socket_tasks = sockets
+            .map(|socket| {
+                let run = run_socket(
+                    Arc::new(socket),
+                    incoming_tx.clone(),
+                    config,
+                    limiter.clone(),
+                );
+                tokio::spawn(run.in_current_span())
+            })
+            .collect();
+
+        Ok(Self {
+            local_addr,
+            incoming,
+            socket_tasks,
+        })
+    }
+
+    pub async fn accept(&mut self) -> io::Result<(LrcpStream, SocketAddr)> {
+        self.incoming
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::other("LRCP socket has stopped"))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    /// Stops accepting sessions and waits for the open ones to close. Dropping
+    /// the listener does the same without waiting.
+    pub async fn close(self) {
+        drop(self.incoming);
+        for task in self.socket_tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+/// A socket LRCP packets arrive on and are answered from.
+#[derive(Debug)]
+enum PacketSocket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+impl PacketSocket {
+    /// Receives the next packet, with its sender if it can be answered, which
+    /// Unix peers can only be once they've bound a path.
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<Peer>)> {
+        match self {
+            PacketSocket::Udp(socket) => {
+                let (num_bytes, address) = socket.recv_from(buf).await?;
+                Ok((num_bytes, Some(Peer::Udp(address))))
+            }
+            PacketSocket::Unix(socket) => {
+                let (num_bytes, address) = socket.recv_from(buf).await?;
+                let path = address.as_pathname().map(|path| Peer::Unix(path.into()));
+                Ok((num_bytes, path))
+            }
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], peer: &Peer) -> io::Result<usize> {
+        match (self, peer) {
+            (PacketSocket::Udp(socket), Peer::Udp(address)) => socket.send_to(buf, address).await,
+            (PacketSocket::Unix(socket), Peer::Unix(path)) => socket.send_to(buf, path).await,
+            _ => Err(io::Error::other(format!("{} is on another socket", peer))),
+        }
+    }
+}
+
+/// Where a packet came from, and where answers to it go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Peer {
+    Udp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Peer {
+    /// The address the peer goes by outside LRCP.
+    fn address(&self) -> SocketAddr {
+        match self {
+            Peer::Udp(address) => *address,
+            Peer::Unix(_) => UNIX_PEER,
+        }
+    }
+
+    /// The IP the access list and limits apply to. Unix peers are on this host
+    /// and exempt.
+    fn limited_ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Udp(address) => Some(address.ip()),
+            Peer::Unix(_) => None,
+        }
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Udp(address) => address.fmt(f),
+            Peer::Unix(path) => path.display().fmt(f),
+        }
+    }
+}
+
+/// One LRCP session, seen from the application. Reads return the peer's data
+/// in order, and writes are delivered reliably. Shutting down or dropping the
+/// stream closes the session once everything written has been acked.
+#[derive(Debug)]
+pub struct LrcpStream {
+    inner: DuplexStream,
+    peer_addr: SocketAddr,
+    session_id: u32,
+}
+
+impl LrcpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+}
+
+impl AsyncRead for LrcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LrcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+type Sessions = BTreeMap<SessionId, Session>;
+struct Session {
+    tx: Sender<Message>,
+    peer: Peer,
+    // The peer's connection slot, given back when the session is removed
+    _limit: Limit,
+}
+
+/// Reads packets from the socket and routes them to their sessions. Sessions
+/// send a close message back through `tx` when they end. Once the listener is
+/// gone, new sessions are refused and the task ends with the last session.
+///
+/// Packets from peers outside the access list are ignored. Packets over a
+/// peer's rate are too, or with the disconnect action are taken as a close of
+/// their session.
+async fn run_socket(
+    socket: Arc<PacketSocket>,
+    incoming: Sender<(LrcpStream, SocketAddr)>,
+    config: LrcpConfig,
+    limiter: Limiter,
+) {
+    let (tx, mut rx) = unbounded_channel::<Message>();
+    let mut sessions = Sessions::new();
+    let mut buf = vec![0u8; config.block_size];
+    let mut accepting = true;
+
+    while accepting || !sessions.is_empty() {
+        tokio::select! {
+            result = read_message(&socket, &mut buf) => {
+                let (mut message, peer) = match result {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        error!("Failed to receive packet: {}", e);
+                        return;
+                    }
+                };
+                info!(%peer, "Received packet");
+                if let Some(ip) = peer.limited_ip() {
+                    if !limiter.permits(ip) {
+                        continue;
+                    }
+                    match limiter.datagram(ip) {
+                        Verdict::Allow => {}
+                        Verdict::Drop => continue,
+                        Verdict::Disconnect => message = Message::new_close(message.session),
+                    }
+                }
+                handle_client_message(message, peer, &socket, &tx, &incoming, &mut sessions, config, &limiter).await;
+            },
+
+            Some(message) = rx.recv() => {
+                info!("Received packet from main channel: {:?}", message);
+                handle_response(message, &socket, &mut sessions).await;
+            }
+
+            _ = incoming.closed(), if accepting => {
+                info!("Listener closed, waiting for {} sessions", sessions.len());
+                accepting = false;
+            }
+        }
+    }
+}
+
+async fn read_message(socket: &PacketSocket, buf: &mut [u8]) -> io::Result<(Message, Peer)> {
+    loop {
+        let (num_bytes, src) = socket.recv_from(buf).await?;
+        let Some(src) = src else {
+            error!("Ignoring packet from an unbound Unix socket, which can't be answered");
+            continue;
+        };
+
+        match Message::parse(&buf[..num_bytes]) {
+            Ok(packet) => return Ok((packet, src)),
+            Err(e) => {
+                error!("Failed to parse packet: {}", e);
+                metrics().parse_error("lrcp");
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_client_message(
+    message: Message,
+    peer: Peer,
+    socket: &Arc<PacketSocket>,
+    tx: &UnboundedSender<Message>,
+    incoming: &Sender<(LrcpStream, SocketAddr)>,
+    sessions: &mut Sessions,
+    config: LrcpConfig,
+    limiter: &Limiter,
+) {
+    info!(%peer, "Handling client message: {:?}", &message);
+
+    if message.payload == Payload::Connect && !sessions.contains_key(&message.session) {
+        // Ignoring the connect leaves the peer retrying until a slot frees up
+        let limit = match peer.limited_ip() {
+            Some(ip) => limiter.try_admit(ip),
+            None => Some(Limit::none()),
+        };
+        let Some(limit) = limit else {
+            if limiter.limits().action == Action::Disconnect {
+                respond(socket, Message::new_close(message.session), &peer).await;
+            }
+            return;
+        };
+
+        // Create a new session
+        info!(session = %message.session, %peer, "Creating a new session");
+        let (packet_tx, packet_rx) = channel::<Message>(config.channel_size);
+        let (app, transport) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+
+        let stream = LrcpStream {
+            inner: app,
+            peer_addr: peer.address(),
+            session_id: message.session.value(),
+        };
+        match incoming.try_send((stream, peer.address())) {
+            Ok(()) => {}
+            // The peer will retry the connect
+            Err(TrySendError::Full(_)) => {
+                error!("Too many sessions waiting to be accepted");
+                return;
+            }
+            Err(TrySendError::Closed(_)) => {
+                respond(socket, Message::new_close(message.session), &peer).await;
+                return;
+            }
+        }
+
+        sessions.insert(
+            message.session.clone(),
+            Session {
+                tx: packet_tx,
+                peer: peer.clone(),
+                _limit: limit,
+            },
+        );
+
+        // Spawn a new task to handle the session
+        let session = LrcpSession::new(
+            message.session.clone(),
+            socket.clone(),
+            peer.clone(),
+            packet_rx,
+            tx.clone(),
+            transport,
+            config,
+        );
+        let span = info_span!("session", session = %message.session, %peer);
+        tokio::spawn(session.run().instrument(span));
+    }
+
+    match sessions.get(&message.session) {
+        // Only the peer that opened a session can send to or close it
+        Some(session) if session.peer != peer => {
+            error!(session = %message.session, %peer, "Ignoring packet from another peer");
+        }
+
+        Some(session) => match session.tx.try_send(message) {
+            Ok(()) => {}
+            // Dropping the packet is fine, the peer will send it again
+            Err(TrySendError::Full(message)) => {
+                error!("Session busy, dropping packet: {:?}", message);
+            }
+            Err(TrySendError::Closed(_)) => {}
+        },
+
+        // Anything for a session we don't know gets closed
+        None => {
+            error!(session = %message.session, %peer, "Session doesn't exist");
+            respond(socket, Message::new_close(message.session), &peer).await;
+        }
+    }
+}
+
+async fn handle_response(message: Message, socket: &PacketSocket, sessions: &mut Sessions) {
+    match message.payload {
+        Payload::Close => {
+            // If the session doesn't exist, ignore the message
+            if let Some(session) = sessions.remove(&message.session) {
+                respond(socket, message, &session.peer).await;
+            } else {
+                error!("Session doesn't exist: {:?}", message.session);
+            }
+        }
+
+        _ => {
+            // If the session doesn't exist, ignore the message
+            if let Some(session) = sessions.get(&message.session) {
+                respond(socket, message, &session.peer).await;
+            } else {
+                error!("Session doesn't exist: {:?}", message.session);
+            }
+        }
+    }
+}
+
+async fn respond(socket: &PacketSocket, message: Message, peer: &Peer) {
+    let bytes = message.to_packet();
+    match socket.send_to(&bytes, peer).await {
+        Ok(_num_bytes) => {
+            info!(%peer, "Sent packet");
+        }
+        Err(e) => {
+            error!("Failed to send packet: {}", e);
+        }
+    }
+}
+
+/// Moves data between one peer and its application stream, acking what we
+/// receive and retransmitting what hasn't been acked.
+struct LrcpSession {
     // Identifies the session
     id: SessionId,
 
-    // The address of the client
-    address: SocketAddr,
-    socket: Arc<UdpSocket>,
+    // Where the client is
+    peer: Peer,
+    socket: Arc<PacketSocket>,
 
     message_rx: Receiver<Message>,
+    // Tells the socket task when the session closes
+    response_tx: UnboundedSender<Message>,
 
-    connected: bool,
-    data: String,
+    // Our end of the application's stream
+    app: DuplexStream,
+    app_closed: bool,
 
     bytes_received: u32,
     bytes_sent: u32,
-    bytes_acked: Arc<RwLock<u32>>,
+    bytes_acked: u32,
+    // Everything after `bytes_acked`, kept for retransmission
+    unacked: Vec<u8>,
+    last_ack: Instant,
+    // When the peer last sent anything, for expiring idle sessions
+    last_received: Instant,
+
+    config: LrcpConfig,
+    // Lists the session in the admin interface, which can also kill it
+    admin: Registered<admin::Session>,
 }
 
 impl LrcpSession {
-    pub fn new(
+    fn new(
         id: SessionId,
-        socket: Arc<UdpSocket>,
-        address: SocketAddr,
+        socket: Arc<PacketSocket>,
+        peer: Peer,
         message_rx: Receiver<Message>,
+        response_tx: UnboundedSender<Message>,
+        app: DuplexStream,
+        config: LrcpConfig,
     ) -> Self {
+        let admin = admin::registry().add_session(id.value(), peer.address());
         Self {
             id,
-            address,
+            peer,
             socket,
             message_rx,
-            connected: false,
-            data: String::new(),
+            response_tx,
+            app,
+            app_closed: false,
             bytes_received: 0,
             bytes_sent: 0,
-            bytes_acked: Arc::new(RwLock::new(0)),
+            bytes_acked: 0,
+            unacked: Vec::new(),
+            last_ack: Instant::now(),
+            last_received: Instant::now(),
+            config,
+            admin,
         }
     }
 
-    pub async fn run(&mut self) {
-        info!(
-            "New session connected. session_id={:?}, peer_address={}",
-            self.id, self.address
-        );
+    async fn run(mut self) {
+        info!("New session connected");
+        metrics().lrcp_sessions_opened.inc();
+        metrics().lrcp_active_sessions.inc();
+
+        let period = self.config.retransmission_timeout;
+        let mut retransmission_timeout = interval_at(Instant::now() + period, period);
+        let mut buf = [0u8; MAX_CHUNK_SIZE];
+        let killed = self.admin.killed.clone();
 
         loop {
+            let can_send = !self.app_closed && self.unacked.len() < MAX_UNACKED;
+
             tokio::select! {
-                Some(message) = self.message_rx.recv() => {
-                    if self.handle_message(message).await.is_err() {
-                        info!("Session closed. session={:?}, address={}", self.id, self.address);
+                message = self.message_rx.recv() => {
+                    let Some(message) = message else {
+                        break;
                     };
+                    if let Err(e) = self.handle_message(message).await {
+                        info!("Session closing: {}", e);
+                        break;
+                    }
                 }
+
+                result = self.app.read(&mut buf), if can_send => {
+                    match result {
+                        Ok(0) | Err(_) => self.app_closed = true,
+                        Ok(num_bytes) => self.send_data(&buf[..num_bytes]).await,
+                    }
+                }
+
+                _ = retransmission_timeout.tick() => {
+                    let timeout = self.config.connection_timeout;
+                    let unanswered = !self.unacked.is_empty() && self.last_ack.elapsed() > timeout;
+                    if unanswered || self.last_received.elapsed() > timeout {
+                        info!("Session timed out");
+                        break;
+                    }
+                    self.retransmit().await;
+                }
+
+                _ = killed.cancelled() => {
+                    info!("Session killed from the admin interface");
+                    break;
+                }
+            }
+            self.update_stats();
+
+            // The application is done and the peer has everything it wrote
+            if self.app_closed && self.unacked.is_empty() {
+                break;
             }
         }
+
+        self.close();
     }
 
-    async fn ack(&self, position: u32) -> anyhow::Result<()> {
-        let response = Message::new_ack(self.id.clone(), position);
-        info!("Acking message: {:?}", &response);
-        match self
-            .socket
-            .send_to(&response.to_packet(), &self.address)
-            .await
-        {
-            Ok(_) => Ok(()),
-            Err(e) => Err(anyhow::anyhow!("Failed to send packet: {}", e)),
+    fn update_stats(&self) {
+        self.admin
+            .bytes_received
+            .store(self.bytes_received, Ordering::Relaxed);
+        self.admin
+            .bytes_sent
+            .store(self.bytes_sent, Ordering::Relaxed);
+        self.admin
+            .bytes_acked
+            .store(self.bytes_acked, Ordering::Relaxed);
+    }
+
+    async fn send(&self, message: Message) {
+        if let Err(e) = self.socket.send_to(&message.to_packet(), &self.peer).await {
+            error!("Failed to send packet: {}", e);
         }
     }
 
-    async fn close(&mut self) -> anyhow::Result<()> {
-        let response = Message::new_close(self.id.clone());
-        info!("Closing session: {:?}", &response);
-        self.socket
-            .send_to(&response.to_packet(), &self.address)
-            .await
-            .expect("Failed to send packet");
+    async fn ack(&self, position: u32) {
+        let response = Message::new_ack(self.id.clone(), position);
+        info!("Acking message: {:?}", &response);
+        metrics().lrcp_acks.with_label_values(&["sent"]).inc();
+        self.send(response).await;
+    }
 
+    fn close(&mut self) {
+        info!("Closing session");
+        metrics().lrcp_active_sessions.dec();
         self.message_rx.close();
-
-        Err(anyhow::anyhow!("Session closed"))
+        let _ = self.response_tx.send(Message::new_close(self.id.clone()));
     }
 
     async fn handle_message(&mut self, msg: Message) -> anyhow::Result<()> {
         info!("Handling new message: {:?}", &msg);
+        self.last_received = Instant::now();
         match msg.payload {
             Payload::Connect => {
-                self.connected = true;
-                self.ack(0).await
+                self.ack(0).await;
+                Ok(())
             }
 
-            Payload::Close => self.close().await,
+            Payload::Close => Err(anyhow::anyhow!("Closed by peer")),
 
             Payload::Ack { position } => {
-                if !self.connected {
-                    self.close().await?;
+                metrics().lrcp_acks.with_label_values(&["received"]).inc();
+                if position <= self.bytes_acked {
+                    return Ok(());
                 }
 
                 if position > self.bytes_sent {
-                    info!(
-                        "Unexpected Ack: {:?}. Current Bytes Sent: {}",
-                        &msg, self.bytes_sent
+                    anyhow::bail!(
+                        "Unexpected Ack: {}. Current Bytes Sent: {}",
+                        position,
+                        self.bytes_sent
                     );
-                    self.close().await?;
                 }
 
-                let mut acked_bytes = self.bytes_acked.write().unwrap();
-                *acked_bytes = position;
+                self.unacked.drain(..(position - self.bytes_acked) as usize);
+                self.bytes_acked = position;
+                self.last_ack = Instant::now();
+
+                if position < self.bytes_sent {
+                    self.retransmit().await;
+                }
 
                 Ok(())
             }
 
             Payload::Data { data, position } => {
-                if !self.connected {
-                    self.close().await?;
-                }
-
                 if position > self.bytes_received {
-                    self.ack(self.bytes_received).await?;
+                    self.ack(self.bytes_received).await;
                     return Ok(());
                 }
 
-                let data_position = self.bytes_received - position;
-                if data_position as usize > data.len() {
+                let data_position = (self.bytes_received - position) as usize;
+                if data_position < data.len() {
+                    let new_data = &data[data_position..];
+                    // Only what the application takes now is acked, the peer
+                    // resends the rest. Waiting for room would stall the session.
+                    let accepted = if self.app_closed {
+                        new_data.len()
+                    } else {
+                        match try_write(&mut self.app, new_data).await {
+                            Ok(written) => written,
+                            Err(_) => {
+                                info!("Application has gone away");
+                                self.app_closed = true;
+                                new_data.len()
+                            }
+                        }
+                    };
+                    self.bytes_received += accepted as u32;
+                } else {
                     info!(
                         "Message already seen. Current Bytes Received: {}",
                         self.bytes_received
                     );
-                    return Ok(());
                 }
 
-                let new_data = &data[data_position as usize..];
-                self.data.push_str(&String::from_utf8_lossy(new_data));
-                self.bytes_received += new_data.len() as u32;
-                self.ack(self.bytes_received).await?;
-
-                if new_data.contains(&b'\n') {
-                    for line in self
-                        .data
-                        .split_inclusive('\n')
-                        .filter(|line| line.ends_with('\n'))
-                    {
-                        let reversed_line = reverse_line(line);
-                        self.send_line(reversed_line).await;
-                    }
-                    if let Some(last_str) = self.data.split_inclusive('\n').last() {
-                        if last_str.ends_with('\n') {
-                            info!("Clearing buffer data. session_id={:?}", self.id);
-                            self.data.clear();
-                        } else {
-                            info!(
-                                "Dropping already sent buffer data. session_id={:?}",
-                                self.id
-                            );
-                            self.data = last_str.to_owned();
-                        }
-                    }
-                }
+                self.ack(self.bytes_received).await;
                 Ok(())
             }
         }
     }
 
-    async fn send_line(&self, line: String) {
-        let messages = chunk_lines(line)
-            .iter()
-            .map(|line| {
-                let position = self.bytes_sent;
-                let message =
-                    Message::new_data(self.id.clone(), line.as_bytes().to_vec(), position);
-                message
-            })
-            .collect();
+    async fn send_data(&mut self, data: &[u8]) {
+        if self.unacked.is_empty() {
+            // Start the timeout from the first data the peer hasn't acked
+            self.last_ack = Instant::now();
+        }
 
-        // Timeout
-        //
-        tokio::spawn(send_messages(
-            self.socket.clone(),
-            self.address,
-            messages,
-            self.bytes_acked.clone(),
-        ));
+        let message = Message::new_data(self.id.clone(), data.to_vec(), self.bytes_sent);
+        self.unacked.extend_from_slice(data);
+        self.bytes_sent += data.len() as u32;
+        self.send(message).await;
+    }
+
+    async fn retransmit(&self) {
+        let mut position = self.bytes_acked;
+        for chunk in self.unacked.chunks(MAX_CHUNK_SIZE) {
+            let message = Message::new_data(self.id.clone(), chunk.to_vec(), position);
+            self.send(message).await;
+            metrics().lrcp_retransmissions.inc();
+            position += chunk.len() as u32;
+        }
     }
 }
 
-fn chunk_lines(line: String) -> Vec<String> {
-    line.as_bytes()
-        .chunks(900)
-        .map(|chunk| String::from_utf8_lossy(chunk).to_string())
-        .collect::<Vec<String>>()
+/// Writes as much of `data` as `app` has room for without waiting.
+async fn try_write(app: &mut DuplexStream, data: &[u8]) -> io::Result<usize> {
+    std::future::poll_fn(|cx| match Pin::new(&mut *app).poll_write(cx, data) {
+        Poll::Pending => Poll::Ready(Ok(0)),
+        written => written,
+    })
+    .await
 }
 
-async fn send_messages(
-    socket: Arc<UdpSocket>,
-    addr: SocketAddr,
-    messages: Vec<Message>,
-    bytes_acked: Arc<RwLock<u32>>,
-) {
-    let mut retransmission_timeout = tokio::time::interval(Duration::from_secs(3));
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
 
-    loop {
-        tokio::select! {
-            biased;
+    async fn exchange(client: &UdpSocket, server: SocketAddr, packet: &str) -> String {
+        client.send_to(packet.as_bytes(), server).await.unwrap();
+        recv(client).await
+    }
 
-            _ = retransmission_timeout.tick() => {
-                let most_recent_ack = { *bytes_acked.read().unwrap() };
-                let mut all_messages_acked = true;
+    async fn recv(client: &UdpSocket) -> String {
+        let mut buf = [0u8; BLOCK_SIZE];
+        let (n, _) = client.recv_from(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
 
-                for message in &messages {
-                    if let Payload::Data { position, ..} = message.payload {
-                        if position > most_recent_ack {
-                            all_messages_acked = false;
-                            socket.send_to(&message.to_packet(), &addr).await.unwrap();
-                        }
-                    }
-                }
-                if all_messages_acked {
-                    break;
-                }
+    #[tokio::test]
+    async fn stream_over_lrcp() {
+        let mut listener = LrcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        assert_eq!(exchange(&client, server, "/connect/1/").await, "/ack/1/0/");
+        let (mut stream, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+
+        assert_eq!(
+            exchange(&client, server, "/data/1/0/hel\\/lo/").await,
+            "/ack/1/6/"
+        );
+        // A repeat is acked again but not delivered twice
+        assert_eq!(
+            exchange(&client, server, "/data/1/0/hel\\/lo/").await,
+            "/ack/1/6/"
+        );
+        let mut buf = [0u8; 6];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hel/lo");
+
+        stream.write_all(b"back\\").await.unwrap();
+        assert_eq!(recv(&client).await, "/data/1/0/back\\\\/");
+        client.send_to(b"/ack/1/5/", server).await.unwrap();
+
+        // Closing the stream closes the session once everything is acked
+        drop(stream);
+        assert_eq!(recv(&client).await, "/close/1/");
+        assert_eq!(exchange(&client, server, "/data/1/6/x/").await, "/close/1/");
+    }
+
+    #[tokio::test]
+    async fn killed_sessions_are_closed() {
+        let mut listener = LrcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer = client.local_addr().unwrap();
+
+        assert_eq!(exchange(&client, server, "/connect/2/").await, "/ack/2/0/");
+        assert_eq!(
+            exchange(&client, server, "/data/2/0/abc/").await,
+            "/ack/2/3/"
+        );
+        let (_stream, _) = listener.accept().await.unwrap();
+
+        let (id, session) = admin::registry()
+            .sessions()
+            .into_iter()
+            .find(|(_, session)| session.peer == peer)
+            .unwrap();
+        assert_eq!(session.session, 2);
+        assert_eq!(session.bytes_received.load(Ordering::Relaxed), 3);
+
+        assert!(admin::registry().kill(id));
+        assert_eq!(recv(&client).await, "/close/2/");
+        assert_eq!(exchange(&client, server, "/data/2/3/d/").await, "/close/2/");
+        assert!(admin::registry().sessions().iter().all(|(i, _)| *i != id));
+    }
+
+    #[tokio::test]
+    async fn peers_that_never_ack_dont_stall_the_session() {
+        let mut listener = LrcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        assert_eq!(exchange(&client, server, "/connect/3/").await, "/ack/3/0/");
+        let (stream, _) = listener.accept().await.unwrap();
+        // Echoes until what it writes backs up, then stops reading too
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(stream);
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let next_ack = async || loop {
+            let packet = tokio::time::timeout(Duration::from_secs(2), recv(&client))
+                .await
+                .expect("the session stopped answering");
+            if let Some(position) = packet.strip_prefix("/ack/3/") {
+                return position.trim_end_matches('/').parse::<usize>().unwrap();
+            }
+        };
+
+        // Sends data without acking any of the echo until the session stops
+        // taking it
+        let data = "a".repeat(900);
+        let mut position = 0;
+        for _ in 0..1000 {
+            let packet = format!("/data/3/{}/{}/", position, data);
+            client.send_to(packet.as_bytes(), server).await.unwrap();
+            let acked = next_ack().await;
+            if acked == position {
+                break;
+            }
+            position = acked;
+        }
+        assert!(position < 1000 * data.len());
+
+        // It still answers
+        client.send_to(b"/close/3/", server).await.unwrap();
+        loop {
+            let packet = tokio::time::timeout(Duration::from_secs(2), recv(&client))
+                .await
+                .expect("the session stopped answering");
+            if packet == "/close/3/" {
+                break;
             }
         }
     }
-}
 
-fn reverse_line(line: &str) -> String {
-    let mut reversed_line: String = line.trim_end().chars().rev().collect();
-    reversed_line.push('\n');
-    reversed_line
+    #[tokio::test]
+    async fn other_peers_cant_use_a_session() {
+        let mut listener = LrcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let intruder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        assert_eq!(exchange(&client, server, "/connect/4/").await, "/ack/4/0/");
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        for packet in ["/connect/4/", "/data/4/0/evil/", "/close/4/"] {
+            intruder.send_to(packet.as_bytes(), server).await.unwrap();
+        }
+        let answer = tokio::time::timeout(Duration::from_millis(100), recv(&intruder)).await;
+        assert!(answer.is_err(), "unexpected {:?}", answer);
+
+        // The session carries on as if nothing happened
+        assert_eq!(
+            exchange(&client, server, "/data/4/0/good/").await,
+            "/ack/4/4/"
+        );
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"good");
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_expire() {
+        let config = LrcpConfig {
+            connection_timeout: Duration::from_millis(200),
+            retransmission_timeout: Duration::from_millis(50),
+            ..LrcpConfig::default()
+        };
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut listener = LrcpListener::from_socket(socket, config, Limiter::unlimited()).unwrap();
+        let server = listener.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        assert_eq!(exchange(&client, server, "/connect/5/").await, "/ack/5/0/");
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        // Nothing is unacked, but the peer has gone quiet
+        let closed = tokio::time::timeout(Duration::from_secs(1), recv(&client)).await;
+        assert_eq!(closed.unwrap(), "/close/5/");
+        assert_eq!(stream.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
 }
@@ -1,14 +1,27 @@
 use nom::branch::alt;
 use nom::bytes::complete::{escaped_transform, is_not, tag};
 use nom::character::complete::char;
-use nom::combinator::value;
+use nom::combinator::{eof, value};
 use nom::sequence::delimited;
 use nom::{character::complete::digit1, IResult};
 use nom::{error, AsBytes};
+use std::fmt::{Display, Formatter};
 
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub struct SessionId(u32);
 
+impl SessionId {
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Display for SessionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Payload {
     Connect,
@@ -32,17 +45,6 @@ impl Message {
         Ok(message)
     }
 
-    pub fn session_id(&self) -> &SessionId {
-        &self.session
-    }
-
-    pub fn new_connect(session: SessionId) -> Self {
-        Self {
-            session,
-            payload: Payload::Connect,
-        }
-    }
-
     pub fn new_close(session: SessionId) -> Self {
         Self {
             session,
@@ -96,26 +98,25 @@ impl Message {
 fn parse_message(input: &[u8]) -> IResult<&[u8], Message> {
     // /data/123/1/Hello, World!/
     let (input, message_kind) =
-        delimited(char::<&[u8], error::Error<_>>('/'), is_not("/"), char('/'))(input)
-            .expect("Failed to parse message kind");
+        delimited(char::<&[u8], error::Error<_>>('/'), is_not("/"), char('/'))(input)?;
 
     // 123/1/Hello, World!/
     let (input, session_id) = parse_u32_from_digits(input)?;
 
     // 1/Hello, World!/
-    let payload = match message_kind.as_bytes() {
-        b"connect" => Payload::Connect,
-        b"close" => Payload::Close,
+    let (input, payload) = match message_kind.as_bytes() {
+        b"connect" => (char('/')(input)?.0, Payload::Connect),
+        b"close" => (char('/')(input)?.0, Payload::Close),
 
         b"ack" => {
-            let (_input, position) = delimited(char('/'), parse_u32_from_digits, char('/'))(input)?;
-            Payload::Ack { position }
+            let (input, position) = delimited(char('/'), parse_u32_from_digits, char('/'))(input)?;
+            (input, Payload::Ack { position })
         }
 
         b"data" => {
             let (input, position) = delimited(char('/'), parse_u32_from_digits, char('/'))(input)?;
 
-            let (_input, data) = escaped_transform(
+            let (input, data) = escaped_transform(
                 is_not::<&str, &[u8], error::Error<&[u8]>>(r#"\/"#),
                 '\\',
                 alt((
@@ -123,11 +124,15 @@ fn parse_message(input: &[u8]) -> IResult<&[u8], Message> {
                     value(b"/".as_slice(), tag(b"/")),
                 )),
             )(input)?;
+            let (input, _) = char('/')(input)?;
 
-            Payload::Data {
-                data: data.to_vec(),
-                position,
-            }
+            (
+                input,
+                Payload::Data {
+                    data: data.to_vec(),
+                    position,
+                },
+            )
         }
 
         _ => {
@@ -138,6 +143,9 @@ fn parse_message(input: &[u8]) -> IResult<&[u8], Message> {
         }
     };
 
+    // Nothing may follow the final slash
+    let (input, _) = eof(input)?;
+
     let message = Message {
         session: SessionId(session_id),
         payload,
@@ -146,12 +154,20 @@ fn parse_message(input: &[u8]) -> IResult<&[u8], Message> {
     Ok((input, message))
 }
 
+/// Numbers in LRCP must be smaller than 2147483648.
+const MAX_NUMBER: u32 = i32::MAX as u32;
+
 fn parse_u32_from_digits(input: &[u8]) -> IResult<&[u8], u32> {
     let (input, digits) = digit1(input)?;
     let number_str = std::str::from_utf8(digits).expect("Failed to parse session id");
     let number = number_str
         .parse::<u32>()
-        .map_err(|_| nom::Err::Error(error::Error::new(input, error::ErrorKind::Digit)))?;
+        .ok()
+        .filter(|number| *number <= MAX_NUMBER)
+        .ok_or(nom::Err::Error(error::Error::new(
+            input,
+            error::ErrorKind::Digit,
+        )))?;
     Ok((input, number))
 }
 
@@ -201,4 +217,23 @@ mod test {
 
         assert_eq!(message.to_packet(), bytes);
     }
+
+    #[test]
+    fn parse_invalid() {
+        for bytes in [
+            b"".as_slice(),
+            b"hello",
+            b"/connect/",
+            b"/connect/123",
+            b"/connect/123/456/",
+            b"/ack/123/",
+            b"/data/123/456/Hello/World/",
+            b"/data/123/456/Hello",
+            b"/dance/123/",
+            b"/connect/2147483648/",
+            b"/ack/1/4294967296/",
+        ] {
+            assert!(parse_message(bytes).is_err(), "{:?}", bytes);
+        }
+    }
 }
@@ -1,27 +1,563 @@
-use log::info;
-use protohackers_rs::{insecure_sockets, line_reversal, means_to_an_end, prime_time, smoke_test};
-use tokio::join;
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand};
+use protohackers_rs::{
+    access::AccessList,
+    admin::{self, LogFilter},
+    config::{Config, LogFormat},
+    limiter::Limiter,
+    metrics,
+    service::{registry, ListenOptions, Service, Transport},
+    shutdown::Shutdown,
+    supervisor::Supervisor,
+};
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinSet;
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter};
+
+const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+#[derive(Parser)]
+#[command(name = "protohackers", about = "Servers for the Protohackers problems")]
+struct Cli {
+    /// Config file with settings for each service
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Log format, overriding the config file
+    #[arg(long, global = true, value_enum, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run services, all of them if none are named
+    Serve(ServeArgs),
+    /// List the available services and their default addresses
+    List,
+}
+
+#[derive(Args, Default)]
+struct ServeArgs {
+    /// Services to run
+    #[arg(value_parser = service_name)]
+    services: Vec<String>,
+
+    /// Address to bind, for every service or for one as SERVICE=ADDR
+    #[arg(long, value_name = "[SERVICE=]ADDR", value_parser = parse_override::<IpAddr>)]
+    bind: Vec<Override<IpAddr>>,
+
+    /// Port for a service as SERVICE=PORT, or just PORT when running one service
+    #[arg(long, value_name = "[SERVICE=]PORT", value_parser = parse_override::<u16>)]
+    port: Vec<Override<u16>>,
+
+    /// Serve Prometheus metrics at http://ADDR/metrics
+    #[arg(long, value_name = "ADDR")]
+    metrics: Option<SocketAddr>,
+
+    /// Serve the admin line protocol on ADDR
+    #[arg(long, value_name = "ADDR")]
+    admin: Option<SocketAddr>,
+}
+
+/// A value given either for every service or for a single named one.
+#[derive(Clone, Debug)]
+struct Override<T> {
+    service: Option<String>,
+    value: T,
+}
+
+fn parse_override<T>(s: &str) -> Result<Override<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let (service, value) = match s.split_once('=') {
+        Some((service, value)) => (Some(service_name(service)?), value),
+        None => (None, s),
+    };
+
+    let value = value
+        .parse()
+        .map_err(|e| format!("invalid value {:?}: {}", value, e))?;
+    Ok(Override { service, value })
+}
+
+fn service_name(s: &str) -> Result<String, String> {
+    if registry().iter().any(|service| service.name() == s) {
+        Ok(s.to_string())
+    } else {
+        Err(format!("unknown service {:?}, see `protohackers list`", s))
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init();
+    let cli = Cli::parse();
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let log_filter = init_logging(&config, cli.log_format.unwrap_or(config.log_format));
+
+    match cli.command.unwrap_or(Command::Serve(ServeArgs::default())) {
+        Command::Serve(args) => serve(args, config, log_filter).await,
+        Command::List => {
+            list();
+            Ok(())
+        }
+    }
+}
+
+/// Installs the global subscriber, returning its filter for the admin interface
+/// to change.
+fn init_logging(config: &Config, format: LogFormat) -> Arc<dyn LogFilter> {
+    let mut directives = vec![config.log_level.unwrap_or(LevelFilter::ERROR).to_string()];
+    for (name, service) in &config.services {
+        if let Some(level) = service.log_level {
+            directives.push(format!("protohackers_rs::{}={}", name, level));
+        }
+    }
+
+    // RUST_LOG has the last word, later directives for a target replace earlier ones
+    directives.extend(std::env::var("RUST_LOG").ok());
+    let filter = EnvFilter::builder().parse_lossy(directives.join(","));
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let logs = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let subscriber = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Text => subscriber.with(logs).init(),
+        LogFormat::Json => subscriber.with(logs.json()).init(),
+    }
+
+    Arc::new(handle)
+}
+
+fn list() {
+    println!("{:<20}{:<11}DEFAULT ADDRESS", "SERVICE", "TRANSPORT");
+    for service in registry() {
+        let addr = SocketAddr::new(DEFAULT_BIND, service.default_port());
+        println!("{:<20}{:<11}{}", service.name(), service.transport(), addr);
+    }
+}
+
+async fn serve(
+    args: ServeArgs,
+    config: Config,
+    log_filter: Arc<dyn LogFilter>,
+) -> anyhow::Result<()> {
     info!("Running Protohackers Servers");
+    let drain_timeout = config.drain_timeout;
+    let metrics_addr = args.metrics.or(config.metrics);
+    let admin_addr = args.admin.or(config.admin);
+    let shutdown = Shutdown::new();
+    let supervisor = Supervisor::new(config.restart);
+    let mut servers = JoinSet::new();
 
-    let _ = join!(
-        tokio::spawn(async move {
-            smoke_test::run("10000").await.unwrap();
-        }),
-        tokio::spawn(async move { prime_time::run("10001").await.unwrap() }),
-        tokio::spawn(async move {
-            means_to_an_end::run("10002").await.unwrap();
-        }),
-        tokio::spawn(async move {
-            line_reversal::run("10007").await.unwrap();
-        }),
-        tokio::spawn(async move {
-            insecure_sockets::run("10008").await.unwrap();
-        })
+    if let Some(addr) = metrics_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind metrics on {}", addr))?;
+        servers.spawn(metrics::serve(listener, shutdown.clone()));
+    }
+
+    if let Some(addr) = admin_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind admin on {}", addr))?;
+        servers.spawn(admin::serve(listener, log_filter, shutdown.clone()));
+    }
+
+    for resolved in resolve(args, config)? {
+        let supervisor = supervisor.clone();
+        let shutdown = shutdown.clone();
+        servers.spawn(async move {
+            let Resolved {
+                service,
+                addr,
+                options,
+                limiter,
+            } = resolved;
+            supervisor
+                .supervise(service, addr, options, limiter, shutdown)
+                .await
+        });
+    }
+
+    tokio::select! {
+        // Supervisors only stop early when a service has failed too often,
+        // which takes the rest down with it
+        Some(result) = servers.join_next() => {
+            result??;
+        }
+        result = shutdown_signal() => result?,
+    }
+
+    info!(
+        "Shutting down, draining connections for up to {:?}",
+        drain_timeout
     );
+    shutdown.trigger();
+    drain(servers, &shutdown, drain_timeout).await
+}
+
+/// Waits for the servers to stop and their connections to finish.
+async fn drain(
+    mut servers: JoinSet<anyhow::Result<()>>,
+    shutdown: &Shutdown,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let drained = async {
+        while let Some(result) = servers.join_next().await {
+            result??;
+        }
+        shutdown.drained().await;
+        anyhow::Ok(())
+    };
+
+    match tokio::time::timeout(timeout, drained).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("Connections still open after {:?}, exiting", timeout);
+            Ok(())
+        }
+    }
+}
 
+async fn shutdown_signal() -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
     Ok(())
 }
+
+/// A configured service, with where and how to listen for it and the limits
+/// and access list its peers are held to.
+struct Resolved {
+    service: Box<dyn Service>,
+    addr: SocketAddr,
+    options: ListenOptions,
+    limiter: Limiter,
+}
+
+/// Works out which services to run, configured and with the address, limits
+/// and access list for each. The command line takes precedence over the config file.
+fn resolve(args: ServeArgs, mut config: Config) -> anyhow::Result<Vec<Resolved>> {
+    for name in config.services.keys() {
+        service_name(name).map_err(anyhow::Error::msg)?;
+    }
+
+    let selected: Vec<Box<dyn Service>> = registry()
+        .into_iter()
+        .filter(|service| match args.services.is_empty() {
+            true => config
+                .services
+                .get(service.name())
+                .is_none_or(|s| s.enabled),
+            false => args.services.iter().any(|s| s == service.name()),
+        })
+        .collect();
+
+    for service in args
+        .bind
+        .iter()
+        .filter_map(|o| o.service.as_ref())
+        .chain(args.port.iter().filter_map(|o| o.service.as_ref()))
+    {
+        if !selected.iter().any(|s| s.name() == service) {
+            anyhow::bail!("{} has an override but isn't being served", service);
+        }
+    }
+
+    if selected.len() > 1 && args.port.iter().any(|o| o.service.is_none()) {
+        anyhow::bail!("--port needs a SERVICE= prefix when serving more than one service");
+    }
+
+    selected
+        .into_iter()
+        .map(|mut service| {
+            let name = service.name();
+            let service_config = config.services.remove(name).unwrap_or_default();
+            service
+                .configure(service_config.options)
+                .with_context(|| format!("Invalid options for {}", name))?;
+
+            let ip = find_override(&args.bind, name)
+                .or(service_config.bind)
+                .or(config.bind)
+                .unwrap_or(DEFAULT_BIND);
+            let port = find_override(&args.port, name)
+                .or(service_config.port)
+                .unwrap_or(service.default_port());
+            let limits = service_config.limits.unwrap_or(config.limits);
+            let access = AccessList {
+                allow: service_config.allow.unwrap_or_else(|| config.allow.clone()),
+                deny: service_config.deny.unwrap_or_else(|| config.deny.clone()),
+            };
+            let limiter = Limiter::new(name, limits, access);
+
+            // Only TCP services can be behind the PROXY protocol or use TLS
+            let tcp = service.transport() == Transport::Tcp;
+            let proxy_protocol = match service_config.proxy_protocol {
+                Some(true) if !tcp => anyhow::bail!("{} can't use the PROXY protocol", name),
+                Some(proxy_protocol) => proxy_protocol,
+                None => config.proxy_protocol && tcp,
+            };
+            let tls = match service_config.tls {
+                Some(_) if !tcp => anyhow::bail!("{} can't use TLS", name),
+                Some(tls) => Some(
+                    tls.load()
+                        .with_context(|| format!("Invalid TLS settings for {}", name))?,
+                ),
+                None => None,
+            };
+            if service_config.unix_socket.is_some() && !service.serves_unix_sockets() {
+                anyhow::bail!("{} can't listen on a Unix socket", name);
+            }
+            let options = ListenOptions {
+                proxy_protocol,
+                tls,
+                unix_socket: service_config.unix_socket,
+            };
+
+            Ok(Resolved {
+                service,
+                addr: SocketAddr::new(ip, port),
+                options,
+                limiter,
+            })
+        })
+        .collect()
+}
+
+/// The last override given for the service, falling back to one given for all.
+fn find_override<T: Copy>(overrides: &[Override<T>], service: &str) -> Option<T> {
+    let named = overrides
+        .iter()
+        .rev()
+        .find(|o| o.service.as_deref() == Some(service));
+    let all = overrides.iter().rev().find(|o| o.service.is_none());
+    named.or(all).map(|o| o.value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn resolve_args(args: &[&str]) -> anyhow::Result<Vec<(&'static str, SocketAddr)>> {
+        resolve_with_config(args, "")
+    }
+
+    fn resolve_with_config(
+        args: &[&str],
+        config: &str,
+    ) -> anyhow::Result<Vec<(&'static str, SocketAddr)>> {
+        let cli = Cli::try_parse_from(["protohackers", "serve"].iter().chain(args))?;
+        match cli.command {
+            Some(Command::Serve(args)) => Ok(resolve(args, Config::parse(config)?)?
+                .into_iter()
+                .map(|resolved| (resolved.service.name(), resolved.addr))
+                .collect()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn defaults_to_every_service() {
+        let services = resolve_args(&[]).unwrap();
+        assert_eq!(services.len(), registry().len());
+        assert_eq!(
+            services[1],
+            ("prime_time", "0.0.0.0:10001".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn overrides() {
+        assert_eq!(
+            resolve_args(&["prime_time", "--port", "4000", "--bind", "127.0.0.1"]).unwrap(),
+            [("prime_time", "127.0.0.1:4000".parse().unwrap())]
+        );
+
+        assert_eq!(
+            resolve_args(&[
+                "smoke_test",
+                "prime_time",
+                "--port",
+                "prime_time=4000",
+                "--bind",
+                "127.0.0.1",
+                "--bind",
+                "smoke_test=::1",
+            ])
+            .unwrap(),
+            [
+                ("smoke_test", "[::1]:10000".parse().unwrap()),
+                ("prime_time", "127.0.0.1:4000".parse().unwrap())
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert!(resolve_args(&["unknown"]).is_err());
+        assert!(resolve_args(&["--port", "unknown=4000"]).is_err());
+        assert!(resolve_args(&["--port", "prime_time=http"]).is_err());
+        assert!(resolve_args(&["smoke_test", "--port", "prime_time=4000"]).is_err());
+        assert!(resolve_args(&["smoke_test", "prime_time", "--port", "4000"]).is_err());
+    }
+
+    #[test]
+    fn config_file() {
+        let config = r#"
+            bind = "127.0.0.1"
+
+            [services.smoke_test]
+            port = 4000
+
+            [services.prime_time]
+            enabled = false
+
+            [services.line_reversal]
+            bind = "::1"
+            retransmission_timeout = 1.5
+        "#;
+
+        let services = resolve_with_config(&[], config).unwrap();
+        assert_eq!(services.len(), registry().len() - 1);
+        assert_eq!(
+            services[0],
+            ("smoke_test", "127.0.0.1:4000".parse().unwrap())
+        );
+        assert!(services.iter().all(|(name, _)| *name != "prime_time"));
+        assert!(services.contains(&("line_reversal", "[::1]:10007".parse().unwrap())));
+
+        // Naming a service runs it even if the config has it disabled
+        assert_eq!(
+            resolve_with_config(&["prime_time", "--port", "5000"], config).unwrap(),
+            [("prime_time", "127.0.0.1:5000".parse().unwrap())]
+        );
+
+        assert!(resolve_with_config(&[], "[services.unknown]").is_err());
+        assert!(resolve_with_config(&[], "[services.smoke_test]\nfoo = 1").is_err());
+        assert!(
+            resolve_with_config(&[], "[services.line_reversal]\nblock_size = \"big\"").is_err()
+        );
+    }
+
+    #[test]
+    fn service_limits_and_access_lists_replace_the_defaults() {
+        let config = r#"
+            deny = ["192.0.2.0/24"]
+
+            [limits]
+            max_connections = 5
+            messages_per_second = 10
+
+            [services.prime_time.limits]
+            max_connections = 2
+
+            [services.insecure_sockets]
+            allow = ["10.20.0.0/16"]
+        "#;
+
+        let limiters = resolve(ServeArgs::default(), Config::parse(config).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|resolved| (resolved.service.name(), resolved.limiter))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        let limits = |name: &str| limiters[name].limits();
+        assert_eq!(limits("smoke_test").max_connections, Some(5));
+        assert_eq!(limits("smoke_test").messages_per_second, Some(10.0));
+        assert_eq!(limits("prime_time").max_connections, Some(2));
+        assert_eq!(limits("prime_time").messages_per_second, None);
+
+        let lab = "10.20.1.2".parse().unwrap();
+        let outside = "203.0.113.9".parse().unwrap();
+        let denied = "192.0.2.1".parse().unwrap();
+        assert!(limiters["smoke_test"].access().permits(outside));
+        assert!(!limiters["smoke_test"].access().permits(denied));
+        assert!(limiters["insecure_sockets"].access().permits(lab));
+        assert!(!limiters["insecure_sockets"].access().permits(outside));
+        assert!(!limiters["insecure_sockets"].access().permits(denied));
+    }
+
+    #[test]
+    fn proxy_protocol_is_for_tcp_services() {
+        let config = r#"
+            proxy_protocol = true
+
+            [services.smoke_test]
+            proxy_protocol = false
+        "#;
+
+        let options = resolve(ServeArgs::default(), Config::parse(config).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|resolved| (resolved.service.name(), resolved.options.proxy_protocol))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        assert!(options["prime_time"]);
+        assert!(!options["smoke_test"]);
+        assert!(!options["line_reversal"]);
+
+        let udp = "[services.unusual_database]\nproxy_protocol = true";
+        assert!(resolve_with_config(&[], udp).is_err());
+    }
+
+    #[test]
+    fn tls_needs_a_tcp_service_and_readable_files() {
+        let udp = "[services.line_reversal]\ntls = { cert = \"cert.pem\", key = \"key.pem\" }";
+        let error = resolve_with_config(&[], udp).unwrap_err();
+        assert_eq!(error.to_string(), "line_reversal can't use TLS");
+
+        let missing = r#"
+            [services.smoke_test]
+            tls = { cert = "/nonexistent/cert.pem", key = "/nonexistent/key.pem" }
+        "#;
+        assert!(resolve_with_config(&[], missing).is_err());
+    }
+
+    #[test]
+    fn unix_sockets_for_stream_services_and_line_reversal() {
+        let config = r#"
+            [services.smoke_test]
+            unix_socket = "/tmp/smoke_test.sock"
+
+            [services.line_reversal]
+            unix_socket = "/tmp/line_reversal.sock"
+        "#;
+
+        let sockets = resolve(ServeArgs::default(), Config::parse(config).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|resolved| (resolved.service.name(), resolved.options.unix_socket))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        assert_eq!(sockets["smoke_test"], Some("/tmp/smoke_test.sock".into()));
+        assert_eq!(
+            sockets["line_reversal"],
+            Some("/tmp/line_reversal.sock".into())
+        );
+        assert_eq!(sockets["prime_time"], None);
+
+        let udp = "[services.unusual_database]\nunix_socket = \"/tmp/unusual_database.sock\"";
+        let error = resolve_with_config(&[], udp).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unusual_database can't listen on a Unix socket"
+        );
+    }
+}
@@ -1,9 +1,14 @@
-use log::info;
+use crate::limiter::{Limit, Limiter, Verdict};
+use crate::metrics::metrics;
+use crate::service::{serve, Listener, ServerHandle, Service, ServiceFuture, StreamListener};
+use crate::shutdown::Shutdown;
 use std::collections::BTreeMap;
+use std::net::SocketAddr;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::ToSocketAddrs,
 };
+use tracing::{info, instrument};
 
 #[derive(Debug)]
 enum Message {
@@ -36,42 +41,83 @@ impl TryFrom<[u8; 9]> for Message {
     }
 }
 
-pub async fn run(port: &str) -> anyhow::Result<()> {
-    let addr = format!("0.0.0.0:{}", port);
-    info!("Running means to an end server on {}...", &addr);
-    let listener = TcpListener::bind(&addr).await?;
+pub struct MeansToAnEnd;
 
-    loop {
-        let (stream, address) = listener.accept().await?;
-        tokio::spawn(async move { handler(stream, address).await });
+impl Service for MeansToAnEnd {
+    fn name(&self) -> &'static str {
+        "means_to_an_end"
     }
+
+    fn default_port(&self) -> u16 {
+        10002
+    }
+
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        Box::pin(async move { run(listener.into_tcp()?, limiter, shutdown).await })
+    }
+}
+
+/// Starts the server on `bind` in the background.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&MeansToAnEnd, bind).await
 }
 
-async fn handler(mut stream: TcpStream, address: std::net::SocketAddr) -> anyhow::Result<()> {
+pub async fn run(
+    listener: StreamListener,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    info!(
+        "Running means to an end server on {}...",
+        listener.local_addr()?
+    );
+
+    serve("means_to_an_end", listener, limiter, shutdown, handler).await
+}
+
+#[instrument(parent = None, name = "connection", skip_all, fields(service = "means_to_an_end", %peer), err(Display))]
+pub async fn handler<S>(
+    stream: S,
+    peer: SocketAddr,
+    limit: Limit,
+    shutdown: Shutdown,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     // Init DB
     let mut db: BTreeMap<i32, i32> = BTreeMap::new();
 
-    let (read_half, mut writer) = stream.split();
+    let (read_half, mut writer) = tokio::io::split(stream);
     let mut reader = BufReader::new(read_half);
 
     let mut bytes = [0u8; 9];
 
-    while let Ok(_num_bytes) = reader.read_exact(&mut bytes).await {
+    while let Some(Ok(_num_bytes)) = shutdown.or_cancel(reader.read_exact(&mut bytes)).await {
+        match limit.message().await {
+            Verdict::Allow => {}
+            Verdict::Drop => continue,
+            Verdict::Disconnect => break,
+        }
+
         let message = Message::try_from(bytes)?;
 
         match message {
             Message::Insert { timestamp, price } => {
-                info!("Received insert message {:?} from {}", message, address);
+                info!("Received insert message {:?}", message);
+                metrics().request("means_to_an_end", "insert");
                 db.insert(timestamp, price);
             }
 
             Message::Query { from, to } => {
-                info!("Received query message {:?} from {}", message, address);
+                info!("Received query message {:?}", message);
+                metrics().request("means_to_an_end", "query");
                 let mean = range_average(&db, from, to);
                 writer.write_i32(mean).await?;
             }
 
             Message::Unknown => {
+                metrics().parse_error("means_to_an_end");
                 writer.write_all(b"Unknown\n").await?;
             }
         }
@@ -109,3 +155,32 @@ fn range_average(map: &BTreeMap<i32, i32>, low: i32, high: i32) -> i32 {
 
     mean
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(kind: u8, first: i32, second: i32) -> Vec<u8> {
+        let mut bytes = vec![kind];
+        bytes.extend(first.to_be_bytes());
+        bytes.extend(second.to_be_bytes());
+        bytes
+    }
+
+    #[tokio::test]
+    async fn example_session() -> anyhow::Result<()> {
+        let (mut client, server) = tokio::io::duplex(64);
+        let peer = "127.0.0.1:1234".parse()?;
+        let task = tokio::spawn(handler(server, peer, Limit::none(), Shutdown::new()));
+
+        client.write_all(&message(b'I', 12345, 101)).await?;
+        client.write_all(&message(b'I', 12346, 102)).await?;
+        client.write_all(&message(b'I', 12347, 100)).await?;
+        client.write_all(&message(b'I', 40960, 5)).await?;
+        client.write_all(&message(b'Q', 12288, 16384)).await?;
+        assert_eq!(client.read_i32().await?, 101);
+
+        drop(client);
+        task.await?
+    }
+}
@@ -0,0 +1,425 @@
+use crate::admin::{self, Connection, Registered};
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::LazyLock;
+use std::task::{Context, Poll};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::sync::WaitForCancellationFutureOwned;
+use tracing::{error, info};
+
+/// Longest request or header line the metrics endpoint will read.
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Everything the services count, shared by the whole process.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    registry: Registry,
+    pub connections_accepted: IntCounterVec,
+    pub active_connections: IntGaugeVec,
+    pub bytes_received: IntCounterVec,
+    pub bytes_sent: IntCounterVec,
+    pub requests: IntCounterVec,
+    pub parse_errors: IntCounterVec,
+    pub lrcp_sessions_opened: IntCounter,
+    pub lrcp_active_sessions: IntGauge,
+    pub lrcp_retransmissions: IntCounter,
+    pub lrcp_acks: IntCounterVec,
+    pub cipher_rejections: IntCounter,
+    pub prime_check_seconds: Histogram,
+    pub limit_violations: IntCounterVec,
+    pub access_rejections: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("protohackers".to_string()), None).unwrap();
+
+        let service = &["service"];
+        let metrics = Self {
+            connections_accepted: IntCounterVec::new(
+                Opts::new("connections_accepted_total", "Connections accepted"),
+                service,
+            )
+            .unwrap(),
+            active_connections: IntGaugeVec::new(
+                Opts::new("active_connections", "Connections currently open"),
+                service,
+            )
+            .unwrap(),
+            bytes_received: IntCounterVec::new(
+                Opts::new("bytes_received_total", "Bytes read from clients"),
+                service,
+            )
+            .unwrap(),
+            bytes_sent: IntCounterVec::new(
+                Opts::new("bytes_sent_total", "Bytes written to clients"),
+                service,
+            )
+            .unwrap(),
+            requests: IntCounterVec::new(
+                Opts::new("requests_total", "Requests handled, by method"),
+                &["service", "method"],
+            )
+            .unwrap(),
+            parse_errors: IntCounterVec::new(
+                Opts::new("parse_errors_total", "Requests that could not be parsed"),
+                service,
+            )
+            .unwrap(),
+            lrcp_sessions_opened: IntCounter::new(
+                "lrcp_sessions_opened_total",
+                "LRCP sessions opened",
+            )
+            .unwrap(),
+            lrcp_active_sessions: IntGauge::new(
+                "lrcp_active_sessions",
+                "LRCP sessions currently open",
+            )
+            .unwrap(),
+            lrcp_retransmissions: IntCounter::new(
+                "lrcp_retransmissions_total",
+                "LRCP data packets sent again",
+            )
+            .unwrap(),
+            lrcp_acks: IntCounterVec::new(
+                Opts::new("lrcp_acks_total", "LRCP acks, by direction"),
+                &["direction"],
+            )
+            .unwrap(),
+            cipher_rejections: IntCounter::new(
+                "cipher_rejections_total",
+                "Insecure sockets clients rejected for an invalid or no-op cipher",
+            )
+            .unwrap(),
+            prime_check_seconds: Histogram::with_opts(
+                HistogramOpts::new("prime_check_seconds", "Time spent checking primality")
+                    .buckets(prometheus::exponential_buckets(1e-7, 10.0, 8).unwrap()),
+            )
+            .unwrap(),
+            limit_violations: IntCounterVec::new(
+                Opts::new(
+                    "limit_violations_total",
+                    "Clients over a per IP limit, by limit and the action taken",
+                ),
+                &["service", "limit", "action"],
+            )
+            .unwrap(),
+            access_rejections: IntCounterVec::new(
+                Opts::new(
+                    "access_rejections_total",
+                    "Connections and datagrams from peers outside the access list",
+                ),
+                service,
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 14] = [
+            Box::new(metrics.connections_accepted.clone()),
+            Box::new(metrics.active_connections.clone()),
+            Box::new(metrics.bytes_received.clone()),
+            Box::new(metrics.bytes_sent.clone()),
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.parse_errors.clone()),
+            Box::new(metrics.lrcp_sessions_opened.clone()),
+            Box::new(metrics.lrcp_active_sessions.clone()),
+            Box::new(metrics.lrcp_retransmissions.clone()),
+            Box::new(metrics.lrcp_acks.clone()),
+            Box::new(metrics.cipher_rejections.clone()),
+            Box::new(metrics.prime_check_seconds.clone()),
+            Box::new(metrics.limit_violations.clone()),
+            Box::new(metrics.access_rejections.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+
+        metrics
+    }
+
+    pub fn request(&self, service: &str, method: &str) {
+        self.requests.with_label_values(&[service, method]).inc();
+    }
+
+    pub fn parse_error(&self, service: &str) {
+        self.parse_errors.with_label_values(&[service]).inc();
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_default()
+    }
+}
+
+/// Counts a newly accepted connection, and the bytes through it until it is
+/// dropped. Meanwhile the admin interface lists it, and killing it there fails
+/// its reads and writes so the handler cleans up as if the client had left.
+pub fn track<S>(service: &'static str, peer: SocketAddr, stream: S) -> Metered<S> {
+    let metrics = metrics();
+    metrics
+        .connections_accepted
+        .with_label_values(&[service])
+        .inc();
+    let active = metrics.active_connections.with_label_values(&[service]);
+    active.inc();
+
+    let connection = admin::registry().add_connection(service, peer);
+    let killed = Box::pin(connection.killed.clone().cancelled_owned());
+    Metered {
+        inner: stream,
+        received: metrics.bytes_received.with_label_values(&[service]),
+        sent: metrics.bytes_sent.with_label_values(&[service]),
+        active,
+        connection,
+        killed,
+    }
+}
+
+pub struct Metered<S> {
+    inner: S,
+    received: IntCounter,
+    sent: IntCounter,
+    active: IntGauge,
+    connection: Registered<Connection>,
+    killed: Pin<Box<WaitForCancellationFutureOwned>>,
+}
+
+impl<S> Metered<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// The connection's entry in the admin interface.
+    pub fn connection(&self) -> &Registered<Connection> {
+        &self.connection
+    }
+
+    /// Fails with an error once the connection has been killed, registering
+    /// for a wakeup otherwise.
+    fn poll_killed(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        match self.killed.as_mut().poll(cx) {
+            Poll::Ready(()) => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "killed from the admin interface",
+            )),
+            Poll::Pending => Ok(()),
+        }
+    }
+}
+
+impl<S> Drop for Metered<S> {
+    fn drop(&mut self) {
+        self.active.dec();
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_killed(cx)?;
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let num_bytes = (buf.filled().len() - filled) as u64;
+        this.received.inc_by(num_bytes);
+        this.connection
+            .bytes_received
+            .fetch_add(num_bytes, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.poll_killed(cx)?;
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.sent.inc_by(written as u64);
+            this.connection
+                .bytes_sent
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Serves the metrics over HTTP at `/metrics`.
+pub async fn serve(listener: TcpListener, shutdown: Shutdown) -> Result<()> {
+    info!("Serving metrics on {}...", listener.local_addr()?);
+
+    while let Some(accepted) = shutdown.or_cancel(listener.accept()).await {
+        let (stream, address) = accepted?;
+        shutdown.spawn(move |_| async move {
+            if let Err(e) = respond(stream).await {
+                error!(peer = %address, "Metrics request error: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn respond(stream: TcpStream) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    (&mut stream)
+        .take(MAX_LINE_LENGTH)
+        .read_line(&mut request_line)
+        .await?;
+
+    // Read the headers so closing the connection doesn't reset it
+    let mut header = String::new();
+    while header != "\r\n" && header != "\n" {
+        header.clear();
+        if (&mut stream)
+            .take(MAX_LINE_LENGTH)
+            .read_line(&mut header)
+            .await?
+            == 0
+        {
+            break;
+        }
+    }
+
+    let (status, body) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", "/metrics", _] => ("200 OK", metrics().encode()),
+        [_, "/metrics", _] => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_connections_and_bytes() -> Result<()> {
+        let (client, server) = tokio::io::duplex(64);
+        let peer = "127.0.0.1:1234".parse()?;
+        let mut server = track("metrics_test", peer, server);
+        let mut client = track("metrics_test", peer, client);
+        let active = metrics()
+            .active_connections
+            .with_label_values(&["metrics_test"]);
+        assert_eq!(active.get(), 2);
+
+        client.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await?;
+
+        let sent = metrics().bytes_sent.with_label_values(&["metrics_test"]);
+        let received = metrics()
+            .bytes_received
+            .with_label_values(&["metrics_test"]);
+        assert_eq!((sent.get(), received.get()), (5, 5));
+        assert_eq!(
+            server.connection().bytes_received.load(Ordering::Relaxed),
+            5
+        );
+
+        drop(client);
+        drop(server);
+        assert_eq!(active.get(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn killing_fails_reads_and_writes() -> Result<()> {
+        let (client, server) = tokio::io::duplex(64);
+        let mut server = track("metrics_kill_test", "127.0.0.1:1234".parse()?, server);
+        let id = server.connection().id();
+
+        // A read already waiting is woken up
+        let reader = tokio::spawn(async move {
+            let mut buf = [0u8; 1];
+            let result = server.read(&mut buf).await;
+            (server, result)
+        });
+        tokio::task::yield_now().await;
+        assert!(admin::registry().kill(id));
+
+        let (mut server, result) = reader.await?;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+        assert!(server.write_all(b"hello").await.is_err());
+
+        drop(server);
+        assert!(!admin::registry().kill(id));
+        drop(client);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_over_http() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(serve(listener, shutdown.clone()));
+        metrics().request("metrics_http_test", "GET");
+
+        let mut stream = TcpStream::connect(address).await?;
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(
+            "protohackers_requests_total{method=\"GET\",service=\"metrics_http_test\"} 1\n"
+        ));
+
+        let mut stream = TcpStream::connect(address).await?;
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        shutdown.trigger();
+        server.await?
+    }
+}
@@ -0,0 +1,215 @@
+use crate::limiter::{Limit, Limiter, Verdict};
+use crate::service::{serve, Listener, ServerHandle, Service, ServiceFuture, StreamListener};
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpStream, ToSocketAddrs},
+};
+use tracing::{info, instrument};
+
+/// The real Budget Chat server we sit in front of.
+pub const UPSTREAM: &str = "chat.protohackers.com:16963";
+
+/// Tony's address, which replaces every Boguscoin address we see.
+const TARGET_ADDRESS: &str = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MobInTheMiddle {
+    pub upstream: String,
+}
+
+impl Default for MobInTheMiddle {
+    fn default() -> Self {
+        Self {
+            upstream: UPSTREAM.to_string(),
+        }
+    }
+}
+
+impl Service for MobInTheMiddle {
+    fn name(&self) -> &'static str {
+        "mob_in_the_middle"
+    }
+
+    fn default_port(&self) -> u16 {
+        10005
+    }
+
+    fn configure(&mut self, options: toml::Table) -> anyhow::Result<()> {
+        *self = options.try_into()?;
+        Ok(())
+    }
+
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        let upstream = self.upstream.clone();
+        Box::pin(async move { run(listener.into_tcp()?, &upstream, limiter, shutdown).await })
+    }
+}
+
+/// Starts the server on `bind` in the background, with the default options.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&MobInTheMiddle::default(), bind).await
+}
+
+pub async fn run(
+    listener: StreamListener,
+    upstream: &str,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    info!(
+        "Running mob in the middle server on {} proxying to {}...",
+        listener.local_addr()?,
+        upstream
+    );
+    let upstream: Arc<str> = upstream.into();
+
+    serve(
+        "mob_in_the_middle",
+        listener,
+        limiter,
+        shutdown,
+        move |stream, peer, limit, shutdown| {
+            let upstream = upstream.clone();
+            async move { handle_connection(stream, peer, &upstream, limit, shutdown).await }
+        },
+    )
+    .await
+}
+
+#[instrument(parent = None, name = "connection", skip_all, fields(service = "mob_in_the_middle", %peer), err(Display))]
+async fn handle_connection<S>(
+    client: S,
+    peer: SocketAddr,
+    upstream: &str,
+    limit: Limit,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    info!("Accepted connection");
+    let Some(server) = shutdown.or_cancel(TcpStream::connect(upstream)).await else {
+        return Ok(());
+    };
+    let mut server = server?;
+    info!("Opened upstream connection to {}", upstream);
+
+    let (client_reader, client_writer) = tokio::io::split(client);
+    let (server_reader, server_writer) = server.split();
+
+    // Whichever side hangs up first ends the session for both. Only the
+    // client's lines count against its limits.
+    let unlimited = Limit::none();
+    tokio::select! {
+        result = relay(client_reader, server_writer, &limit) => result?,
+        result = relay(server_reader, client_writer, &unlimited) => result?,
+        _ = shutdown.triggered() => {}
+    }
+
+    info!("Closing connection");
+    Ok(())
+}
+
+/// Copies complete lines from `reader` to `writer`, rewriting Boguscoin addresses.
+/// A trailing partial line is dropped when the reader disconnects.
+async fn relay<R, W>(reader: R, mut writer: W, limit: &Limit) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        reader.read_until(b'\n', &mut line).await?;
+        if line.last() != Some(&b'\n') {
+            return Ok(());
+        }
+
+        match limit.message().await {
+            Verdict::Allow => {}
+            Verdict::Drop => continue,
+            Verdict::Disconnect => return Ok(()),
+        }
+
+        let message = String::from_utf8_lossy(&line[..line.len() - 1]);
+        let mut rewritten = rewrite_message(&message);
+        rewritten.push('\n');
+
+        writer.write_all(rewritten.as_bytes()).await?;
+    }
+}
+
+fn rewrite_message(message: &str) -> String {
+    message
+        .split(' ')
+        .map(|token| {
+            if is_boguscoin_address(token) {
+                TARGET_ADDRESS
+            } else {
+                token
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn is_boguscoin_address(token: &str) -> bool {
+    token.starts_with('7')
+        && (26..=35).contains(&token.len())
+        && token.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rewrites_addresses_at_token_boundaries() {
+        let cases = [
+            (
+                "Hi alice, please send payment to 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX",
+                "Hi alice, please send payment to 7YWHMfk9JZe0LM0g1ZauHuiSxhI",
+            ),
+            (
+                "7F1u3wSD5RbOHQmupo9nx4TnhQ is my address",
+                "7YWHMfk9JZe0LM0g1ZauHuiSxhI is my address",
+            ),
+            (
+                "pay 7LOrwbDlS8NujgjddyogWgIM93MV5N2VR or 7adNeSwJkMakpEcln9HEtthSRtxdmEHOT8T",
+                "pay 7YWHMfk9JZe0LM0g1ZauHuiSxhI or 7YWHMfk9JZe0LM0g1ZauHuiSxhI",
+            ),
+        ];
+
+        for (message, expected) in cases {
+            assert_eq!(rewrite_message(message), expected);
+        }
+    }
+
+    #[test]
+    fn leaves_non_addresses_alone() {
+        let messages = [
+            // Too short and too long
+            "7iKDZEwPZSqIvDnHvVN2r0hUW",
+            "7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHXaaaaa",
+            // Wrong first character
+            "8iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX",
+            // Not delimited by spaces
+            "This is a product ID, not a Boguscoin: 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX-1234",
+            "7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX!",
+            // Multiple spaces are preserved
+            "two  spaces  ",
+        ];
+
+        for message in messages {
+            assert_eq!(rewrite_message(message), message);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use crate::limiter::{Limit, Limiter, Verdict};
+use crate::metrics::metrics;
+use crate::service::{serve, Listener, ServerHandle, Service, ServiceFuture, StreamListener};
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::ToSocketAddrs,
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+use tracing::{info, instrument};
+
+use self::{
+    authority::Sites,
+    message::{Message, PestControlCodec, PROTOCOL, VERSION},
+};
+
+mod authority;
+mod message;
+
+/// The real Authority Server that site policies are created on.
+pub const AUTHORITY: &str = "pestcontrol.protohackers.com:20547";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PestControl {
+    pub authority: String,
+}
+
+impl Default for PestControl {
+    fn default() -> Self {
+        Self {
+            authority: AUTHORITY.to_string(),
+        }
+    }
+}
+
+impl Service for PestControl {
+    fn name(&self) -> &'static str {
+        "pest_control"
+    }
+
+    fn default_port(&self) -> u16 {
+        10011
+    }
+
+    fn configure(&mut self, options: toml::Table) -> anyhow::Result<()> {
+        *self = options.try_into()?;
+        Ok(())
+    }
+
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        let authority = self.authority.clone();
+        Box::pin(async move { run(listener.into_tcp()?, &authority, limiter, shutdown).await })
+    }
+}
+
+/// Starts the server on `bind` in the background, with the default options.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&PestControl::default(), bind).await
+}
+
+pub async fn run(
+    listener: StreamListener,
+    authority: &str,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    info!(
+        "Running pest control server on {} with authority {}...",
+        listener.local_addr()?,
+        authority
+    );
+    let sites = Sites::new(authority, shutdown.clone());
+
+    serve(
+        "pest_control",
+        listener,
+        limiter,
+        shutdown,
+        move |stream, peer, limit, shutdown| {
+            handle_connection(stream, peer, sites.clone(), limit, shutdown)
+        },
+    )
+    .await
+}
+
+#[instrument(parent = None, name = "connection", skip_all, fields(service = "pest_control", %peer), err(Display))]
+async fn handle_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    sites: Arc<Sites>,
+    limit: Limit,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    info!("Accepted connection");
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = FramedRead::new(reader, PestControlCodec);
+
+    writer.write_all(&Message::hello().to_bytes()).await?;
+
+    let result = handle_messages(&mut reader, &sites, &limit, &shutdown).await;
+    if let Err(e) = &result {
+        info!("Sending error: {}", e);
+        writer
+            .write_all(&Message::error(&e.to_string()).to_bytes())
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_messages<R>(
+    reader: &mut FramedRead<R, PestControlCodec>,
+    sites: &Arc<Sites>,
+    limit: &Limit,
+    shutdown: &Shutdown,
+) -> Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    match shutdown
+        .or_cancel(reader.next())
+        .await
+        .flatten()
+        .transpose()
+        .inspect_err(|_| metrics().parse_error("pest_control"))?
+    {
+        Some(Message::Hello { protocol, version })
+            if protocol == PROTOCOL && version == VERSION =>
+        {
+            metrics().request("pest_control", "hello");
+        }
+        Some(message) => anyhow::bail!("expected hello, got {:?}", message),
+        None => return Ok(()),
+    }
+
+    while let Some(message) = shutdown
+        .or_cancel(reader.next())
+        .await
+        .flatten()
+        .transpose()
+        .inspect_err(|_| metrics().parse_error("pest_control"))?
+    {
+        match limit.message().await {
+            Verdict::Allow => {}
+            Verdict::Drop => continue,
+            Verdict::Disconnect => anyhow::bail!("too many messages"),
+        }
+
+        match message {
+            Message::SiteVisit { site, populations } => {
+                info!("Visit to site {}: {:?}", site, populations);
+                metrics().request("pest_control", "site_visit");
+                sites.visit(site, authority::populations(populations)?);
+            }
+            message => anyhow::bail!("unexpected message {:?}", message),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,335 @@
+use super::message::{Action, Message, Observation, PestControlCodec, Target, PROTOCOL, VERSION};
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+use tracing::{error, info, info_span, Instrument};
+
+type Populations = HashMap<String, u32>;
+
+/// One task per site, each holding the site's authority connection and the
+/// policies it has created. Visits to a site are handled in the order they arrive.
+/// The tasks run on the service's `Shutdown`, and stop once it triggers.
+pub struct Sites {
+    authority: String,
+    sites: Mutex<HashMap<u32, UnboundedSender<Populations>>>,
+    shutdown: Shutdown,
+}
+
+impl Sites {
+    pub fn new(authority: &str, shutdown: Shutdown) -> Arc<Self> {
+        Arc::new(Self {
+            authority: authority.to_string(),
+            sites: Mutex::new(HashMap::new()),
+            shutdown,
+        })
+    }
+
+    pub fn visit(self: &Arc<Self>, site: u32, populations: Populations) {
+        let mut sites = self.sites.lock().unwrap();
+
+        let populations = match sites.get(&site) {
+            Some(tx) => match tx.send(populations) {
+                Ok(()) => return,
+                // The site's task has ended, start a fresh one
+                Err(e) => e.0,
+            },
+            None => populations,
+        };
+
+        self.start(&mut sites, site, [populations]);
+    }
+
+    /// Starts a task for `site` with `visits` queued up for it.
+    fn start(
+        self: &Arc<Self>,
+        sites: &mut HashMap<u32, UnboundedSender<Populations>>,
+        site: u32,
+        visits: impl IntoIterator<Item = Populations>,
+    ) {
+        let (tx, mut rx) = unbounded_channel();
+        for populations in visits {
+            let _ = tx.send(populations);
+        }
+        sites.insert(site, tx);
+
+        let this = self.clone();
+        // Sites outlive the connections reporting them, so get their own span
+        let span = info_span!(parent: None, "site", service = "pest_control", site);
+        self.shutdown.spawn(move |shutdown| {
+            async move {
+                let result = run_site(site, &this.authority, &mut rx, &shutdown).await;
+
+                // Closing the channel under the lock sends later visits to a new task
+                let mut sites = this.sites.lock().unwrap();
+                rx.close();
+                let unsent: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+                match result {
+                    // The visit being handled is dropped, so retries always make progress
+                    Err(e) if !unsent.is_empty() && !shutdown.is_triggered() => {
+                        error!("Site failed, retrying {} visits: {}", unsent.len(), e);
+                        this.start(&mut sites, site, unsent);
+                    }
+                    result => {
+                        if let Err(e) = result {
+                            error!("Site failed: {}", e);
+                        }
+                        sites.remove(&site);
+                    }
+                }
+            }
+            .instrument(span)
+        });
+    }
+}
+
+/// Handles `site`'s visits as they come in, connecting to the authority for the
+/// first one, until shutdown.
+async fn run_site(
+    site: u32,
+    authority: &str,
+    rx: &mut UnboundedReceiver<Populations>,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let Some(Some(mut populations)) = shutdown.or_cancel(rx.recv()).await else {
+        return Ok(());
+    };
+    let Some(authority) = shutdown
+        .or_cancel(Authority::connect(authority, site))
+        .await
+    else {
+        return Ok(());
+    };
+    let mut authority = authority?;
+    info!("Targets {:?}", authority.targets);
+
+    // species -> (policy id, action)
+    let mut policies: HashMap<String, (u32, Action)> = HashMap::new();
+
+    loop {
+        for target in authority.targets.clone() {
+            let count = populations.get(&target.species).copied().unwrap_or(0);
+            let wanted = required_action(&target, count);
+            let current = policies.get(&target.species).copied();
+
+            if current.map(|(_, action)| action) == wanted {
+                continue;
+            }
+
+            if let Some((policy, _)) = current {
+                authority.delete_policy(policy).await?;
+                policies.remove(&target.species);
+            }
+
+            if let Some(action) = wanted {
+                let policy = authority.create_policy(&target.species, action).await?;
+                policies.insert(target.species.clone(), (policy, action));
+            }
+        }
+
+        populations = match shutdown.or_cancel(rx.recv()).await {
+            Some(Some(populations)) => populations,
+            _ => return Ok(()),
+        };
+    }
+}
+
+fn required_action(target: &Target, count: u32) -> Option<Action> {
+    if count < target.min {
+        Some(Action::Conserve)
+    } else if count > target.max {
+        Some(Action::Cull)
+    } else {
+        None
+    }
+}
+
+struct Authority {
+    reader: FramedRead<OwnedReadHalf, PestControlCodec>,
+    writer: OwnedWriteHalf,
+    targets: Vec<Target>,
+}
+
+impl Authority {
+    async fn connect(addr: &str, site: u32) -> Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let mut authority = Self {
+            reader: FramedRead::new(reader, PestControlCodec),
+            writer,
+            targets: Vec::new(),
+        };
+
+        match authority.request(Message::hello()).await? {
+            Message::Hello { protocol, version } if protocol == PROTOCOL && version == VERSION => {}
+            message => anyhow::bail!("Unexpected hello from authority: {:?}", message),
+        }
+
+        match authority.request(Message::DialAuthority { site }).await? {
+            Message::TargetPopulations {
+                site: s,
+                populations,
+            } if s == site => {
+                authority.targets = populations;
+            }
+            message => anyhow::bail!("Unexpected response to dial: {:?}", message),
+        }
+
+        Ok(authority)
+    }
+
+    async fn create_policy(&mut self, species: &str, action: Action) -> Result<u32> {
+        let message = Message::CreatePolicy {
+            species: species.to_string(),
+            action,
+        };
+
+        match self.request(message).await? {
+            Message::PolicyResult { policy } => {
+                info!("Created policy {} to {:?} {}", policy, action, species);
+                Ok(policy)
+            }
+            message => anyhow::bail!("Unexpected response to create policy: {:?}", message),
+        }
+    }
+
+    async fn delete_policy(&mut self, policy: u32) -> Result<()> {
+        match self.request(Message::DeletePolicy { policy }).await? {
+            Message::Ok => {
+                info!("Deleted policy {}", policy);
+                Ok(())
+            }
+            message => anyhow::bail!("Unexpected response to delete policy: {:?}", message),
+        }
+    }
+
+    async fn request(&mut self, message: Message) -> Result<Message> {
+        self.writer.write_all(&message.to_bytes()).await?;
+
+        match self.reader.next().await {
+            Some(Ok(Message::Error { message })) => anyhow::bail!("Authority error: {}", message),
+            Some(Ok(message)) => Ok(message),
+            Some(Err(e)) => Err(e),
+            None => anyhow::bail!("Authority closed the connection"),
+        }
+    }
+}
+
+/// Collapses a site visit into counts per species, rejecting visits that
+/// report the same species twice with different counts.
+pub fn populations(observations: Vec<Observation>) -> Result<Populations> {
+    let mut populations = Populations::new();
+
+    for Observation { species, count } in observations {
+        match populations.get(&species) {
+            Some(existing) if *existing != count => {
+                anyhow::bail!("conflicting counts for {}", species)
+            }
+            _ => {
+                populations.insert(species, count);
+            }
+        }
+    }
+
+    Ok(populations)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn actions_for_counts() {
+        let target = Target {
+            species: "dog".to_string(),
+            min: 1,
+            max: 3,
+        };
+
+        assert_eq!(required_action(&target, 0), Some(Action::Conserve));
+        assert_eq!(required_action(&target, 1), None);
+        assert_eq!(required_action(&target, 3), None);
+        assert_eq!(required_action(&target, 4), Some(Action::Cull));
+    }
+
+    #[test]
+    fn conflicting_observations() {
+        let observation = |species: &str, count| Observation {
+            species: species.to_string(),
+            count,
+        };
+
+        let populations = populations(vec![observation("dog", 1), observation("dog", 1)]).unwrap();
+        assert_eq!(populations.get("dog"), Some(&1));
+
+        assert!(super::populations(vec![observation("dog", 1), observation("dog", 2)]).is_err());
+    }
+
+    /// Plays the authority for a site watching dogs, failing the first
+    /// connection's first policy and passing on the policies created after.
+    async fn authority(listener: TcpListener, created: UnboundedSender<Message>) {
+        for fail in [true, false] {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = FramedRead::new(reader, PestControlCodec);
+            while let Some(Ok(message)) = reader.next().await {
+                let response = match message {
+                    Message::Hello { .. } => Message::hello(),
+                    Message::DialAuthority { site } => Message::TargetPopulations {
+                        site,
+                        populations: vec![Target {
+                            species: "dog".to_string(),
+                            min: 1,
+                            max: 3,
+                        }],
+                    },
+                    Message::CreatePolicy { .. } if fail => Message::error("try again"),
+                    message @ Message::CreatePolicy { .. } => {
+                        created.send(message).unwrap();
+                        Message::PolicyResult { policy: 1 }
+                    }
+                    message => panic!("Unexpected {:?}", message),
+                };
+                writer.write_all(&response.to_bytes()).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_queued_visits_and_stops_at_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (created, mut rx) = unbounded_channel();
+        let authority = tokio::spawn(authority(listener, created));
+
+        let shutdown = Shutdown::new();
+        let sites = Sites::new(&address, shutdown.clone());
+        let visit = |count| Populations::from([("dog".to_string(), count)]);
+        sites.visit(1, visit(0));
+        sites.visit(1, visit(5));
+
+        // The first visit fails, and the one queued behind it goes to a new connection
+        let expected = Message::CreatePolicy {
+            species: "dog".to_string(),
+            action: Action::Cull,
+        };
+        assert_eq!(rx.recv().await, Some(expected));
+
+        shutdown.trigger();
+        let drained = tokio::time::timeout(Duration::from_secs(1), shutdown.drained());
+        drained.await.unwrap();
+        // Which closed the authority connection
+        authority.await.unwrap();
+    }
+}
@@ -0,0 +1,413 @@
+use nom::{
+    combinator::{all_consuming, map_res},
+    multi::{length_count, length_data},
+    number::complete::{be_u32, be_u8},
+    IResult,
+};
+use tokio_util::{bytes::BytesMut, codec::Decoder};
+
+const HELLO: u8 = 0x50;
+const ERROR: u8 = 0x51;
+const OK: u8 = 0x52;
+const DIAL_AUTHORITY: u8 = 0x53;
+const TARGET_POPULATIONS: u8 = 0x54;
+const CREATE_POLICY: u8 = 0x55;
+const DELETE_POLICY: u8 = 0x56;
+const POLICY_RESULT: u8 = 0x57;
+const SITE_VISIT: u8 = 0x58;
+
+const CULL: u8 = 0x90;
+const CONSERVE: u8 = 0xa0;
+
+// Type, length and checksum
+const HEADER_LENGTH: usize = 5;
+const MIN_MESSAGE_LENGTH: usize = HEADER_LENGTH + 1;
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+pub const PROTOCOL: &str = "pestcontrol";
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    Hello {
+        protocol: String,
+        version: u32,
+    },
+    Error {
+        message: String,
+    },
+    Ok,
+    DialAuthority {
+        site: u32,
+    },
+    TargetPopulations {
+        site: u32,
+        populations: Vec<Target>,
+    },
+    CreatePolicy {
+        species: String,
+        action: Action,
+    },
+    DeletePolicy {
+        policy: u32,
+    },
+    PolicyResult {
+        policy: u32,
+    },
+    SiteVisit {
+        site: u32,
+        populations: Vec<Observation>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Target {
+    pub species: String,
+    pub min: u32,
+    pub max: u32,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Observation {
+    pub species: String,
+    pub count: u32,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Action {
+    Cull,
+    Conserve,
+}
+
+impl Message {
+    pub fn hello() -> Self {
+        Self::Hello {
+            protocol: PROTOCOL.to_string(),
+            version: VERSION,
+        }
+    }
+
+    pub fn error(message: &str) -> Self {
+        Self::Error {
+            message: message.to_string(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut content = Vec::new();
+        let kind = match self {
+            Message::Hello { protocol, version } => {
+                push_str(&mut content, protocol);
+                push_u32(&mut content, *version);
+                HELLO
+            }
+
+            Message::Error { message } => {
+                push_str(&mut content, message);
+                ERROR
+            }
+
+            Message::Ok => OK,
+
+            Message::DialAuthority { site } => {
+                push_u32(&mut content, *site);
+                DIAL_AUTHORITY
+            }
+
+            Message::TargetPopulations { site, populations } => {
+                push_u32(&mut content, *site);
+                push_u32(&mut content, populations.len() as u32);
+                for target in populations {
+                    push_str(&mut content, &target.species);
+                    push_u32(&mut content, target.min);
+                    push_u32(&mut content, target.max);
+                }
+                TARGET_POPULATIONS
+            }
+
+            Message::CreatePolicy { species, action } => {
+                push_str(&mut content, species);
+                content.push(match action {
+                    Action::Cull => CULL,
+                    Action::Conserve => CONSERVE,
+                });
+                CREATE_POLICY
+            }
+
+            Message::DeletePolicy { policy } => {
+                push_u32(&mut content, *policy);
+                DELETE_POLICY
+            }
+
+            Message::PolicyResult { policy } => {
+                push_u32(&mut content, *policy);
+                POLICY_RESULT
+            }
+
+            Message::SiteVisit { site, populations } => {
+                push_u32(&mut content, *site);
+                push_u32(&mut content, populations.len() as u32);
+                for observation in populations {
+                    push_str(&mut content, &observation.species);
+                    push_u32(&mut content, observation.count);
+                }
+                SITE_VISIT
+            }
+        };
+
+        let length = (content.len() + MIN_MESSAGE_LENGTH) as u32;
+        let mut bytes = vec![kind];
+        push_u32(&mut bytes, length);
+        bytes.extend_from_slice(&content);
+
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(0u8.wrapping_sub(sum));
+        bytes
+    }
+}
+
+fn push_u32(bytes: &mut Vec<u8>, n: u32) {
+    bytes.extend_from_slice(&n.to_be_bytes());
+}
+
+fn push_str(bytes: &mut Vec<u8>, s: &str) {
+    push_u32(bytes, s.len() as u32);
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+/// Splits a byte stream into length-prefixed messages, validating the
+/// checksum and that the content fills the message exactly.
+#[derive(Debug, Default)]
+pub struct PestControlCodec;
+
+impl Decoder for PestControlCodec {
+    type Item = Message;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes(src[1..5].try_into()?) as usize;
+        if !(MIN_MESSAGE_LENGTH..=MAX_MESSAGE_LENGTH).contains(&length) {
+            anyhow::bail!("invalid message length {}", length);
+        }
+
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
+        }
+
+        let frame = src.split_to(length);
+        if frame.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            anyhow::bail!("invalid checksum");
+        }
+
+        let content = &frame[HEADER_LENGTH..length - 1];
+        let (_, message) = parse_content(frame[0], content)
+            .map_err(|_| anyhow::anyhow!("invalid message content"))?;
+
+        Ok(Some(message))
+    }
+}
+
+fn parse_content(kind: u8, content: &[u8]) -> IResult<&[u8], Message> {
+    match kind {
+        HELLO => all_consuming(parse_hello)(content),
+        ERROR => all_consuming(parse_error)(content),
+        OK if content.is_empty() => Ok((content, Message::Ok)),
+        DIAL_AUTHORITY => all_consuming(parse_dial_authority)(content),
+        TARGET_POPULATIONS => all_consuming(parse_target_populations)(content),
+        CREATE_POLICY => all_consuming(parse_create_policy)(content),
+        DELETE_POLICY => {
+            let (input, policy) = all_consuming(be_u32)(content)?;
+            Ok((input, Message::DeletePolicy { policy }))
+        }
+        POLICY_RESULT => {
+            let (input, policy) = all_consuming(be_u32)(content)?;
+            Ok((input, Message::PolicyResult { policy }))
+        }
+        SITE_VISIT => all_consuming(parse_site_visit)(content),
+        _ => Err(nom::Err::Error(nom::error::Error::new(
+            content,
+            nom::error::ErrorKind::Tag,
+        ))),
+    }
+}
+
+fn parse_hello(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, protocol) = parse_str(input)?;
+    let (input, version) = be_u32(input)?;
+    Ok((input, Message::Hello { protocol, version }))
+}
+
+fn parse_error(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, message) = parse_str(input)?;
+    Ok((input, Message::Error { message }))
+}
+
+fn parse_dial_authority(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, site) = be_u32(input)?;
+    Ok((input, Message::DialAuthority { site }))
+}
+
+fn parse_target_populations(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, site) = be_u32(input)?;
+    let (input, populations) = length_count(be_u32, parse_target)(input)?;
+    Ok((input, Message::TargetPopulations { site, populations }))
+}
+
+fn parse_target(input: &[u8]) -> IResult<&[u8], Target> {
+    let (input, species) = parse_str(input)?;
+    let (input, min) = be_u32(input)?;
+    let (input, max) = be_u32(input)?;
+    Ok((input, Target { species, min, max }))
+}
+
+fn parse_create_policy(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, species) = parse_str(input)?;
+    let (input, action) = map_res(be_u8, |action| match action {
+        CULL => Ok(Action::Cull),
+        CONSERVE => Ok(Action::Conserve),
+        _ => Err(()),
+    })(input)?;
+    Ok((input, Message::CreatePolicy { species, action }))
+}
+
+fn parse_site_visit(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, site) = be_u32(input)?;
+    let (input, populations) = length_count(be_u32, parse_observation)(input)?;
+    Ok((input, Message::SiteVisit { site, populations }))
+}
+
+fn parse_observation(input: &[u8]) -> IResult<&[u8], Observation> {
+    let (input, species) = parse_str(input)?;
+    let (input, count) = be_u32(input)?;
+    Ok((input, Observation { species, count }))
+}
+
+fn parse_str(input: &[u8]) -> IResult<&[u8], String> {
+    let (input, bytes) = length_data(be_u32)(input)?;
+    let s = std::str::from_utf8(bytes)
+        .map_err(|_| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Char)))?;
+    Ok((input, s.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Option<Message>> {
+        PestControlCodec.decode(&mut BytesMut::from(bytes))
+    }
+
+    #[test]
+    fn parse_hello() {
+        let bytes = [
+            0x50, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00, 0x0b, 0x70, 0x65, 0x73, 0x74, 0x63,
+            0x6f, 0x6e, 0x74, 0x72, 0x6f, 0x6c, 0x00, 0x00, 0x00, 0x01, 0xce,
+        ];
+        assert_eq!(decode(&bytes).unwrap(), Some(Message::hello()));
+        assert_eq!(Message::hello().to_bytes(), bytes);
+    }
+
+    #[test]
+    fn parse_target_populations() {
+        let bytes = [
+            0x54, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x30, 0x39, 0x00, 0x00, 0x00, 0x02, 0x00,
+            0x00, 0x00, 0x03, 0x64, 0x6f, 0x67, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03,
+            0x00, 0x00, 0x00, 0x03, 0x72, 0x61, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x0a, 0x80,
+        ];
+        let message = Message::TargetPopulations {
+            site: 12345,
+            populations: vec![
+                Target {
+                    species: "dog".to_string(),
+                    min: 1,
+                    max: 3,
+                },
+                Target {
+                    species: "rat".to_string(),
+                    min: 0,
+                    max: 10,
+                },
+            ],
+        };
+        assert_eq!(decode(&bytes).unwrap(), Some(message.clone()));
+        assert_eq!(message.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn round_trip_messages() {
+        let messages = [
+            Message::error("bad"),
+            Message::Ok,
+            Message::DialAuthority { site: 12345 },
+            Message::CreatePolicy {
+                species: "dog".to_string(),
+                action: Action::Conserve,
+            },
+            Message::DeletePolicy { policy: 123 },
+            Message::PolicyResult { policy: 123 },
+            Message::SiteVisit {
+                site: 12345,
+                populations: vec![Observation {
+                    species: "dog".to_string(),
+                    count: 1,
+                }],
+            },
+        ];
+
+        for message in messages {
+            assert_eq!(decode(&message.to_bytes()).unwrap(), Some(message));
+        }
+    }
+
+    #[test]
+    fn wait_for_complete_message() {
+        let bytes = Message::DialAuthority { site: 1 }.to_bytes();
+        let mut src = BytesMut::new();
+
+        for (i, byte) in bytes.iter().enumerate() {
+            src.extend_from_slice(&[*byte]);
+            let message = PestControlCodec.decode(&mut src).unwrap();
+            assert_eq!(message.is_some(), i == bytes.len() - 1);
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn reject_invalid_messages() {
+        let mut bad_checksum = Message::Ok.to_bytes();
+        *bad_checksum.last_mut().unwrap() += 1;
+        assert!(decode(&bad_checksum).is_err());
+
+        // Length claims more content than the message has
+        let mut short = Message::DialAuthority { site: 1 }.to_bytes();
+        short.insert(short.len() - 1, 0);
+        short[4] += 1;
+        let sum = short[..short.len() - 1]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        *short.last_mut().unwrap() = 0u8.wrapping_sub(sum);
+        assert!(decode(&short).is_err());
+
+        // Unknown type, and lengths that can't be right
+        assert!(decode(&[0x99, 0x00, 0x00, 0x00, 0x06, 0x61]).is_err());
+        assert!(decode(&[0x52, 0x00, 0x00, 0x00, 0x02]).is_err());
+        assert!(decode(&[0x52, 0x7f, 0xff, 0xff, 0xff]).is_err());
+
+        // An unknown action
+        let mut bytes = Message::CreatePolicy {
+            species: "dog".to_string(),
+            action: Action::Cull,
+        }
+        .to_bytes();
+        let action = bytes.len() - 2;
+        bytes[action] = 0x91;
+        bytes[action + 1] -= 1;
+        assert!(decode(&bytes).is_err());
+    }
+}
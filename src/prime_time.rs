@@ -1,23 +1,86 @@
-use log::info;
+use crate::limiter::{Limit, Limiter, Verdict};
+use crate::metrics::metrics;
+use crate::service::{serve, Listener, ServerHandle, Service, ServiceFuture, StreamListener};
+use crate::shutdown::Shutdown;
 use primal::is_prime;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::ToSocketAddrs,
 };
+use tracing::{info, instrument};
 
-pub async fn run(port: &str) -> anyhow::Result<()> {
-    let addr = format!("0.0.0.0:{}", port);
-    info!("Running prime time server on {}...", &addr);
+/// Longest request line we will buffer before giving up on the client.
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
 
-    let listener = TcpListener::bind(&addr).await?;
+/// Sent in reply to a malformed request, just before disconnecting.
+const MALFORMED_RESPONSE: &[u8] = b"malformed\n";
 
-    loop {
-        let (stream, address) = listener.accept().await?;
-        tokio::spawn(async move { prime_handler(stream, address).await });
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrimeTime {
+    pub max_line_length: usize,
+}
+
+impl Default for PrimeTime {
+    fn default() -> Self {
+        Self {
+            max_line_length: MAX_LINE_LENGTH,
+        }
     }
 }
 
+impl Service for PrimeTime {
+    fn name(&self) -> &'static str {
+        "prime_time"
+    }
+
+    fn default_port(&self) -> u16 {
+        10001
+    }
+
+    fn configure(&mut self, options: toml::Table) -> anyhow::Result<()> {
+        let options: Self = options.try_into()?;
+        anyhow::ensure!(
+            options.max_line_length > 0,
+            "max_line_length must be above zero"
+        );
+        *self = options;
+        Ok(())
+    }
+
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        let max_line_length = self.max_line_length;
+        Box::pin(async move { run(listener.into_tcp()?, max_line_length, limiter, shutdown).await })
+    }
+}
+
+/// Starts the server on `bind` in the background, with the default options.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&PrimeTime::default(), bind).await
+}
+
+pub async fn run(
+    listener: StreamListener,
+    max_line_length: usize,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    info!("Running prime time server on {}...", listener.local_addr()?);
+
+    serve(
+        "prime_time",
+        listener,
+        limiter,
+        shutdown,
+        move |stream, peer, limit, shutdown| {
+            prime_handler(stream, peer, max_line_length, limit, shutdown)
+        },
+    )
+    .await
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Request {
     method: String,
@@ -30,23 +93,59 @@ struct Response {
     prime: bool,
 }
 
-async fn prime_handler(stream: TcpStream, address: std::net::SocketAddr) -> anyhow::Result<()> {
+#[instrument(parent = None, name = "connection", skip_all, fields(service = "prime_time", %peer), err(Display))]
+pub async fn prime_handler<S>(
+    stream: S,
+    peer: SocketAddr,
+    max_line_length: usize,
+    limit: Limit,
+    shutdown: Shutdown,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let mut reader = BufReader::new(stream);
-    let mut line = String::new();
+    let mut line = Vec::new();
 
-    while let Ok(num_bytes) = reader.read_line(&mut line).await {
-        if num_bytes == 0 {
+    while let Some(num_bytes) = shutdown
+        .or_cancel(
+            (&mut reader)
+                .take(max_line_length as u64 + 1)
+                .read_until(b'\n', &mut line),
+        )
+        .await
+    {
+        if num_bytes? == 0 {
             break;
         }
 
-        let request: Request = serde_json::from_str(line.trim())?;
-        info!("Received {:?} from {}", request, address);
+        if !line.ends_with(b"\n") && line.len() > max_line_length {
+            anyhow::bail!("Request is over {} bytes", max_line_length);
+        }
 
-        let response = match request.method.as_str() {
-            "isPrime" => handle_correct_request(request)?,
+        match limit.message().await {
+            Verdict::Allow => {}
+            Verdict::Drop => {
+                line.clear();
+                continue;
+            }
+            Verdict::Disconnect => return Ok(()),
+        }
 
-            _ => "Invalid request".to_string(),
+        // Text that isn't UTF-8 can't be JSON either
+        let request = match std::str::from_utf8(&line).ok().and_then(parse_request) {
+            Some(request) => request,
+            None => {
+                info!("Malformed request: {:?}", String::from_utf8_lossy(&line));
+                metrics().parse_error("prime_time");
+                reader.write_all(MALFORMED_RESPONSE).await?;
+                return Ok(());
+            }
         };
+        info!("Received {:?}", request);
+        metrics().request("prime_time", &request.method);
+
+        let response = handle_correct_request(request)?;
 
         reader.write_all(response.as_bytes()).await?;
         reader.write_u8(10).await?;
@@ -56,8 +155,22 @@ async fn prime_handler(stream: TcpStream, address: std::net::SocketAddr) -> anyh
     Ok(())
 }
 
+/// A conforming request is a JSON object with an `isPrime` method and a number.
+fn parse_request(line: &str) -> Option<Request> {
+    // Serde would also accept the fields as an array
+    let value: serde_json::Value = serde_json::from_str(line.trim()).ok()?;
+    if !value.is_object() {
+        return None;
+    }
+
+    let request: Request = serde_json::from_value(value).ok()?;
+    (request.method == "isPrime").then_some(request)
+}
+
 fn handle_correct_request(request: Request) -> anyhow::Result<String> {
-    let request_num_is_prime = is_prime(request.number as u64);
+    let timer = metrics().prime_check_seconds.start_timer();
+    let request_num_is_prime = number_is_prime(request.number);
+    timer.observe_duration();
     let response = Response {
         method: request.method,
         prime: request_num_is_prime,
@@ -66,3 +179,50 @@ fn handle_correct_request(request: Request) -> anyhow::Result<String> {
     info!("Sending {:?}", &response);
     serde_json::to_string(&response).map_err(|e| e.into())
 }
+
+fn number_is_prime(number: f64) -> bool {
+    // Fractions and negative numbers are never prime
+    number.fract() == 0.0 && number >= 2.0 && is_prime(number as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_a_zero_max_line_length() {
+        let options: toml::Table = toml::from_str("max_line_length = 0").unwrap();
+        let error = PrimeTime::default().configure(options).unwrap_err();
+        assert_eq!(error.to_string(), "max_line_length must be above zero");
+    }
+
+    #[tokio::test]
+    async fn answers_requests() -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(1024);
+        let peer = "127.0.0.1:1234".parse()?;
+        let handler = tokio::spawn(prime_handler(
+            server,
+            peer,
+            64,
+            Limit::none(),
+            Shutdown::new(),
+        ));
+
+        let mut client = BufReader::new(client);
+        client
+            .write_all(
+                b"{\"method\":\"isPrime\",\"number\":7}\n{\"method\":\"isPrime\",\"number\":8}\n",
+            )
+            .await?;
+        let mut responses = String::new();
+        client.read_line(&mut responses).await?;
+        client.read_line(&mut responses).await?;
+        assert_eq!(
+            responses,
+            "{\"method\":\"isPrime\",\"prime\":true}\n{\"method\":\"isPrime\",\"prime\":false}\n"
+        );
+
+        drop(client);
+        handler.await?
+    }
+}
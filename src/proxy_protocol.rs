@@ -0,0 +1,209 @@
+use anyhow::{bail, ensure, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How a version 1 header starts.
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest version 1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+/// How a version 2 header starts.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Reads a PROXY protocol header, version 1 or 2, from the start of `stream`,
+/// leaving everything after it unread. Returns the address of the client the
+/// balancer is passing on, or `None` if the header has none, as for the
+/// balancer's own health checks.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..6] {
+        read_v2(stream).await
+    } else {
+        bail!("missing PROXY protocol header")
+    }
+}
+
+async fn read_v1<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // A byte at a time, so none of the client's data is read with it
+    let mut line = V1_PREFIX.to_vec();
+    while !line.ends_with(b"\r\n") {
+        ensure!(
+            line.len() < V1_MAX_LENGTH,
+            "PROXY header is over {} bytes",
+            V1_MAX_LENGTH
+        );
+        line.push(stream.read_u8().await?);
+    }
+
+    parse_v1(&line[..line.len() - 2])
+}
+
+/// Parses a version 1 header without its CRLF, such as
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443`.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, source_port, _] => {
+            let ip: IpAddr = source.parse()?;
+            ensure!(
+                ip.is_ipv4() == (family == "TCP4"),
+                "{} is not a {} address",
+                ip,
+                family
+            );
+            Ok(Some(SocketAddr::new(ip, source_port.parse()?)))
+        }
+        _ => bail!("malformed PROXY header {:?}", line),
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 10];
+    stream.read_exact(&mut header).await?;
+    ensure!(
+        header[..6] == V2_SIGNATURE[6..],
+        "malformed PROXY v2 signature"
+    );
+
+    let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+
+    parse_v2(header[6], header[7], &addresses)
+}
+
+/// Parses what follows a version 2 signature: the version and command, the
+/// address family and transport, and then the addresses and any TLVs, which
+/// are ignored.
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>> {
+    ensure!(
+        version_command >> 4 == 2,
+        "unsupported PROXY version {}",
+        version_command >> 4
+    );
+    match version_command & 0x0f {
+        // The balancer's own connection
+        0x0 => return Ok(None),
+        0x1 => {}
+        command => bail!("unknown PROXY command {}", command),
+    }
+
+    match family >> 4 {
+        0x1 => {
+            ensure!(addresses.len() >= 12, "PROXY v2 IPv4 addresses cut short");
+            let ip: [u8; 4] = addresses[..4].try_into()?;
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        0x2 => {
+            ensure!(addresses.len() >= 36, "PROXY v2 IPv6 addresses cut short");
+            let ip: [u8; 16] = addresses[..16].try_into()?;
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // Unspecified or a Unix socket, neither of which is a peer we can use
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> Result<(Option<SocketAddr>, &[u8])> {
+        let address = read_header(&mut bytes).await?;
+        Ok((address, bytes))
+    }
+
+    fn v2(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([version_command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (address, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nhello")
+            .await
+            .unwrap();
+        assert_eq!(address, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+
+        let (address, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(address, Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let (address, rest) = read(b"PROXY UNKNOWN\r\nhello").await.unwrap();
+        assert_eq!(address, None);
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend(56324u16.to_be_bytes());
+        addresses.extend(443u16.to_be_bytes());
+        // A TLV, which is skipped
+        addresses.extend([0x04, 0x00, 0x01, 0xff]);
+        let mut bytes = v2(0x21, 0x11, &addresses);
+        bytes.extend(b"hello");
+
+        let (address, rest) = read(&bytes).await.unwrap();
+        assert_eq!(address, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend([0; 16]);
+        addresses.extend(4000u16.to_be_bytes());
+        addresses.extend(443u16.to_be_bytes());
+        let (address, _) = read(&v2(0x21, 0x21, &addresses)).await.unwrap();
+        assert_eq!(address, Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        // LOCAL, with the addresses still there to skip
+        let mut bytes = v2(0x20, 0x11, &[0; 12]);
+        bytes.extend(b"hello");
+        let (address, rest) = read(&bytes).await.unwrap();
+        assert_eq!(address, None);
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_headers() {
+        let too_long = format!("PROXY UNKNOWN {}\r\n", "x".repeat(100));
+        let bad = [
+            b"GET / HTTP/1.1\r\n".to_vec(),
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n".to_vec(),
+            b"PROXY TCP4 2001:db8::1 2001:db8::2 4000 443\r\n".to_vec(),
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n".to_vec(),
+            b"PROXY TCP4 192.0.2.1".to_vec(),
+            too_long.into_bytes(),
+            v2(0x11, 0x11, &[0; 12]),
+            v2(0x22, 0x11, &[0; 12]),
+            v2(0x21, 0x11, &[0; 8]),
+            v2(0x21, 0x21, &[0; 12]),
+            V2_SIGNATURE[..10].to_vec(),
+        ];
+
+        for bytes in bad {
+            assert!(read(&bytes).await.is_err(), "{:?}", bytes.escape_ascii());
+        }
+    }
+}
@@ -0,0 +1,595 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{
+    TcpListener, TcpStream, ToSocketAddrs, UdpSocket, UnixDatagram, UnixListener, UnixStream,
+};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_rustls::{rustls::ServerConfig, server::TlsStream, TlsAcceptor};
+use tracing::{info, info_span, Instrument};
+
+use crate::limiter::{Limit, Limiter};
+use crate::metrics::{self, Metered};
+use crate::proxy_protocol;
+use crate::shutdown::Shutdown;
+use crate::{
+    budget_chat::BudgetChat, code_storage::CodeStorage, insecure_sockets::InsecureSockets,
+    job_centre::JobCentre, line_reversal::LineReversal, means_to_an_end::MeansToAnEnd,
+    mob_in_the_middle::MobInTheMiddle, pest_control::PestControl, prime_time::PrimeTime,
+    smoke_test::SmokeTest, speed_daemon::SpeedDaemon, unusual_database::UnusualDatabase,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Tcp => f.pad("tcp"),
+            Transport::Udp => f.pad("udp"),
+        }
+    }
+}
+
+/// How long a connection gets to send its PROXY protocol header and finish
+/// its TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The address Unix socket peers go by in logs and the admin interface. They're
+/// on this host but have no address of their own, and are exempt from limits
+/// and access lists rather than all counting as this one client.
+pub const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// How a service's listener treats new connections, beyond where it listens.
+#[derive(Debug, Clone, Default)]
+pub struct ListenOptions {
+    /// Read a PROXY protocol header from each TCP connection and take the
+    /// client's address from it, for services behind a load balancer.
+    pub proxy_protocol: bool,
+    /// Terminate TLS on each TCP connection, after any PROXY header.
+    pub tls: Option<Arc<ServerConfig>>,
+    /// Also listen on a Unix socket at this path, a stream socket for TCP
+    /// services and a datagram socket for UDP ones. Its peers are on this
+    /// host, so they send no PROXY header, skip TLS, and aren't held to the
+    /// access list or limits.
+    pub unix_socket: Option<PathBuf>,
+}
+
+/// Bound sockets, ready to hand to a service.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(StreamListener),
+    Udp {
+        socket: UdpSocket,
+        unix: Option<UnixDatagram>,
+    },
+}
+
+impl Listener {
+    pub async fn bind(
+        transport: Transport,
+        addr: impl ToSocketAddrs,
+        options: ListenOptions,
+    ) -> io::Result<Self> {
+        match transport {
+            Transport::Tcp => {
+                let listener = TcpListener::bind(addr).await?;
+                let unix = match &options.unix_socket {
+                    Some(path) => Some(UnixListener::bind(remove_stale_socket(path, transport)?)?),
+                    None => None,
+                };
+                Ok(Listener::Tcp(StreamListener::new(listener, unix, options)))
+            }
+            Transport::Udp => {
+                let socket = UdpSocket::bind(addr).await?;
+                let unix = match &options.unix_socket {
+                    Some(path) => Some(UnixDatagram::bind(remove_stale_socket(path, transport)?)?),
+                    None => None,
+                };
+                Ok(Listener::Udp { socket, unix })
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            Listener::Udp { socket, .. } => socket.local_addr(),
+        }
+    }
+
+    pub fn into_tcp(self) -> anyhow::Result<StreamListener> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener),
+            Listener::Udp { .. } => anyhow::bail!("Expected a TCP listener, got a UDP socket"),
+        }
+    }
+
+    pub fn into_udp(self) -> anyhow::Result<UdpSocket> {
+        match self.into_datagram_sockets()? {
+            (socket, None) => Ok(socket),
+            (_, Some(_)) => anyhow::bail!("Expected a UDP socket, got a Unix socket as well"),
+        }
+    }
+
+    /// The UDP socket, with the Unix datagram socket if there is one.
+    pub fn into_datagram_sockets(self) -> anyhow::Result<(UdpSocket, Option<UnixDatagram>)> {
+        match self {
+            Listener::Udp { socket, unix } => Ok((socket, unix)),
+            Listener::Tcp(_) => anyhow::bail!("Expected a UDP socket, got a TCP listener"),
+        }
+    }
+}
+
+/// Removes a socket an earlier run left at `path`, which would stop it being
+/// bound again. Only sockets refusing connections are stale, so one a running
+/// server still answers on is left, like anything else there, for the bind to
+/// fail on.
+fn remove_stale_socket(path: &Path, transport: Transport) -> io::Result<&Path> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            let connected = match transport {
+                Transport::Tcp => std::os::unix::net::UnixStream::connect(path).map(drop),
+                Transport::Udp => std::os::unix::net::UnixDatagram::unbound()
+                    .and_then(|socket| socket.connect(path)),
+            };
+            if connected.is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused) {
+                std::fs::remove_file(path)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(path)
+}
+
+/// Accepts connections for a stream service, yielding each with the address of
+/// the client behind it. With the PROXY protocol or TLS on, each connection's
+/// header and handshake happen on a task of their own, so a client slow to
+/// send them holds up nobody else, and connections that fail either are closed.
+/// So are connections from peers the access list rejects, before any handshake
+/// unless the client's address has to come from its PROXY header.
+/// Connections to the Unix socket, if any, come straight through as from
+/// `UNIX_PEER`, whatever the access list says.
+#[derive(Debug)]
+pub struct StreamListener {
+    listener: TcpListener,
+    unix: Option<UnixListener>,
+    options: ListenOptions,
+    // Connections still sending their PROXY header or TLS handshake
+    pending: JoinSet<Option<(Stream, SocketAddr)>>,
+}
+
+impl StreamListener {
+    pub fn new(listener: TcpListener, unix: Option<UnixListener>, options: ListenOptions) -> Self {
+        Self {
+            listener,
+            unix,
+            options,
+            pending: JoinSet::new(),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts the next connection from a peer `limiter` permits. Cancelling
+    /// this loses no connections.
+    pub async fn accept(&mut self, limiter: &Limiter) -> io::Result<(Stream, SocketAddr)> {
+        let handshakes = self.options.proxy_protocol || self.options.tls.is_some();
+
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => {
+                    let (stream, address) = accepted?;
+                    // Behind a balancer the client is only known after the header
+                    if !self.options.proxy_protocol && !limiter.permits(address.ip()) {
+                        continue;
+                    }
+                    if !handshakes {
+                        return Ok((Stream::Tcp(stream), address));
+                    }
+                    let handshake = handshake(stream, address, self.options.clone());
+                    self.pending.spawn(handshake.in_current_span());
+                }
+
+                Some(accepted) = accept_unix(self.unix.as_ref()) => {
+                    return Ok((Stream::Unix(accepted?), UNIX_PEER));
+                }
+
+                Some(joined) = self.pending.join_next() => {
+                    let Ok(Some((stream, client))) = joined else {
+                        continue;
+                    };
+                    if !self.options.proxy_protocol || limiter.permits(client.ip()) {
+                        return Ok((stream, client));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs a stream service's accept loop until shutdown, handing each connection
+/// to `handler` on a task of its own. Peers the access list rejects are closed
+/// straight away, and the rest only count towards the metrics and show up in
+/// the admin interface once `limiter` has admitted them. Unix socket peers
+/// aren't limited.
+pub async fn serve<H, Fut>(
+    service: &'static str,
+    mut listener: StreamListener,
+    limiter: Limiter,
+    shutdown: Shutdown,
+    handler: H,
+) -> anyhow::Result<()>
+where
+    H: Fn(Metered<Stream>, SocketAddr, Limit, Shutdown) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+    while let Some((stream, peer)) = shutdown.accept(&mut listener, &limiter).await? {
+        let limiter = limiter.clone();
+        let handler = handler.clone();
+        shutdown.spawn(move |shutdown| async move {
+            let limit = match stream {
+                Stream::Unix(_) => Limit::none(),
+                _ => match limiter.admit(peer.ip(), &shutdown).await {
+                    Some(limit) => limit,
+                    None => return,
+                },
+            };
+            let stream = metrics::track(service, peer, stream);
+            // Handlers log their own errors
+            let _ = handler(stream, peer, limit, shutdown).await;
+        });
+    }
+
+    Ok(())
+}
+
+/// Accepts from `listener`, or `None` straight away without one.
+async fn accept_unix(listener: Option<&UnixListener>) -> Option<io::Result<UnixStream>> {
+    Some(listener?.accept().await.map(|(stream, _)| stream))
+}
+
+/// Reads `stream`'s PROXY header and does its TLS handshake, as `options` ask,
+/// returning the stream with the client's address, or `None` if it has to be
+/// closed.
+async fn handshake(
+    mut stream: TcpStream,
+    address: SocketAddr,
+    options: ListenOptions,
+) -> Option<(Stream, SocketAddr)> {
+    let handshake = async {
+        let mut client = address;
+        if options.proxy_protocol {
+            // Without a client address it's the balancer itself
+            client = proxy_protocol::read_header(&mut stream)
+                .await?
+                .unwrap_or(address);
+        }
+
+        let stream = match options.tls {
+            Some(config) => {
+                let stream = TlsAcceptor::from(config).accept(stream).await;
+                Stream::Tls(Box::new(
+                    stream.map_err(|e| anyhow::anyhow!("TLS handshake failed: {}", e))?,
+                ))
+            }
+            None => Stream::Tcp(stream),
+        };
+        anyhow::Ok((stream, client))
+    };
+
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(connection)) => Some(connection),
+        Ok(Err(e)) => {
+            info!(peer = %address, "Closing connection: {}", e);
+            None
+        }
+        Err(_) => {
+            info!(peer = %address, "Closing connection: no handshake after {:?}", HANDSHAKE_TIMEOUT);
+            None
+        }
+    }
+}
+
+/// An accepted connection, in plain TCP, with TLS terminated, or over the
+/// service's Unix socket.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            Stream::Tls(stream) => stream.is_write_vectored(),
+            Stream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+pub type ServiceFuture = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+/// One Protohackers problem. The binary binds a listener of the service's
+/// transport and hands it over; the service serves on it, holding its peers to
+/// `limiter`, until it fails or `shutdown` is triggered. Connections spawned
+/// through `shutdown` may run on after that, and the binary waits for them to
+/// drain.
+pub trait Service: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn default_port(&self) -> u16;
+
+    fn transport(&self) -> Transport {
+        Transport::Tcp
+    }
+
+    /// Whether the service can also listen on a Unix socket, see
+    /// `ListenOptions::unix_socket`.
+    fn serves_unix_sockets(&self) -> bool {
+        self.transport() == Transport::Tcp
+    }
+
+    /// Applies the service specific options from the service's config table.
+    fn configure(&mut self, options: toml::Table) -> anyhow::Result<()> {
+        match options.keys().next() {
+            Some(option) => anyhow::bail!("{} has no option {:?}", self.name(), option),
+            None => Ok(()),
+        }
+    }
+
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture;
+}
+
+/// A service running in the background, for embedding the servers in other
+/// programs and tests. Dropping the handle leaves the server running.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: Shutdown,
+    server: JoinHandle<anyhow::Result<()>>,
+}
+
+/// Runs `service` on its own task, with everything it logs in a span naming it.
+pub(crate) fn start(
+    service: &dyn Service,
+    listener: Listener,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> JoinHandle<anyhow::Result<()>> {
+    let span = info_span!("service", service = service.name());
+    tokio::spawn(service.run(listener, limiter, shutdown).instrument(span))
+}
+
+impl ServerHandle {
+    /// Binds `addr`, which may use port 0, and starts `service` on it.
+    pub async fn spawn(service: &dyn Service, addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        Self::spawn_with(
+            service,
+            addr,
+            ListenOptions::default(),
+            Limiter::unlimited(),
+        )
+        .await
+    }
+
+    /// Like `spawn`, listening with `options` and holding the service's peers
+    /// to `limiter`.
+    pub async fn spawn_with(
+        service: &dyn Service,
+        addr: impl ToSocketAddrs,
+        options: ListenOptions,
+        limiter: Limiter,
+    ) -> anyhow::Result<Self> {
+        let listener = Listener::bind(service.transport(), addr, options).await?;
+        let local_addr = listener.local_addr()?;
+        let shutdown = Shutdown::new();
+        let server = start(service, listener, limiter, shutdown.clone());
+
+        Ok(Self {
+            local_addr,
+            shutdown,
+            server,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting connections and waits for the open ones to finish.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        self.shutdown.trigger();
+        self.join().await
+    }
+
+    /// Waits for the server to stop, either through `shutdown` or because it
+    /// failed, and then for its connections to finish.
+    pub async fn join(self) -> anyhow::Result<()> {
+        let result = self.server.await;
+        self.shutdown.trigger();
+        self.shutdown.drained().await;
+        result?
+    }
+}
+
+/// Every service, in problem order.
+pub fn registry() -> Vec<Box<dyn Service>> {
+    vec![
+        Box::new(SmokeTest),
+        Box::new(PrimeTime::default()),
+        Box::new(MeansToAnEnd),
+        Box::new(BudgetChat),
+        Box::new(UnusualDatabase),
+        Box::new(MobInTheMiddle::default()),
+        Box::new(SpeedDaemon),
+        Box::new(LineReversal::default()),
+        Box::new(InsecureSockets),
+        Box::new(JobCentre),
+        Box::new(CodeStorage::default()),
+        Box::new(PestControl::default()),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::access::AccessList;
+    use crate::limiter::Limits;
+    use std::collections::HashSet;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn unique_names_and_ports() {
+        let services = registry();
+        let names: HashSet<_> = services.iter().map(|s| s.name()).collect();
+        let ports: HashSet<_> = services.iter().map(|s| s.default_port()).collect();
+
+        assert_eq!(names.len(), services.len());
+        assert_eq!(ports.len(), services.len());
+    }
+
+    #[tokio::test]
+    async fn spawn_on_ephemeral_ports() {
+        let first = ServerHandle::spawn(&SmokeTest, "127.0.0.1:0")
+            .await
+            .unwrap();
+        let second = ServerHandle::spawn(&SmokeTest, "127.0.0.1:0")
+            .await
+            .unwrap();
+        assert_ne!(first.local_addr().port(), 0);
+        assert_ne!(first.local_addr(), second.local_addr());
+
+        let mut stream = TcpStream::connect(first.local_addr()).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // The open connection is closed as part of shutting down
+        first.shutdown().await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        second.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn counts_connections_once_admitted() {
+        let limits = Limits {
+            max_connections: Some(1),
+            ..Limits::default()
+        };
+        let limiter = Limiter::new("serve_test", limits, AccessList::default());
+        let listener = Listener::bind(Transport::Tcp, "127.0.0.1:0", ListenOptions::default())
+            .await
+            .unwrap()
+            .into_tcp()
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let handler = crate::smoke_test::handle_stream;
+        let server = tokio::spawn(serve(
+            "serve_test",
+            listener,
+            limiter,
+            shutdown.clone(),
+            handler,
+        ));
+        let active = metrics::metrics()
+            .active_connections
+            .with_label_values(&["serve_test"]);
+
+        let mut buf = [0; 1];
+        let mut first = TcpStream::connect(address).await.unwrap();
+        first.write_all(b"1").await.unwrap();
+        first.read_exact(&mut buf).await.unwrap();
+        assert_eq!(active.get(), 1);
+
+        // Waiting for a slot doesn't count as being connected
+        let mut second = TcpStream::connect(address).await.unwrap();
+        second.write_all(b"2").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(active.get(), 1);
+
+        drop(first);
+        second.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"2");
+        assert_eq!(active.get(), 1);
+
+        shutdown.trigger();
+        server.await.unwrap().unwrap();
+        assert_eq!(second.read(&mut buf).await.unwrap(), 0);
+        shutdown.drained().await;
+        assert_eq!(active.get(), 0);
+    }
+}
@@ -0,0 +1,104 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use tokio::task::JoinHandle;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::limiter::Limiter;
+use crate::service::{Stream, StreamListener};
+
+/// Tells a server and its connections when to stop, and keeps track of the
+/// connections so the server can wait for them to finish.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    connections: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.token.cancel();
+        self.connections.close();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Runs `future` unless shutdown comes first. Handlers wrap the wait for
+    /// their next request in this, so a request already being handled finishes.
+    pub async fn or_cancel<F: Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            _ = self.token.cancelled() => None,
+            output = future => Some(output),
+        }
+    }
+
+    /// Accepts the next connection `limiter` permits, or `None` once shutdown
+    /// has started.
+    pub async fn accept(
+        &self,
+        listener: &mut StreamListener,
+        limiter: &Limiter,
+    ) -> io::Result<Option<(Stream, SocketAddr)>> {
+        self.or_cancel(listener.accept(limiter)).await.transpose()
+    }
+
+    /// Spawns a connection handler that `drained` will wait for, handing it
+    /// its own copy of the shutdown signal.
+    pub fn spawn<F, Fut>(&self, task: F) -> JoinHandle<Fut::Output>
+    where
+        F: FnOnce(Shutdown) -> Fut,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        self.connections.spawn(task(self.clone()))
+    }
+
+    /// Completes once shutdown has started and every spawned handler is done.
+    pub async fn drained(&self) {
+        self.connections.wait().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn drains_handlers() {
+        let shutdown = Shutdown::new();
+        let (tx, rx) = oneshot::channel::<()>();
+
+        shutdown.spawn(|shutdown| async move {
+            // Waiting for the next request stops at shutdown
+            assert!(shutdown
+                .or_cancel(std::future::pending::<()>())
+                .await
+                .is_none());
+            // But the handler still gets to finish up
+            rx.await.unwrap();
+        });
+
+        assert_eq!(shutdown.or_cancel(async { 1 }).await, Some(1));
+        shutdown.trigger();
+        assert!(shutdown.is_triggered());
+
+        let drained = tokio::time::timeout(Duration::from_millis(50), shutdown.drained());
+        assert!(drained.await.is_err());
+
+        tx.send(()).unwrap();
+        shutdown.drained().await;
+    }
+}
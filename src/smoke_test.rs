@@ -1,23 +1,91 @@
-use log::info;
+use crate::limiter::{Limit, Limiter, Verdict};
+use crate::service::{serve, Listener, ServerHandle, Service, ServiceFuture, StreamListener};
+use crate::shutdown::Shutdown;
+use std::net::SocketAddr;
 use tokio::{
-    io::copy,
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::ToSocketAddrs,
 };
+use tracing::{info, instrument};
 
-pub async fn run(port: &str) -> anyhow::Result<()> {
-    info!("Running smoke test...");
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(addr).await?;
+pub struct SmokeTest;
 
-    loop {
-        let (stream, _address) = listener.accept().await?;
-        tokio::spawn(async move { handle_stream(stream).await });
+impl Service for SmokeTest {
+    fn name(&self) -> &'static str {
+        "smoke_test"
     }
+
+    fn default_port(&self) -> u16 {
+        10000
+    }
+
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        Box::pin(async move { run(listener.into_tcp()?, limiter, shutdown).await })
+    }
+}
+
+/// Starts the server on `bind` in the background.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&SmokeTest, bind).await
 }
 
-async fn handle_stream(mut stream: TcpStream) -> anyhow::Result<()> {
-    let (mut reader, mut writer) = stream.split();
+pub async fn run(
+    listener: StreamListener,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    info!("Running smoke test on {}...", listener.local_addr()?);
+
+    serve("smoke_test", listener, limiter, shutdown, handle_stream).await
+}
+
+#[instrument(parent = None, name = "connection", skip_all, fields(service = "smoke_test", %peer), err(Display))]
+pub async fn handle_stream<S>(
+    mut stream: S,
+    peer: SocketAddr,
+    limit: Limit,
+    shutdown: Shutdown,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = [0u8; 4096];
     info!("Copying data...");
-    copy(&mut reader, &mut writer).await?;
+
+    // Stop reading at shutdown, but echo whatever has been read
+    while let Some(num_bytes) = shutdown.or_cancel(stream.read(&mut buf)).await {
+        let num_bytes = num_bytes?;
+        if num_bytes == 0 {
+            break;
+        }
+
+        // Each read counts as a message
+        match limit.message().await {
+            Verdict::Allow => stream.write_all(&buf[..num_bytes]).await?,
+            Verdict::Drop => {}
+            Verdict::Disconnect => break,
+        }
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn echoes_until_closed() -> anyhow::Result<()> {
+        let (mut client, server) = tokio::io::duplex(64);
+        let peer = "127.0.0.1:1234".parse()?;
+        let handler = tokio::spawn(handle_stream(server, peer, Limit::none(), Shutdown::new()));
+
+        client.write_all(b"hello").await?;
+        client.shutdown().await?;
+        let mut echoed = Vec::new();
+        client.read_to_end(&mut echoed).await?;
+
+        assert_eq!(echoed, b"hello");
+        handler.await?
+    }
+}
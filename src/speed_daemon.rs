@@ -0,0 +1,236 @@
+use crate::limiter::{Limit, Limiter, Verdict};
+use crate::metrics::metrics;
+use crate::service::{serve, Listener, ServerHandle, Service, ServiceFuture, StreamListener};
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::ToSocketAddrs,
+    sync::mpsc::UnboundedReceiver,
+    time::{interval_at, Instant, Interval},
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+use tracing::{info, instrument};
+
+use self::{
+    message::{ClientMessage, ServerMessage, SpeedDaemonCodec, Ticket},
+    state::{DispatcherId, State},
+};
+
+mod message;
+mod state;
+
+enum Heartbeat {
+    Unrequested,
+    Off,
+    Every(Interval),
+}
+
+/// What a client has said it is. Clients may only identify themselves once.
+enum Client {
+    Unidentified,
+    Camera {
+        road: u16,
+        mile: u16,
+        limit: u16,
+    },
+    Dispatcher {
+        id: DispatcherId,
+        tickets: UnboundedReceiver<Ticket>,
+    },
+}
+
+pub struct SpeedDaemon;
+
+impl Service for SpeedDaemon {
+    fn name(&self) -> &'static str {
+        "speed_daemon"
+    }
+
+    fn default_port(&self) -> u16 {
+        10006
+    }
+
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        Box::pin(async move { run(listener.into_tcp()?, limiter, shutdown).await })
+    }
+}
+
+/// Starts the server on `bind` in the background.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&SpeedDaemon, bind).await
+}
+
+pub async fn run(
+    listener: StreamListener,
+    limiter: Limiter,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    info!(
+        "Running speed daemon server on {}...",
+        listener.local_addr()?
+    );
+    let state = Arc::new(Mutex::new(State::default()));
+
+    serve(
+        "speed_daemon",
+        listener,
+        limiter,
+        shutdown,
+        move |stream, peer, limit, shutdown| {
+            handle_connection(stream, peer, state.clone(), limit, shutdown)
+        },
+    )
+    .await
+}
+
+#[instrument(parent = None, name = "connection", skip_all, fields(service = "speed_daemon", %peer), err(Display))]
+async fn handle_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    state: Arc<Mutex<State>>,
+    limit: Limit,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    info!("Accepted connection");
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = FramedRead::new(reader, SpeedDaemonCodec);
+
+    let mut client = Client::Unidentified;
+    let mut heartbeat = Heartbeat::Unrequested;
+    // A ticket that failed to send, to go back in the queue
+    let mut unsent = None;
+
+    let result = loop {
+        tokio::select! {
+            message = reader.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    Some(Err(e)) => {
+                        metrics().parse_error("speed_daemon");
+                        break Err(e);
+                    }
+                    None => break Ok(()),
+                };
+                match limit.message().await {
+                    Verdict::Allow => {}
+                    Verdict::Drop => continue,
+                    Verdict::Disconnect => break Err(anyhow::anyhow!("Too many messages")),
+                }
+                info!("Received {:?}", message);
+                metrics().request("speed_daemon", message.method());
+
+                if let Err(e) = handle_message(message, &mut client, &mut heartbeat, &state) {
+                    break Err(e);
+                }
+            }
+
+            _ = tick(&mut heartbeat) => {
+                if let Err(e) = send(&mut writer, ServerMessage::Heartbeat).await {
+                    break Err(e);
+                }
+            }
+
+            Some(ticket) = next_ticket(&mut client) => {
+                info!("Sending {:?}", ticket);
+                if let Err(e) = send(&mut writer, ServerMessage::Ticket(ticket.clone())).await {
+                    unsent = Some(ticket);
+                    break Err(e);
+                }
+            }
+
+            _ = shutdown.triggered() => break Ok(()),
+        }
+    };
+
+    if let Client::Dispatcher { id, mut tickets } = client {
+        // Nothing more is sent to the dispatcher while the lock is held
+        let mut state = state.lock().unwrap();
+        let buffered = std::iter::from_fn(|| tickets.try_recv().ok());
+        state.remove_dispatcher(id, unsent.into_iter().chain(buffered));
+    }
+
+    if let Err(e) = result {
+        info!("Sending error: {}", e);
+        send(&mut writer, ServerMessage::error(&e.to_string())).await?;
+    }
+
+    Ok(())
+}
+
+fn handle_message(
+    message: ClientMessage,
+    client: &mut Client,
+    heartbeat: &mut Heartbeat,
+    state: &Mutex<State>,
+) -> Result<()> {
+    match (message, &client) {
+        (ClientMessage::Plate { plate, timestamp }, Client::Camera { road, mile, limit }) => {
+            let tickets = state
+                .lock()
+                .unwrap()
+                .observe(&plate, *road, *mile, *limit, timestamp);
+            for ticket in tickets {
+                info!("Issued ticket {:?}", ticket);
+            }
+        }
+
+        (ClientMessage::Plate { .. }, _) => anyhow::bail!("not a camera"),
+
+        (ClientMessage::WantHeartbeat { interval }, _) => {
+            if !matches!(heartbeat, Heartbeat::Unrequested) {
+                anyhow::bail!("heartbeat already requested");
+            }
+
+            // An interval of zero means no heartbeats, but still counts as the one request
+            let period = Duration::from_millis(interval as u64 * 100);
+            *heartbeat = match interval {
+                0 => Heartbeat::Off,
+                _ => Heartbeat::Every(interval_at(Instant::now() + period, period)),
+            };
+        }
+
+        (ClientMessage::IAmCamera { road, mile, limit }, Client::Unidentified) => {
+            *client = Client::Camera { road, mile, limit };
+        }
+
+        (ClientMessage::IAmDispatcher { roads }, Client::Unidentified) => {
+            let (id, tickets) = state.lock().unwrap().add_dispatcher(&roads);
+            *client = Client::Dispatcher { id, tickets };
+        }
+
+        (ClientMessage::IAmCamera { .. } | ClientMessage::IAmDispatcher { .. }, _) => {
+            anyhow::bail!("already identified")
+        }
+    }
+
+    Ok(())
+}
+
+async fn tick(heartbeat: &mut Heartbeat) {
+    match heartbeat {
+        Heartbeat::Every(interval) => {
+            interval.tick().await;
+        }
+        _ => std::future::pending().await,
+    }
+}
+
+async fn next_ticket(client: &mut Client) -> Option<Ticket> {
+    match client {
+        Client::Dispatcher { tickets, .. } => tickets.recv().await,
+        _ => std::future::pending().await,
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin>(writer: &mut W, message: ServerMessage) -> Result<()> {
+    writer.write_all(&message.to_bytes()).await?;
+    Ok(())
+}
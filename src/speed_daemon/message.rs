@@ -0,0 +1,284 @@
+use nom::{
+    error,
+    multi::{length_count, length_data},
+    number::streaming::{be_u16, be_u32, be_u8},
+    IResult,
+};
+use tokio_util::{
+    bytes::{Buf, BytesMut},
+    codec::Decoder,
+};
+
+const ERROR: u8 = 0x10;
+const PLATE: u8 = 0x20;
+const TICKET: u8 = 0x21;
+const WANT_HEARTBEAT: u8 = 0x40;
+const HEARTBEAT: u8 = 0x41;
+const I_AM_CAMERA: u8 = 0x80;
+const I_AM_DISPATCHER: u8 = 0x81;
+
+/// Messages sent from cameras and dispatchers to the server.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ClientMessage {
+    Plate { plate: String, timestamp: u32 },
+    WantHeartbeat { interval: u32 },
+    IAmCamera { road: u16, mile: u16, limit: u16 },
+    IAmDispatcher { roads: Vec<u16> },
+}
+
+impl ClientMessage {
+    /// The message type, for metrics.
+    pub fn method(&self) -> &'static str {
+        match self {
+            ClientMessage::Plate { .. } => "plate",
+            ClientMessage::WantHeartbeat { .. } => "want_heartbeat",
+            ClientMessage::IAmCamera { .. } => "i_am_camera",
+            ClientMessage::IAmDispatcher { .. } => "i_am_dispatcher",
+        }
+    }
+}
+
+/// Messages sent from the server to cameras and dispatchers.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ServerMessage {
+    Error { msg: String },
+    Ticket(Ticket),
+    Heartbeat,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ticket {
+    pub plate: String,
+    pub road: u16,
+    pub mile1: u16,
+    pub timestamp1: u32,
+    pub mile2: u16,
+    pub timestamp2: u32,
+    /// 100x miles per hour
+    pub speed: u16,
+}
+
+impl ServerMessage {
+    pub fn error(msg: &str) -> Self {
+        Self::Error {
+            msg: msg.to_string(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            ServerMessage::Error { msg } => {
+                bytes.push(ERROR);
+                push_str(&mut bytes, msg);
+            }
+
+            ServerMessage::Ticket(ticket) => {
+                bytes.push(TICKET);
+                push_str(&mut bytes, &ticket.plate);
+                bytes.extend_from_slice(&ticket.road.to_be_bytes());
+                bytes.extend_from_slice(&ticket.mile1.to_be_bytes());
+                bytes.extend_from_slice(&ticket.timestamp1.to_be_bytes());
+                bytes.extend_from_slice(&ticket.mile2.to_be_bytes());
+                bytes.extend_from_slice(&ticket.timestamp2.to_be_bytes());
+                bytes.extend_from_slice(&ticket.speed.to_be_bytes());
+            }
+
+            ServerMessage::Heartbeat => bytes.push(HEARTBEAT),
+        }
+        bytes
+    }
+}
+
+fn push_str(bytes: &mut Vec<u8>, s: &str) {
+    // Strings are at most 255 bytes on the wire
+    let s = &s.as_bytes()[..s.len().min(u8::MAX as usize)];
+    bytes.push(s.len() as u8);
+    bytes.extend_from_slice(s);
+}
+
+/// Decodes client messages from a byte stream, waiting for more data when a
+/// message has been split across reads.
+#[derive(Debug, Default)]
+pub struct SpeedDaemonCodec;
+
+impl Decoder for SpeedDaemonCodec {
+    type Item = ClientMessage;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match parse_client_message(src) {
+            Ok((input, message)) => {
+                let consumed = src.len() - input.len();
+                src.advance(consumed);
+                Ok(Some(message))
+            }
+            Err(nom::Err::Incomplete(_)) => Ok(None),
+            Err(_) => Err(anyhow::anyhow!("illegal msg")),
+        }
+    }
+}
+
+fn parse_client_message(input: &[u8]) -> IResult<&[u8], ClientMessage> {
+    let (input, kind) = be_u8(input)?;
+
+    match kind {
+        PLATE => {
+            let (input, plate) = parse_str(input)?;
+            let (input, timestamp) = be_u32(input)?;
+            Ok((input, ClientMessage::Plate { plate, timestamp }))
+        }
+
+        WANT_HEARTBEAT => {
+            let (input, interval) = be_u32(input)?;
+            Ok((input, ClientMessage::WantHeartbeat { interval }))
+        }
+
+        I_AM_CAMERA => {
+            let (input, road) = be_u16(input)?;
+            let (input, mile) = be_u16(input)?;
+            let (input, limit) = be_u16(input)?;
+            Ok((input, ClientMessage::IAmCamera { road, mile, limit }))
+        }
+
+        I_AM_DISPATCHER => {
+            let (input, roads) = length_count(be_u8, be_u16)(input)?;
+            Ok((input, ClientMessage::IAmDispatcher { roads }))
+        }
+
+        _ => Err(nom::Err::Failure(error::Error::new(
+            input,
+            error::ErrorKind::Tag,
+        ))),
+    }
+}
+
+fn parse_str(input: &[u8]) -> IResult<&[u8], String> {
+    let (input, bytes) = length_data(be_u8)(input)?;
+    Ok((input, String::from_utf8_lossy(bytes).to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_all(bytes: &[u8]) -> anyhow::Result<Vec<ClientMessage>> {
+        let mut src = BytesMut::from(bytes);
+        let mut messages = Vec::new();
+        while let Some(message) = SpeedDaemonCodec.decode(&mut src)? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    #[test]
+    fn parse_plate() {
+        let bytes = [0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x03, 0xe8];
+        assert_eq!(
+            decode_all(&bytes).unwrap(),
+            vec![ClientMessage::Plate {
+                plate: "UN1X".to_string(),
+                timestamp: 1000
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_want_heartbeat() {
+        let bytes = [0x40, 0x00, 0x00, 0x04, 0xdb];
+        assert_eq!(
+            decode_all(&bytes).unwrap(),
+            vec![ClientMessage::WantHeartbeat { interval: 1243 }]
+        );
+    }
+
+    #[test]
+    fn parse_i_am_camera() {
+        let bytes = [0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c];
+        assert_eq!(
+            decode_all(&bytes).unwrap(),
+            vec![ClientMessage::IAmCamera {
+                road: 66,
+                mile: 100,
+                limit: 60
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_i_am_dispatcher() {
+        let bytes = [0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88];
+        assert_eq!(
+            decode_all(&bytes).unwrap(),
+            vec![ClientMessage::IAmDispatcher {
+                roads: vec![66, 368, 5000]
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_split_frames() {
+        let bytes = [
+            0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c, 0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00,
+            0x00, 0x03, 0xe8,
+        ];
+        let mut src = BytesMut::new();
+        let mut messages = Vec::new();
+
+        // Feed the stream one byte at a time
+        for byte in bytes {
+            src.extend_from_slice(&[byte]);
+            if let Some(message) = SpeedDaemonCodec.decode(&mut src).unwrap() {
+                messages.push(message);
+            }
+        }
+
+        assert!(src.is_empty());
+        assert_eq!(
+            messages,
+            vec![
+                ClientMessage::IAmCamera {
+                    road: 66,
+                    mile: 100,
+                    limit: 60
+                },
+                ClientMessage::Plate {
+                    plate: "UN1X".to_string(),
+                    timestamp: 1000
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_illegal_message_type() {
+        assert!(decode_all(&[0x21, 0x00]).is_err());
+        assert!(decode_all(&[0x10, 0x03, 0x62, 0x61, 0x64]).is_err());
+    }
+
+    #[test]
+    fn encode_server_messages() {
+        assert_eq!(
+            ServerMessage::error("bad").to_bytes(),
+            vec![0x10, 0x03, 0x62, 0x61, 0x64]
+        );
+        assert_eq!(ServerMessage::Heartbeat.to_bytes(), vec![0x41]);
+
+        let ticket = ServerMessage::Ticket(Ticket {
+            plate: "UN1X".to_string(),
+            road: 66,
+            mile1: 100,
+            timestamp1: 123456,
+            mile2: 110,
+            timestamp2: 123816,
+            speed: 10000,
+        });
+        assert_eq!(
+            ticket.to_bytes(),
+            vec![
+                0x21, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x42, 0x00, 0x64, 0x00, 0x01, 0xe2, 0x40,
+                0x00, 0x6e, 0x00, 0x01, 0xe3, 0xa8, 0x27, 0x10
+            ]
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use super::message::Ticket;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::info;
+
+const SECONDS_PER_DAY: u32 = 86400;
+
+pub type DispatcherId = usize;
+
+/// Everything the cameras have seen, and the dispatchers waiting for tickets.
+#[derive(Default)]
+pub struct State {
+    // (plate, road) -> timestamp -> mile
+    observations: HashMap<(String, u16), BTreeMap<u32, u16>>,
+    // (plate, day) pairs that already have a ticket
+    ticketed_days: HashSet<(String, u32)>,
+
+    next_dispatcher: DispatcherId,
+    dispatchers: HashMap<u16, Vec<(DispatcherId, UnboundedSender<Ticket>)>>,
+    // Tickets for roads that have no dispatcher yet
+    pending: HashMap<u16, Vec<Ticket>>,
+}
+
+impl State {
+    /// Records a plate observation and dispatches any tickets it results in.
+    pub fn observe(
+        &mut self,
+        plate: &str,
+        road: u16,
+        mile: u16,
+        limit: u16,
+        timestamp: u32,
+    ) -> Vec<Ticket> {
+        let observations = self
+            .observations
+            .entry((plate.to_string(), road))
+            .or_default();
+        observations.insert(timestamp, mile);
+
+        // Only the neighbouring observations can produce a new ticket
+        let before = observations.range(..timestamp).next_back();
+        let after = observations
+            .range((Bound::Excluded(timestamp), Bound::Unbounded))
+            .next();
+
+        let candidates: Vec<Ticket> = [
+            before.map(|(&t, &m)| ((t, m), (timestamp, mile))),
+            after.map(|(&t, &m)| ((timestamp, mile), (t, m))),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|(first, second)| check_speed(plate, road, limit, first, second))
+        .collect();
+
+        let mut issued = Vec::new();
+        for ticket in candidates {
+            if self.claim_days(&ticket) {
+                issued.push(ticket.clone());
+                self.dispatch(ticket);
+            }
+        }
+
+        issued
+    }
+
+    /// Registers a dispatcher for the given roads, handing it any tickets
+    /// that were waiting for one.
+    pub fn add_dispatcher(&mut self, roads: &[u16]) -> (DispatcherId, UnboundedReceiver<Ticket>) {
+        let id = self.next_dispatcher;
+        self.next_dispatcher += 1;
+
+        let (tx, rx) = unbounded_channel();
+        for road in roads {
+            self.dispatchers
+                .entry(*road)
+                .or_default()
+                .push((id, tx.clone()));
+
+            for ticket in self.pending.remove(road).unwrap_or_default() {
+                info!("Dispatching queued ticket {:?}", ticket);
+                let _ = tx.send(ticket);
+            }
+        }
+
+        (id, rx)
+    }
+
+    /// Unregisters a dispatcher, passing the tickets it never sent on to
+    /// another dispatcher or back to the queue.
+    pub fn remove_dispatcher(
+        &mut self,
+        id: DispatcherId,
+        unsent: impl IntoIterator<Item = Ticket>,
+    ) {
+        for dispatchers in self.dispatchers.values_mut() {
+            dispatchers.retain(|(dispatcher, _)| *dispatcher != id);
+        }
+
+        for ticket in unsent {
+            info!("Requeueing unsent ticket {:?}", ticket);
+            self.dispatch(ticket);
+        }
+    }
+
+    fn dispatch(&mut self, ticket: Ticket) {
+        let mut ticket = ticket;
+
+        if let Some(dispatchers) = self.dispatchers.get_mut(&ticket.road) {
+            while let Some((_, tx)) = dispatchers.first() {
+                match tx.send(ticket) {
+                    Ok(()) => return,
+                    Err(e) => {
+                        // The dispatcher has gone away, try the next one
+                        ticket = e.0;
+                        dispatchers.remove(0);
+                    }
+                }
+            }
+        }
+
+        info!("No dispatcher for road {}, queueing ticket", ticket.road);
+        self.pending.entry(ticket.road).or_default().push(ticket);
+    }
+
+    /// Marks every day the ticket covers as ticketed, unless one of them
+    /// already is, in which case the ticket must not be issued.
+    fn claim_days(&mut self, ticket: &Ticket) -> bool {
+        let days = ticket.timestamp1 / SECONDS_PER_DAY..=ticket.timestamp2 / SECONDS_PER_DAY;
+
+        if days
+            .clone()
+            .any(|day| self.ticketed_days.contains(&(ticket.plate.clone(), day)))
+        {
+            return false;
+        }
+
+        for day in days {
+            self.ticketed_days.insert((ticket.plate.clone(), day));
+        }
+        true
+    }
+}
+
+fn check_speed(
+    plate: &str,
+    road: u16,
+    limit: u16,
+    (timestamp1, mile1): (u32, u16),
+    (timestamp2, mile2): (u32, u16),
+) -> Option<Ticket> {
+    let distance = mile1.abs_diff(mile2) as f64;
+    let hours = (timestamp2 - timestamp1) as f64 / 3600.0;
+    let speed = distance / hours;
+
+    if speed < limit as f64 + 0.5 {
+        return None;
+    }
+
+    Some(Ticket {
+        plate: plate.to_string(),
+        road,
+        mile1,
+        timestamp1,
+        mile2,
+        timestamp2,
+        speed: (speed * 100.0).round().min(u16::MAX as f64) as u16,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn example_ticket() {
+        let mut state = State::default();
+        assert!(state.observe("UN1X", 123, 8, 60, 0).is_empty());
+        let tickets = state.observe("UN1X", 123, 9, 60, 45);
+
+        assert_eq!(
+            tickets,
+            vec![Ticket {
+                plate: "UN1X".to_string(),
+                road: 123,
+                mile1: 8,
+                timestamp1: 0,
+                mile2: 9,
+                timestamp2: 45,
+                speed: 8000,
+            }]
+        );
+    }
+
+    #[test]
+    fn out_of_order_observations() {
+        let mut state = State::default();
+        assert!(state.observe("UN1X", 123, 9, 60, 45).is_empty());
+        let tickets = state.observe("UN1X", 123, 8, 60, 0);
+
+        assert_eq!(tickets.len(), 1);
+        assert_eq!((tickets[0].mile1, tickets[0].timestamp1), (8, 0));
+        assert_eq!((tickets[0].mile2, tickets[0].timestamp2), (9, 45));
+    }
+
+    #[test]
+    fn within_limit_is_not_ticketed() {
+        let mut state = State::default();
+        state.observe("UN1X", 123, 0, 60, 0);
+        // Exactly 60.4 mph
+        assert!(state.observe("UN1X", 123, 151, 60, 9000).is_empty());
+        // Different road
+        assert!(state.observe("UN1X", 124, 100, 60, 9001).is_empty());
+    }
+
+    #[test]
+    fn one_ticket_per_day() {
+        let mut state = State::default();
+        state.observe("UN1X", 1, 0, 60, 0);
+        assert_eq!(state.observe("UN1X", 1, 10, 60, 60).len(), 1);
+        assert!(state.observe("UN1X", 1, 20, 60, 120).is_empty());
+
+        // Spanning into the next day is blocked by the first ticket too
+        assert!(state
+            .observe("UN1X", 2, 0, 60, SECONDS_PER_DAY - 60)
+            .is_empty());
+        assert!(state.observe("UN1X", 2, 10, 60, SECONDS_PER_DAY).is_empty());
+
+        // But a fresh day is fine
+        state.observe("UN1X", 3, 0, 60, SECONDS_PER_DAY * 3);
+        assert_eq!(
+            state
+                .observe("UN1X", 3, 10, 60, SECONDS_PER_DAY * 3 + 60)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn tickets_wait_for_a_dispatcher() {
+        let mut state = State::default();
+        state.observe("UN1X", 123, 8, 60, 0);
+        state.observe("UN1X", 123, 9, 60, 45);
+
+        let (_id, mut other_road) = state.add_dispatcher(&[1]);
+        assert!(other_road.try_recv().is_err());
+
+        let (_id, mut rx) = state.add_dispatcher(&[1, 123]);
+        assert_eq!(rx.try_recv().unwrap().plate, "UN1X");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn removed_dispatchers_do_not_receive_tickets() {
+        let mut state = State::default();
+        let (id, _rx) = state.add_dispatcher(&[123]);
+        state.remove_dispatcher(id, []);
+
+        state.observe("UN1X", 123, 8, 60, 0);
+        state.observe("UN1X", 123, 9, 60, 45);
+
+        let (_id, mut rx) = state.add_dispatcher(&[123]);
+        assert_eq!(rx.try_recv().unwrap().speed, 8000);
+    }
+
+    #[test]
+    fn unsent_tickets_are_requeued() {
+        let mut state = State::default();
+        let (id, _rx) = state.add_dispatcher(&[123]);
+        state.observe("UN1X", 123, 8, 60, 0);
+        let tickets = state.observe("UN1X", 123, 9, 60, 45);
+
+        state.remove_dispatcher(id, tickets);
+        let (_id, mut rx) = state.add_dispatcher(&[123]);
+        assert_eq!(rx.try_recv().unwrap().plate, "UN1X");
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use crate::config::seconds;
+use crate::limiter::Limiter;
+use crate::service::{self, ListenOptions, Listener, Service};
+use crate::shutdown::Shutdown;
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_FAILURES: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceState {
+    Starting,
+    Running,
+    Failed,
+    Restarting,
+}
+
+impl Display for ServiceState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceState::Starting => f.pad("starting"),
+            ServiceState::Running => f.pad("running"),
+            ServiceState::Failed => f.pad("failed"),
+            ServiceState::Restarting => f.pad("restarting"),
+        }
+    }
+}
+
+/// When to restart a failed service. The wait between restarts doubles from
+/// `initial_backoff` up to `max_backoff`, and a service that stays up for
+/// `max_backoff` is considered recovered.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RestartPolicy {
+    #[serde(deserialize_with = "seconds")]
+    pub initial_backoff: Duration,
+    #[serde(deserialize_with = "seconds")]
+    pub max_backoff: Duration,
+    /// Failures in a row before the service is given up on.
+    pub max_failures: u32,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: INITIAL_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+            max_failures: MAX_FAILURES,
+        }
+    }
+}
+
+/// Runs services, restarting them when they fail, and records what state
+/// each of them is in.
+#[derive(Debug, Clone, Default)]
+pub struct Supervisor {
+    policy: RestartPolicy,
+    states: Arc<Mutex<BTreeMap<&'static str, ServiceState>>>,
+}
+
+impl Supervisor {
+    pub fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            states: Arc::default(),
+        }
+    }
+
+    pub fn states(&self) -> BTreeMap<&'static str, ServiceState> {
+        self.states.lock().unwrap().clone()
+    }
+
+    pub fn state(&self, name: &str) -> Option<ServiceState> {
+        self.states.lock().unwrap().get(name).copied()
+    }
+
+    fn set_state(&self, name: &'static str, state: ServiceState) {
+        info!("{} is {}", name, state);
+        self.states.lock().unwrap().insert(name, state);
+    }
+
+    /// Binds `addr` with `options` and runs `service` on it, with `limiter`
+    /// shared across restarts, until `shutdown` is triggered.
+    /// Failures, including failing to bind, are retried until the service
+    /// fails `max_failures` times in a row, which is returned as an error.
+    pub async fn supervise(
+        &self,
+        service: Box<dyn Service>,
+        addr: SocketAddr,
+        options: ListenOptions,
+        limiter: Limiter,
+        shutdown: Shutdown,
+    ) -> anyhow::Result<()> {
+        let name = service.name();
+        let mut failures = 0;
+        let mut backoff = self.policy.initial_backoff;
+
+        loop {
+            self.set_state(name, ServiceState::Starting);
+            let started = Instant::now();
+            let result = self
+                .run_once(service.as_ref(), addr, &options, &limiter, &shutdown)
+                .await;
+
+            if shutdown.is_triggered() {
+                return result;
+            }
+
+            let e = match result {
+                Ok(()) => anyhow::anyhow!("{} stopped unexpectedly", name),
+                Err(e) => e,
+            };
+
+            if started.elapsed() >= self.policy.max_backoff {
+                failures = 0;
+                backoff = self.policy.initial_backoff;
+            }
+            failures += 1;
+            self.set_state(name, ServiceState::Failed);
+            error!("{:#} ({}/{})", e, failures, self.policy.max_failures);
+
+            if failures >= self.policy.max_failures {
+                return Err(e.context(format!("{} failed {} times in a row", name, failures)));
+            }
+
+            self.set_state(name, ServiceState::Restarting);
+            warn!("Restarting {} in {:?}", name, backoff);
+            if shutdown
+                .or_cancel(tokio::time::sleep(backoff))
+                .await
+                .is_none()
+            {
+                return Ok(());
+            }
+            backoff = (backoff * 2).min(self.policy.max_backoff);
+        }
+    }
+
+    async fn run_once(
+        &self,
+        service: &dyn Service,
+        addr: SocketAddr,
+        options: &ListenOptions,
+        limiter: &Limiter,
+        shutdown: &Shutdown,
+    ) -> anyhow::Result<()> {
+        let name = service.name();
+        let listener = Listener::bind(service.transport(), addr, options.clone())
+            .await
+            .with_context(|| format!("{} failed to bind {}", name, addr))?;
+
+        self.set_state(name, ServiceState::Running);
+        // Run on its own task so a panic counts as a failure like any other
+        service::start(service, listener, limiter.clone(), shutdown.clone())
+            .await
+            .with_context(|| format!("{} panicked", name))?
+            .with_context(|| format!("{} failed", name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::service::ServiceFuture;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Fails its first `failures` runs, then serves until shutdown.
+    struct Flaky {
+        failures: u32,
+        runs: Arc<AtomicU32>,
+    }
+
+    impl Service for Flaky {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn default_port(&self) -> u16 {
+            0
+        }
+
+        fn run(&self, _listener: Listener, _limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+            let run = self.runs.fetch_add(1, Ordering::SeqCst);
+            let failures = self.failures;
+            Box::pin(async move {
+                anyhow::ensure!(run >= failures, "run {} failed", run);
+                shutdown.triggered().await;
+                Ok(())
+            })
+        }
+    }
+
+    fn flaky(failures: u32) -> (Box<dyn Service>, Arc<AtomicU32>) {
+        let runs = Arc::new(AtomicU32::new(0));
+        let service = Flaky {
+            failures,
+            runs: runs.clone(),
+        };
+        (Box::new(service), runs)
+    }
+
+    fn supervisor() -> Supervisor {
+        Supervisor::new(RestartPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_secs(1),
+            max_failures: 3,
+        })
+    }
+
+    const ADDR: &str = "127.0.0.1:0";
+
+    #[tokio::test]
+    async fn restarts_failed_services() {
+        let supervisor = supervisor();
+        let shutdown = Shutdown::new();
+        let (service, runs) = flaky(2);
+
+        let task = tokio::spawn({
+            let supervisor = supervisor.clone();
+            let shutdown = shutdown.clone();
+            async move {
+                supervisor
+                    .supervise(
+                        service,
+                        ADDR.parse()?,
+                        ListenOptions::default(),
+                        Limiter::unlimited(),
+                        shutdown,
+                    )
+                    .await
+            }
+        });
+
+        while runs.load(Ordering::SeqCst) < 3 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(supervisor.state("flaky"), Some(ServiceState::Running));
+
+        shutdown.trigger();
+        task.await.unwrap().unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_failures() {
+        let supervisor = supervisor();
+        let (service, runs) = flaky(u32::MAX);
+
+        let result = supervisor
+            .supervise(
+                service,
+                ADDR.parse().unwrap(),
+                ListenOptions::default(),
+                Limiter::unlimited(),
+                Shutdown::new(),
+            )
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(supervisor.states()["flaky"], ServiceState::Failed);
+    }
+}
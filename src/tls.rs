@@ -0,0 +1,89 @@
+use anyhow::{ensure, Context, Result};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A certificate chain and private key to terminate TLS with, both PEM files.
+/// The chain starts with the server's own certificate.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn load(&self) -> Result<Arc<ServerConfig>> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("Failed to read certificates from {}", self.cert.display()))?;
+        ensure!(
+            !certs.is_empty(),
+            "No certificates in {}",
+            self.cert.display()
+        );
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .with_context(|| format!("Failed to read a private key from {}", self.key.display()))?;
+
+        server_config(certs, key)
+    }
+}
+
+/// A rustls config serving `certs` without asking clients for certificates.
+pub fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Certificate doesn't match the private key")?;
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn loads_pem_files() {
+        let dir = std::env::temp_dir().join(format!("protohackers-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let certified = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let other = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let write = |name: &str, contents: String| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+
+        let config = TlsConfig {
+            cert: write("cert.pem", certified.cert.pem()),
+            key: write("key.pem", certified.key_pair.serialize_pem()),
+        };
+        assert!(config.load().is_ok());
+
+        let missing = TlsConfig {
+            cert: dir.join("missing.pem"),
+            ..config.clone()
+        };
+        assert!(missing.load().is_err());
+
+        let swapped = TlsConfig {
+            cert: config.key.clone(),
+            key: config.cert.clone(),
+        };
+        assert!(swapped.load().is_err());
+
+        let mismatched = TlsConfig {
+            key: write("other.pem", other.key_pair.serialize_pem()),
+            ..config
+        };
+        assert!(mismatched.load().is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
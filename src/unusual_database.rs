@@ -0,0 +1,195 @@
+use crate::limiter::{Limiter, Verdict};
+use crate::metrics::metrics;
+use crate::service::{Listener, ServerHandle, Service, ServiceFuture, Transport};
+use crate::shutdown::Shutdown;
+use std::collections::HashMap;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tracing::{error, info};
+
+const BLOCK_SIZE: usize = 1000;
+const VERSION_KEY: &str = "version";
+const VERSION: &str = "Ken's Key-Value Store 1.0";
+
+#[derive(Debug, PartialEq)]
+enum Request {
+    Insert { key: String, value: String },
+    Retrieve { key: String },
+}
+
+impl Request {
+    /// The request type, for metrics.
+    fn method(&self) -> &'static str {
+        match self {
+            Request::Insert { .. } => "insert",
+            Request::Retrieve { .. } => "retrieve",
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Self {
+        let request = String::from_utf8_lossy(bytes);
+        match request.split_once('=') {
+            Some((key, value)) => Request::Insert {
+                key: key.to_string(),
+                value: value.to_string(),
+            },
+            None => Request::Retrieve {
+                key: request.to_string(),
+            },
+        }
+    }
+}
+
+struct Database {
+    store: HashMap<String, String>,
+}
+
+impl Database {
+    fn new() -> Self {
+        let mut store = HashMap::new();
+        store.insert(VERSION_KEY.to_string(), VERSION.to_string());
+        Self { store }
+    }
+
+    /// Applies the request, returning the response to send back, if any.
+    fn handle(&mut self, request: Request) -> Option<String> {
+        match request {
+            Request::Insert { key, value } => {
+                if key != VERSION_KEY {
+                    self.store.insert(key, value);
+                }
+                None
+            }
+
+            Request::Retrieve { key } => {
+                let value = self.store.get(&key).map(String::as_str).unwrap_or("");
+                Some(format!("{}={}", key, value))
+            }
+        }
+    }
+}
+
+pub struct UnusualDatabase;
+
+impl Service for UnusualDatabase {
+    fn name(&self) -> &'static str {
+        "unusual_database"
+    }
+
+    fn default_port(&self) -> u16 {
+        10004
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Udp
+    }
+
+    fn run(&self, listener: Listener, limiter: Limiter, shutdown: Shutdown) -> ServiceFuture {
+        Box::pin(async move { run(listener.into_udp()?, limiter, shutdown).await })
+    }
+}
+
+/// Starts the server on `bind` in the background.
+pub async fn spawn(bind: impl ToSocketAddrs) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn(&UnusualDatabase, bind).await
+}
+
+/// There are no connections to cap or close, so only the datagram rate is
+/// limited and datagrams over it are dropped whatever the action.
+pub async fn run(socket: UdpSocket, limiter: Limiter, shutdown: Shutdown) -> anyhow::Result<()> {
+    info!(
+        "Running Unusual Database server on {}...",
+        socket.local_addr()?
+    );
+
+    let mut db = Database::new();
+    let bytes_received = metrics()
+        .bytes_received
+        .with_label_values(&["unusual_database"]);
+    let bytes_sent = metrics()
+        .bytes_sent
+        .with_label_values(&["unusual_database"]);
+    let mut buf = [0u8; BLOCK_SIZE];
+
+    while let Some(received) = shutdown.or_cancel(socket.recv_from(&mut buf)).await {
+        let (num_bytes, address) = received?;
+        bytes_received.inc_by(num_bytes as u64);
+        if !limiter.permits(address.ip()) || limiter.datagram(address.ip()) != Verdict::Allow {
+            continue;
+        }
+
+        let request = Request::parse(&buf[..num_bytes]);
+        info!(peer = %address, "Received {:?}", request);
+        metrics().request("unusual_database", request.method());
+
+        if let Some(response) = db.handle(request) {
+            match socket.send_to(response.as_bytes(), address).await {
+                Ok(num_bytes) => bytes_sent.inc_by(num_bytes as u64),
+                Err(e) => error!(peer = %address, "Failed to send packet: {}", e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_splits_on_first_equals() {
+        assert_eq!(
+            Request::parse(b"foo=bar=baz"),
+            Request::Insert {
+                key: "foo".to_string(),
+                value: "bar=baz".to_string()
+            }
+        );
+        assert_eq!(
+            Request::parse(b"=foo"),
+            Request::Insert {
+                key: "".to_string(),
+                value: "foo".to_string()
+            }
+        );
+        assert_eq!(
+            Request::parse(b"foo==="),
+            Request::Insert {
+                key: "foo".to_string(),
+                value: "==".to_string()
+            }
+        );
+        assert_eq!(
+            Request::parse(b"foo"),
+            Request::Retrieve {
+                key: "foo".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn insert_then_retrieve() {
+        let mut db = Database::new();
+        assert_eq!(db.handle(Request::parse(b"foo=bar")), None);
+        assert_eq!(
+            db.handle(Request::parse(b"foo")),
+            Some("foo=bar".to_string())
+        );
+        assert_eq!(db.handle(Request::parse(b"foo=")), None);
+        assert_eq!(db.handle(Request::parse(b"foo")), Some("foo=".to_string()));
+        assert_eq!(
+            db.handle(Request::parse(b"missing")),
+            Some("missing=".to_string())
+        );
+    }
+
+    #[test]
+    fn version_is_read_only() {
+        let mut db = Database::new();
+        assert_eq!(db.handle(Request::parse(b"version=hacked")), None);
+        assert_eq!(
+            db.handle(Request::parse(b"version")),
+            Some("version=Ken's Key-Value Store 1.0".to_string())
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use common::{connect, expect, expect_closed, expect_nothing, LOCALHOST};
+use protohackers_rs::budget_chat;
+use protohackers_rs::service::ServerHandle;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+mod common;
+
+const WELCOME: &[u8] = b"Welcome to budgetchat! What shall I call you?\n";
+
+async fn join(server: &ServerHandle, name: &str, present: &str) -> TcpStream {
+    let mut client = connect(server).await;
+    expect(&mut client, WELCOME).await;
+    client
+        .write_all(format!("{}\n", name).as_bytes())
+        .await
+        .unwrap();
+    expect(
+        &mut client,
+        format!("* The room contains: {}\n", present).as_bytes(),
+    )
+    .await;
+    client
+}
+
+#[tokio::test]
+async fn example_session() {
+    let server = budget_chat::spawn(LOCALHOST).await.unwrap();
+
+    let mut bob = join(&server, "bob", "").await;
+    let mut charlie = join(&server, "charlie", "bob").await;
+    expect(&mut bob, b"* charlie has entered the room\n").await;
+
+    let mut dave = join(&server, "dave", "bob, charlie").await;
+    expect(&mut bob, b"* dave has entered the room\n").await;
+    expect(&mut charlie, b"* dave has entered the room\n").await;
+
+    bob.write_all(b"hi alice\n").await.unwrap();
+    expect(&mut charlie, b"[bob] hi alice\n").await;
+    expect(&mut dave, b"[bob] hi alice\n").await;
+    // Nobody hears their own messages
+    expect_nothing(&mut bob).await;
+
+    drop(charlie);
+    expect(&mut bob, b"* charlie has left the room\n").await;
+    expect(&mut dave, b"* charlie has left the room\n").await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn illegal_names_are_disconnected() {
+    let server = budget_chat::spawn(LOCALHOST).await.unwrap();
+    let mut bob = join(&server, "bob", "").await;
+
+    for name in ["", "bob smith", "bob!"] {
+        let mut client = connect(&server).await;
+        expect(&mut client, WELCOME).await;
+        client
+            .write_all(format!("{}\n", name).as_bytes())
+            .await
+            .unwrap();
+        expect(&mut client, b"* Illegal name, goodbye\n").await;
+        expect_closed(&mut client).await;
+    }
+
+    // Clients that never joined are not announced
+    let mut lurker = connect(&server).await;
+    expect(&mut lurker, WELCOME).await;
+    drop(lurker);
+    expect_nothing(&mut bob).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn long_lines_are_relayed_and_longer_ones_disconnected() {
+    let server = budget_chat::spawn(LOCALHOST).await.unwrap();
+    let mut bob = join(&server, "bob", "").await;
+    let mut alice = join(&server, "alice", "bob").await;
+    expect(&mut bob, b"* alice has entered the room\n").await;
+
+    let message = "a".repeat(1000);
+    alice
+        .write_all(format!("{}\n", message).as_bytes())
+        .await
+        .unwrap();
+    expect(&mut bob, format!("[alice] {}\n", message).as_bytes()).await;
+
+    // Closed before the newline ever arrives
+    alice.write_all(&[b'a'; 2000]).await.unwrap();
+    expect_closed(&mut alice).await;
+    expect(&mut bob, b"* alice has left the room\n").await;
+
+    let mut client = connect(&server).await;
+    expect(&mut client, WELCOME).await;
+    client.write_all(&[b'a'; 2000]).await.unwrap();
+    expect_closed(&mut client).await;
+    expect_nothing(&mut bob).await;
+
+    server.shutdown().await.unwrap();
+}
@@ -0,0 +1,88 @@
+use common::{connect, expect, expect_closed, LOCALHOST};
+use protohackers_rs::code_storage::{self, CodeStorage};
+use protohackers_rs::service::{ServerHandle, Service};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+mod common;
+
+async fn send(client: &mut TcpStream, request: &[u8], response: &[u8]) {
+    client.write_all(request).await.unwrap();
+    expect(client, response).await;
+    expect(client, b"READY\n").await;
+}
+
+#[tokio::test]
+async fn put_get_and_list() {
+    let server = code_storage::spawn(LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+    expect(&mut client, b"READY\n").await;
+
+    send(&mut client, b"HELP\n", b"OK usage: HELP|GET|PUT|LIST\n").await;
+    send(&mut client, b"PUT /dir/a.txt 6\nhello\n", b"OK r1\n").await;
+    // Identical contents don't make a new revision
+    send(&mut client, b"PUT /dir/a.txt 6\nhello\n", b"OK r1\n").await;
+    send(&mut client, b"PUT /dir/a.txt 4\nbye\n", b"OK r2\n").await;
+    send(&mut client, b"PUT /b.txt 0\n", b"OK r1\n").await;
+
+    send(&mut client, b"GET /dir/a.txt\n", b"OK 4\nbye\n").await;
+    send(&mut client, b"GET /dir/a.txt r1\n", b"OK 6\nhello\n").await;
+    send(&mut client, b"GET /b.txt\n", b"OK 0\n").await;
+    send(&mut client, b"LIST /\n", b"OK 2\nb.txt r1\ndir/ DIR\n").await;
+    send(&mut client, b"LIST /dir\n", b"OK 1\na.txt r2\n").await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn errors() {
+    let server = code_storage::spawn(LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+    expect(&mut client, b"READY\n").await;
+
+    send(&mut client, b"GET /missing\n", b"ERR no such file\n").await;
+    send(&mut client, b"PUT /a 1\nx", b"OK r1\n").await;
+    send(&mut client, b"GET /a r2\n", b"ERR no such revision\n").await;
+    send(&mut client, b"PUT /a 1\n\x00", b"ERR text files only\n").await;
+    send(&mut client, b"PUT a 1\n", b"ERR illegal file name\n").await;
+    send(
+        &mut client,
+        b"PUT /a\n",
+        b"ERR usage: PUT file length newline data\n",
+    )
+    .await;
+    send(&mut client, b"GET\n", b"ERR usage: GET file [revision]\n").await;
+    send(&mut client, b"LIST\n", b"ERR usage: LIST dir\n").await;
+
+    // Unknown methods end the session
+    client.write_all(b"DELETE /a\n").await.unwrap();
+    expect(&mut client, b"ERR illegal method: DELETE\n").await;
+    expect_closed(&mut client).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[test]
+fn rejects_a_zero_max_file_size() {
+    let options: toml::Table = toml::from_str("max_file_size = 0").unwrap();
+    let error = CodeStorage::default().configure(options).unwrap_err();
+    assert_eq!(error.to_string(), "max_file_size must be above zero");
+}
+
+#[tokio::test]
+async fn refuses_files_over_the_maximum_size() {
+    let service = CodeStorage { max_file_size: 8 };
+    let server = ServerHandle::spawn(&service, LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+    expect(&mut client, b"READY\n").await;
+
+    send(&mut client, b"PUT /a 8\n12345678", b"OK r1\n").await;
+    client
+        .write_all(b"PUT /a 18446744073709551615\n")
+        .await
+        .unwrap();
+    expect(&mut client, b"ERR file too large\n").await;
+    expect_closed(&mut client).await;
+
+    server.shutdown().await.unwrap();
+}
@@ -0,0 +1,122 @@
+// Each test binary only uses some of these
+#![allow(dead_code)]
+
+use protohackers_rs::service::{ListenOptions, ServerHandle};
+use protohackers_rs::tls;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// How long to wait for anything the server should send.
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where every server under test is bound.
+pub const LOCALHOST: &str = "127.0.0.1:0";
+
+pub async fn connect(server: &ServerHandle) -> TcpStream {
+    TcpStream::connect(server.local_addr()).await.unwrap()
+}
+
+/// A fresh path for a Unix socket, unique to the test binary and `name`.
+pub fn socket_path(name: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("protohackers-{}-{}.sock", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Options serving TLS with a fresh self-signed certificate for `localhost`,
+/// and a connector trusting it.
+pub fn self_signed() -> (ListenOptions, TlsConnector) {
+    let certified = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+    let cert = certified.cert.der().clone();
+    let key = certified.key_pair.serialize_der().try_into().unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.clone()).unwrap();
+    let client = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let options = ListenOptions {
+        tls: Some(tls::server_config(vec![cert], key).unwrap()),
+        ..ListenOptions::default()
+    };
+    (options, TlsConnector::from(Arc::new(client)))
+}
+
+pub async fn connect_tls(server: &ServerHandle, connector: &TlsConnector) -> TlsStream<TcpStream> {
+    let stream = connect(server).await;
+    let localhost = ServerName::try_from("localhost").unwrap();
+    connector.connect(localhost, stream).await.unwrap()
+}
+
+/// Reads exactly as many bytes as `expected` and checks they match.
+pub async fn expect<R: AsyncRead + Unpin>(reader: &mut R, expected: &[u8]) {
+    let mut buf = vec![0u8; expected.len()];
+    tokio::time::timeout(TIMEOUT, reader.read_exact(&mut buf))
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for {:?}", expected.escape_ascii()))
+        .unwrap();
+
+    assert_eq!(
+        buf.escape_ascii().to_string(),
+        expected.escape_ascii().to_string()
+    );
+}
+
+/// Checks the server closes the connection without sending anything else.
+pub async fn expect_closed<R: AsyncRead + Unpin>(reader: &mut R) {
+    let mut rest = Vec::new();
+    let result = tokio::time::timeout(TIMEOUT, reader.read_to_end(&mut rest))
+        .await
+        .expect("timed out waiting for the connection to close");
+
+    // A reset counts as closed too
+    if result.is_ok() {
+        assert_eq!(rest.escape_ascii().to_string(), "");
+    }
+}
+
+/// Checks nothing arrives for a little while.
+pub async fn expect_nothing<R: AsyncRead + Unpin>(reader: &mut R) {
+    let mut buf = [0u8; 1];
+    let read = tokio::time::timeout(Duration::from_millis(100), reader.read(&mut buf)).await;
+    assert!(read.is_err(), "unexpected {:?}", read);
+}
+
+/// A UDP socket connected to the server.
+pub async fn udp_client(server: &ServerHandle) -> UdpSocket {
+    let socket = UdpSocket::bind(LOCALHOST).await.unwrap();
+    socket.connect(server.local_addr()).await.unwrap();
+    socket
+}
+
+pub async fn recv(socket: &UdpSocket) -> String {
+    let mut buf = [0u8; 1024];
+    let len = tokio::time::timeout(TIMEOUT, socket.recv(&mut buf))
+        .await
+        .expect("timed out waiting for a packet")
+        .unwrap();
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+pub async fn exchange(socket: &UdpSocket, packet: &str) -> String {
+    socket.send(packet.as_bytes()).await.unwrap();
+    recv(socket).await
+}
+
+pub async fn expect_no_packet(socket: &UdpSocket, wait: Duration) {
+    let mut buf = [0u8; 1024];
+    let received = tokio::time::timeout(wait, socket.recv(&mut buf)).await;
+    assert!(
+        received.is_err(),
+        "unexpected packet {:?}",
+        received.map(|r| r.map(|len| String::from_utf8_lossy(&buf[..len]).to_string()))
+    );
+}
@@ -0,0 +1,141 @@
+use common::{connect, connect_tls, expect, expect_closed, self_signed, LOCALHOST};
+use protohackers_rs::access::AccessList;
+use protohackers_rs::insecure_sockets::{self, CipherStream, InsecureSockets};
+use protohackers_rs::limiter::{Limiter, Limits};
+use protohackers_rs::service::{ListenOptions, ServerHandle};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+mod common;
+
+#[tokio::test]
+async fn example_session() {
+    let server = insecure_sockets::spawn(LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+
+    // xor(123), addpos, reversebits
+    client
+        .write_all(&[0x02, 0x7b, 0x05, 0x01, 0x00])
+        .await
+        .unwrap();
+
+    // "4x dog,5x car\n" -> "5x car\n"
+    client
+        .write_all(&[
+            0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e,
+        ])
+        .await
+        .unwrap();
+    expect(&mut client, &[0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee]).await;
+
+    // "3x rat,2x cat\n" -> "3x rat\n"
+    client
+        .write_all(&[
+            0x6a, 0x48, 0xd6, 0x58, 0x34, 0x44, 0xd6, 0x7a, 0x98, 0x4e, 0x0c, 0xcc, 0x94, 0x31,
+        ])
+        .await
+        .unwrap();
+    expect(&mut client, &[0xf2, 0xd0, 0x26, 0xc8, 0xa4, 0xd8, 0x7e]).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn cipher_applies_across_lines() {
+    let server = insecure_sockets::spawn(LOCALHOST).await.unwrap();
+    let stream = connect(&server).await;
+
+    // addpos, xorpos, add(200) so the stream position matters
+    let cipher = CipherStream::connect(stream, &[0x05, 0x03, 0x04, 0xc8, 0x00])
+        .await
+        .unwrap();
+    let mut client = BufReader::new(cipher);
+
+    for (request, response) in [
+        (
+            "10x toy car,15x dog on a string,4x inflatable motorcycle\n",
+            "15x dog on a string\n",
+        ),
+        ("1x a,2x b\n", "2x b\n"),
+    ] {
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.flush().await.unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        assert_eq!(line, response);
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn noop_ciphers_are_disconnected() {
+    let server = insecure_sockets::spawn(LOCALHOST).await.unwrap();
+
+    let specs: [&[u8]; 5] = [
+        // Empty
+        &[0x00],
+        // xor(0)
+        &[0x02, 0x00, 0x00],
+        // xor(0xa0), xor(0xa0)
+        &[0x02, 0xa0, 0x02, 0xa0, 0x00],
+        // reversebits, reversebits
+        &[0x01, 0x01, 0x00],
+        // add(0x80), add(0x80)
+        &[0x04, 0x80, 0x04, 0x80, 0x00],
+    ];
+
+    for spec in specs {
+        let mut client = connect(&server).await;
+        client.write_all(spec).await.unwrap();
+        client.write_all(b"1x a\n").await.unwrap();
+        expect_closed(&mut client).await;
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn closes_connections_outside_the_allow_list() {
+    let access = AccessList {
+        allow: vec!["10.20.0.0/16".parse().unwrap()],
+        deny: Vec::new(),
+    };
+    let limiter = Limiter::new("insecure_sockets", Limits::default(), access);
+    let server = ServerHandle::spawn_with(
+        &InsecureSockets,
+        LOCALHOST,
+        ListenOptions::default(),
+        limiter,
+    )
+    .await
+    .unwrap();
+
+    let mut client = connect(&server).await;
+    expect_closed(&mut client).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn ciphers_inside_tls() {
+    let (options, connector) = self_signed();
+    let server =
+        ServerHandle::spawn_with(&InsecureSockets, LOCALHOST, options, Limiter::unlimited())
+            .await
+            .unwrap();
+
+    let stream = connect_tls(&server, &connector).await;
+    // xor(123), addpos, reversebits
+    let cipher = CipherStream::connect(stream, &[0x02, 0x7b, 0x05, 0x01, 0x00])
+        .await
+        .unwrap();
+    let mut client = BufReader::new(cipher);
+
+    client.write_all(b"4x dog,5x car\n").await.unwrap();
+    client.flush().await.unwrap();
+    let mut line = String::new();
+    client.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "5x car\n");
+
+    server.shutdown().await.unwrap();
+}
@@ -0,0 +1,163 @@
+use common::{connect, expect, expect_nothing, LOCALHOST};
+use protohackers_rs::job_centre;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+mod common;
+
+async fn request(client: &mut BufReader<TcpStream>, request: &str, response: &str) {
+    client
+        .write_all(format!("{}\n", request).as_bytes())
+        .await
+        .unwrap();
+    expect(client, format!("{}\n", response).as_bytes()).await;
+}
+
+#[tokio::test]
+async fn example_session() {
+    let server = job_centre::spawn(LOCALHOST).await.unwrap();
+    let mut client = BufReader::new(connect(&server).await);
+
+    request(
+        &mut client,
+        r#"{"request":"put","queue":"queue1","job":{"title":"example-job"},"pri":123}"#,
+        r#"{"status":"ok","id":0}"#,
+    )
+    .await;
+    request(
+        &mut client,
+        r#"{"request":"get","queues":["queue1"]}"#,
+        r#"{"status":"ok","id":0,"job":{"title":"example-job"},"pri":123,"queue":"queue1"}"#,
+    )
+    .await;
+    request(
+        &mut client,
+        r#"{"request":"abort","id":0}"#,
+        r#"{"status":"ok"}"#,
+    )
+    .await;
+    request(
+        &mut client,
+        r#"{"request":"get","queues":["queue1"]}"#,
+        r#"{"status":"ok","id":0,"job":{"title":"example-job"},"pri":123,"queue":"queue1"}"#,
+    )
+    .await;
+    request(
+        &mut client,
+        r#"{"request":"delete","id":0}"#,
+        r#"{"status":"ok"}"#,
+    )
+    .await;
+    request(
+        &mut client,
+        r#"{"request":"get","queues":["queue1"]}"#,
+        r#"{"status":"no-job"}"#,
+    )
+    .await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn highest_priority_first() {
+    let server = job_centre::spawn(LOCALHOST).await.unwrap();
+    let mut client = BufReader::new(connect(&server).await);
+
+    for (queue, pri, id) in [("a", 1, 0), ("b", 3, 1), ("a", 2, 2)] {
+        request(
+            &mut client,
+            &format!(
+                r#"{{"request":"put","queue":"{}","job":{{}},"pri":{}}}"#,
+                queue, pri
+            ),
+            &format!(r#"{{"status":"ok","id":{}}}"#, id),
+        )
+        .await;
+    }
+
+    for (queue, pri, id) in [("b", 3, 1), ("a", 2, 2), ("a", 1, 0)] {
+        request(
+            &mut client,
+            r#"{"request":"get","queues":["a","b"]}"#,
+            &format!(
+                r#"{{"status":"ok","id":{},"job":{{}},"pri":{},"queue":"{}"}}"#,
+                id, pri, queue
+            ),
+        )
+        .await;
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn disconnecting_aborts_jobs_and_wakes_waiters() {
+    let server = job_centre::spawn(LOCALHOST).await.unwrap();
+    let mut worker = BufReader::new(connect(&server).await);
+    let mut waiter = BufReader::new(connect(&server).await);
+
+    request(
+        &mut worker,
+        r#"{"request":"put","queue":"q","job":{"n":1},"pri":1}"#,
+        r#"{"status":"ok","id":0}"#,
+    )
+    .await;
+    request(
+        &mut worker,
+        r#"{"request":"get","queues":["q"]}"#,
+        r#"{"status":"ok","id":0,"job":{"n":1},"pri":1,"queue":"q"}"#,
+    )
+    .await;
+
+    waiter
+        .write_all(b"{\"request\":\"get\",\"queues\":[\"q\"],\"wait\":true}\n")
+        .await
+        .unwrap();
+    expect_nothing(&mut waiter).await;
+
+    // The worker's job goes back on the queue for the waiting client
+    drop(worker);
+    expect(
+        &mut waiter,
+        b"{\"status\":\"ok\",\"id\":0,\"job\":{\"n\":1},\"pri\":1,\"queue\":\"q\"}\n",
+    )
+    .await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn invalid_requests_get_errors() {
+    let server = job_centre::spawn(LOCALHOST).await.unwrap();
+    let mut client = BufReader::new(connect(&server).await);
+
+    for invalid in [
+        "not json",
+        r#"{"request":"put","queue":"q","pri":1}"#,
+        r#"{"request":"get"}"#,
+        r#"{"request":"explode"}"#,
+    ] {
+        client
+            .write_all(format!("{}\n", invalid).as_bytes())
+            .await
+            .unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        assert!(
+            line.starts_with(r#"{"status":"error","error":"#),
+            "{:?} got {:?}",
+            invalid,
+            line
+        );
+    }
+
+    // The connection survives errors
+    request(
+        &mut client,
+        r#"{"request":"delete","id":12345}"#,
+        r#"{"status":"no-job"}"#,
+    )
+    .await;
+
+    server.shutdown().await.unwrap();
+}
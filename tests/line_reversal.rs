@@ -0,0 +1,282 @@
+use common::{exchange, expect_no_packet, recv, socket_path, udp_client, LOCALHOST, TIMEOUT};
+use protohackers_rs::access::AccessList;
+use protohackers_rs::limiter::{Limiter, Limits};
+use protohackers_rs::line_reversal::{self, lrcp::LrcpConfig, LineReversal};
+use protohackers_rs::service::{ListenOptions, ServerHandle};
+use std::time::Duration;
+use tokio::net::UnixDatagram;
+
+mod common;
+
+const RETRANSMISSION_TIMEOUT: Duration = Duration::from_millis(200);
+
+async fn spawn() -> ServerHandle {
+    let service = LineReversal {
+        config: LrcpConfig {
+            retransmission_timeout: RETRANSMISSION_TIMEOUT,
+            ..LrcpConfig::default()
+        },
+    };
+    ServerHandle::spawn(&service, LOCALHOST).await.unwrap()
+}
+
+#[tokio::test]
+async fn example_session() {
+    let server = line_reversal::spawn(LOCALHOST).await.unwrap();
+    let client = udp_client(&server).await;
+
+    assert_eq!(exchange(&client, "/connect/12345/").await, "/ack/12345/0/");
+    assert_eq!(
+        exchange(&client, "/data/12345/0/hello\n/").await,
+        "/ack/12345/6/"
+    );
+    assert_eq!(recv(&client).await, "/data/12345/0/olleh\n/");
+    client.send(b"/ack/12345/6/").await.unwrap();
+
+    assert_eq!(
+        exchange(&client, "/data/12345/6/Hello, world!\n/").await,
+        "/ack/12345/20/"
+    );
+    assert_eq!(recv(&client).await, "/data/12345/6/!dlrow ,olleH\n/");
+    client.send(b"/ack/12345/20/").await.unwrap();
+
+    assert_eq!(exchange(&client, "/close/12345/").await, "/close/12345/");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn lines_span_packets_and_slashes_are_escaped() {
+    let server = line_reversal::spawn(LOCALHOST).await.unwrap();
+    let client = udp_client(&server).await;
+
+    assert_eq!(exchange(&client, "/connect/1/").await, "/ack/1/0/");
+    assert_eq!(exchange(&client, "/data/1/0/foo\\/ba/").await, "/ack/1/6/");
+    assert_eq!(
+        exchange(&client, "/data/1/6/r\\\\baz\nnext/").await,
+        "/ack/1/16/"
+    );
+    assert_eq!(recv(&client).await, "/data/1/0/zab\\\\rab\\/oof\n/");
+    client.send(b"/ack/1/12/").await.unwrap();
+
+    // Duplicate connects are acked without starting over
+    assert_eq!(exchange(&client, "/connect/1/").await, "/ack/1/0/");
+    assert_eq!(exchange(&client, "/data/1/16/\n/").await, "/ack/1/17/");
+    assert_eq!(recv(&client).await, "/data/1/12/txen\n/");
+    // Shutting down waits for everything sent to be acked
+    client.send(b"/ack/1/17/").await.unwrap();
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn lost_packets() {
+    let server = spawn().await;
+    let client = udp_client(&server).await;
+
+    assert_eq!(exchange(&client, "/connect/7/").await, "/ack/7/0/");
+
+    // Data after a gap is dropped, and the ack says what was received
+    assert_eq!(exchange(&client, "/data/7/3/def\n/").await, "/ack/7/0/");
+    assert_eq!(exchange(&client, "/data/7/0/abc/").await, "/ack/7/3/");
+    // Repeats are acked but only delivered once
+    assert_eq!(exchange(&client, "/data/7/0/abc/").await, "/ack/7/3/");
+    // Overlapping data is delivered from where the server is up to
+    assert_eq!(exchange(&client, "/data/7/1/bcdef\n/").await, "/ack/7/7/");
+    assert_eq!(recv(&client).await, "/data/7/0/fedcba\n/");
+    client.send(b"/ack/7/7/").await.unwrap();
+    expect_no_packet(&client, RETRANSMISSION_TIMEOUT * 2).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn unacked_data_is_retransmitted() {
+    let server = spawn().await;
+    let client = udp_client(&server).await;
+
+    assert_eq!(exchange(&client, "/connect/8/").await, "/ack/8/0/");
+    assert_eq!(exchange(&client, "/data/8/0/abc\n/").await, "/ack/8/4/");
+    assert_eq!(recv(&client).await, "/data/8/0/cba\n/");
+
+    // The reply is sent again until it is acked
+    assert_eq!(recv(&client).await, "/data/8/0/cba\n/");
+    assert_eq!(recv(&client).await, "/data/8/0/cba\n/");
+
+    // A partial ack gets the rest retransmitted straight away
+    assert_eq!(exchange(&client, "/ack/8/2/").await, "/data/8/2/a\n/");
+    client.send(b"/ack/8/4/").await.unwrap();
+    expect_no_packet(&client, RETRANSMISSION_TIMEOUT * 2).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn misbehaving_peers_are_closed() {
+    let server = line_reversal::spawn(LOCALHOST).await.unwrap();
+    let client = udp_client(&server).await;
+
+    // Sessions that were never opened
+    assert_eq!(exchange(&client, "/data/9/0/abc/").await, "/close/9/");
+    assert_eq!(exchange(&client, "/ack/9/0/").await, "/close/9/");
+
+    // Acking data that was never sent
+    assert_eq!(exchange(&client, "/connect/9/").await, "/ack/9/0/");
+    assert_eq!(exchange(&client, "/ack/9/100/").await, "/close/9/");
+
+    // Invalid packets are ignored
+    for packet in [
+        "",
+        "/connect/",
+        "/connect/9",
+        "/data/9/0/a/b/",
+        "/connect/2147483648/",
+    ] {
+        client.send(packet.as_bytes()).await.unwrap();
+    }
+    expect_no_packet(&client, Duration::from_millis(100)).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn ignores_denied_peers() {
+    let access = AccessList {
+        allow: Vec::new(),
+        deny: vec!["127.0.0.0/8".parse().unwrap()],
+    };
+    let limiter = Limiter::new("line_reversal", Limits::default(), access);
+    let server = ServerHandle::spawn_with(
+        &LineReversal::default(),
+        LOCALHOST,
+        ListenOptions::default(),
+        limiter,
+    )
+    .await
+    .unwrap();
+    let client = udp_client(&server).await;
+
+    // Not even a close for a session it doesn't know
+    client.send(b"/connect/1/").await.unwrap();
+    client.send(b"/data/2/0/hello\n/").await.unwrap();
+    expect_no_packet(&client, RETRANSMISSION_TIMEOUT).await;
+
+    server.shutdown().await.unwrap();
+}
+
+async fn unix_recv(client: &UnixDatagram) -> String {
+    let mut buf = [0u8; 1024];
+    let len = tokio::time::timeout(TIMEOUT, client.recv(&mut buf))
+        .await
+        .expect("timed out waiting for a packet")
+        .unwrap();
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+async fn unix_exchange(client: &UnixDatagram, packet: &str) -> String {
+    client.send(packet.as_bytes()).await.unwrap();
+    unix_recv(client).await
+}
+
+#[tokio::test]
+async fn sessions_over_a_unix_datagram_socket() {
+    let path = socket_path("lrcp");
+    let options = ListenOptions {
+        unix_socket: Some(path.clone()),
+        ..ListenOptions::default()
+    };
+    let server = ServerHandle::spawn_with(
+        &LineReversal::default(),
+        LOCALHOST,
+        options,
+        Limiter::unlimited(),
+    )
+    .await
+    .unwrap();
+
+    // Answers need somewhere to go, so the client binds a path too
+    let client_path = socket_path("lrcp-client");
+    let client = UnixDatagram::bind(&client_path).unwrap();
+    client.connect(&path).unwrap();
+    assert_eq!(unix_exchange(&client, "/connect/7/").await, "/ack/7/0/");
+    assert_eq!(
+        unix_exchange(&client, "/data/7/0/hello\n/").await,
+        "/ack/7/6/"
+    );
+    assert_eq!(unix_recv(&client).await, "/data/7/0/olleh\n/");
+    client.send(b"/ack/7/6/").await.unwrap();
+    assert_eq!(unix_exchange(&client, "/close/7/").await, "/close/7/");
+
+    // UDP is still served alongside
+    let udp = udp_client(&server).await;
+    assert_eq!(exchange(&udp, "/connect/8/").await, "/ack/8/0/");
+
+    server.shutdown().await.unwrap();
+    for path in [path, client_path] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn unix_peers_skip_the_access_list() {
+    let path = socket_path("lrcp-denied");
+    let options = ListenOptions {
+        unix_socket: Some(path.clone()),
+        ..ListenOptions::default()
+    };
+    let access = AccessList {
+        allow: Vec::new(),
+        deny: vec!["127.0.0.0/8".parse().unwrap()],
+    };
+    let limiter = Limiter::new("line_reversal", Limits::default(), access);
+    let server = ServerHandle::spawn_with(&LineReversal::default(), LOCALHOST, options, limiter)
+        .await
+        .unwrap();
+
+    let client_path = socket_path("lrcp-denied-client");
+    let client = UnixDatagram::bind(&client_path).unwrap();
+    client.connect(&path).unwrap();
+    assert_eq!(unix_exchange(&client, "/connect/7/").await, "/ack/7/0/");
+
+    server.shutdown().await.unwrap();
+    for path in [path, client_path] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn leaves_a_unix_datagram_socket_still_in_use() {
+    let path = socket_path("lrcp-in-use");
+    let options = ListenOptions {
+        unix_socket: Some(path.clone()),
+        ..ListenOptions::default()
+    };
+    let server = spawn_on_unix(options.clone()).await.unwrap();
+    assert!(spawn_on_unix(options.clone()).await.is_err());
+
+    let client_path = socket_path("lrcp-in-use-client");
+    let client = UnixDatagram::bind(&client_path).unwrap();
+    client.connect(&path).unwrap();
+    assert_eq!(unix_exchange(&client, "/connect/7/").await, "/ack/7/0/");
+    server.shutdown().await.unwrap();
+
+    // Once nothing answers on it, the socket is stale and gets replaced
+    let server = spawn_on_unix(options).await.unwrap();
+    client.connect(&path).unwrap();
+    assert_eq!(unix_exchange(&client, "/connect/8/").await, "/ack/8/0/");
+
+    server.shutdown().await.unwrap();
+    for path in [path, client_path] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+async fn spawn_on_unix(options: ListenOptions) -> anyhow::Result<ServerHandle> {
+    ServerHandle::spawn_with(
+        &LineReversal::default(),
+        LOCALHOST,
+        options,
+        Limiter::unlimited(),
+    )
+    .await
+}
@@ -0,0 +1,110 @@
+use common::{connect, expect, LOCALHOST};
+use protohackers_rs::means_to_an_end;
+use tokio::io::AsyncWriteExt;
+
+mod common;
+
+fn insert(timestamp: i32, price: i32) -> Vec<u8> {
+    message(b'I', timestamp, price)
+}
+
+fn query(from: i32, to: i32) -> Vec<u8> {
+    message(b'Q', from, to)
+}
+
+fn message(kind: u8, first: i32, second: i32) -> Vec<u8> {
+    let mut bytes = vec![kind];
+    bytes.extend(first.to_be_bytes());
+    bytes.extend(second.to_be_bytes());
+    bytes
+}
+
+#[tokio::test]
+async fn example_session() {
+    let server = means_to_an_end::spawn(LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+
+    let requests = [
+        insert(12345, 101),
+        insert(12346, 102),
+        insert(12347, 100),
+        insert(40960, 5),
+        query(12288, 16384),
+    ]
+    .concat();
+    client.write_all(&requests).await.unwrap();
+    expect(&mut client, &[0x00, 0x00, 0x00, 0x65]).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn out_of_order_inserts() {
+    let server = means_to_an_end::spawn(LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+
+    for (timestamp, price) in [(300, 30), (100, 10), (-50, 1000), (200, 20)] {
+        client.write_all(&insert(timestamp, price)).await.unwrap();
+    }
+
+    let cases = [
+        (query(100, 300), 20),
+        (query(100, 100), 10),
+        (query(150, 250), 20),
+        (query(-100, 0), 1000),
+        (query(i32::MIN, i32::MAX), 265),
+        // Empty and backwards ranges have no mean
+        (query(400, 500), 0),
+        (query(300, 100), 0),
+    ];
+    for (request, mean) in cases {
+        client.write_all(&request).await.unwrap();
+        expect(&mut client, &i32::to_be_bytes(mean)).await;
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn large_prices_do_not_overflow() {
+    let server = means_to_an_end::spawn(LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+
+    client.write_all(&insert(1, i32::MAX)).await.unwrap();
+    client.write_all(&insert(2, i32::MAX)).await.unwrap();
+    client.write_all(&query(0, 10)).await.unwrap();
+    expect(&mut client, &i32::MAX.to_be_bytes()).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn messages_split_across_writes() {
+    let server = means_to_an_end::spawn(LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+
+    let requests = [insert(1, 10), insert(2, 20), query(1, 2)].concat();
+    for byte in requests {
+        client.write_all(&[byte]).await.unwrap();
+        client.flush().await.unwrap();
+    }
+    expect(&mut client, &15i32.to_be_bytes()).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn sessions_have_their_own_prices() {
+    let server = means_to_an_end::spawn(LOCALHOST).await.unwrap();
+    let mut first = connect(&server).await;
+    let mut second = connect(&server).await;
+
+    first.write_all(&insert(1, 100)).await.unwrap();
+    first.write_all(&query(0, 10)).await.unwrap();
+    expect(&mut first, &100i32.to_be_bytes()).await;
+
+    second.write_all(&query(0, 10)).await.unwrap();
+    expect(&mut second, &0i32.to_be_bytes()).await;
+
+    server.shutdown().await.unwrap();
+}
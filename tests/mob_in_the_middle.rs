@@ -0,0 +1,83 @@
+use common::{connect, expect, expect_closed, LOCALHOST};
+use protohackers_rs::budget_chat;
+use protohackers_rs::mob_in_the_middle::MobInTheMiddle;
+use protohackers_rs::service::ServerHandle;
+use tokio::io::AsyncWriteExt;
+
+mod common;
+
+const TONY: &str = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
+
+#[tokio::test]
+async fn rewrites_boguscoin_addresses_both_ways() {
+    let chat = budget_chat::spawn(LOCALHOST).await.unwrap();
+    let mob = MobInTheMiddle {
+        upstream: chat.local_addr().to_string(),
+    };
+    let proxy = ServerHandle::spawn(&mob, LOCALHOST).await.unwrap();
+
+    let mut bob = connect(&chat).await;
+    expect(&mut bob, b"Welcome to budgetchat! What shall I call you?\n").await;
+    bob.write_all(b"bob\n").await.unwrap();
+    expect(&mut bob, b"* The room contains: \n").await;
+
+    let mut alice = connect(&proxy).await;
+    expect(
+        &mut alice,
+        b"Welcome to budgetchat! What shall I call you?\n",
+    )
+    .await;
+    alice.write_all(b"alice\n").await.unwrap();
+    expect(&mut alice, b"* The room contains: bob\n").await;
+    expect(&mut bob, b"* alice has entered the room\n").await;
+
+    bob.write_all(b"Send to 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX please\n")
+        .await
+        .unwrap();
+    expect(
+        &mut alice,
+        format!("[bob] Send to {} please\n", TONY).as_bytes(),
+    )
+    .await;
+
+    alice
+        .write_all(b"7F1u3wSD5RbOHQmupo9nx4TnhQ or 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX-1234\n")
+        .await
+        .unwrap();
+    expect(
+        &mut bob,
+        format!("[alice] {} or 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX-1234\n", TONY).as_bytes(),
+    )
+    .await;
+
+    // A partial line is dropped when the client hangs up
+    alice.write_all(b"no newline").await.unwrap();
+    drop(alice);
+    expect(&mut bob, b"* alice has left the room\n").await;
+
+    proxy.shutdown().await.unwrap();
+    drop(bob);
+    chat.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn upstream_disconnect_closes_the_client() {
+    let chat = budget_chat::spawn(LOCALHOST).await.unwrap();
+    let mob = MobInTheMiddle {
+        upstream: chat.local_addr().to_string(),
+    };
+    let proxy = ServerHandle::spawn(&mob, LOCALHOST).await.unwrap();
+
+    let mut client = connect(&proxy).await;
+    expect(
+        &mut client,
+        b"Welcome to budgetchat! What shall I call you?\n",
+    )
+    .await;
+    client.write_all(b"not allowed\n").await.unwrap();
+    expect(&mut client, b"* Illegal name, goodbye\n").await;
+    expect_closed(&mut client).await;
+
+    proxy.shutdown().await.unwrap();
+    chat.shutdown().await.unwrap();
+}
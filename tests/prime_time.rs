@@ -0,0 +1,93 @@
+use common::{connect, expect, expect_closed, LOCALHOST};
+use protohackers_rs::prime_time;
+use tokio::io::AsyncWriteExt;
+
+mod common;
+
+const TRUE: &[u8] = b"{\"method\":\"isPrime\",\"prime\":true}\n";
+const FALSE: &[u8] = b"{\"method\":\"isPrime\",\"prime\":false}\n";
+
+#[tokio::test]
+async fn conforming_requests() {
+    let server = prime_time::spawn(LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+
+    let cases: &[(&str, &[u8])] = &[
+        (r#"{"method":"isPrime","number":123}"#, FALSE),
+        (r#"{"method":"isPrime","number":7}"#, TRUE),
+        (r#"{"number":2,"method":"isPrime"}"#, TRUE),
+        (r#"{"method":"isPrime","number":1}"#, FALSE),
+        (r#"{"method":"isPrime","number":0}"#, FALSE),
+        (r#"{"method":"isPrime","number":-7}"#, FALSE),
+        // Non-integers are never prime
+        (r#"{"method":"isPrime","number":7.5}"#, FALSE),
+        (r#"{"method":"isPrime","number":1e100}"#, FALSE),
+        (
+            r#"{"method":"isPrime","number":84374637463746374637463746346}"#,
+            FALSE,
+        ),
+        (r#"{"method":"isPrime","number":2147483647}"#, TRUE),
+        // Extra fields are ignored
+        (r#"{"method":"isPrime","number":13,"extra":[1,2]}"#, TRUE),
+    ];
+
+    for (request, response) in cases {
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.write_all(b"\n").await.unwrap();
+        expect(&mut client, response).await;
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn requests_split_across_writes() {
+    let server = prime_time::spawn(LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+
+    client.write_all(b"{\"method\":\"isPr").await.unwrap();
+    client.flush().await.unwrap();
+    client
+        .write_all(b"ime\",\"number\":3}\n{\"method\":\"isPrime\",\"number\":4}\n")
+        .await
+        .unwrap();
+    expect(&mut client, &[TRUE, FALSE].concat()).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn malformed_requests_get_a_malformed_response_and_disconnect() {
+    let server = prime_time::spawn(LOCALHOST).await.unwrap();
+
+    let requests: [&[u8]; 10] = [
+        b"not json",
+        b"{}",
+        br#"{"method":"isPrime"}"#,
+        br#"{"number":7}"#,
+        br#"{"method":"isPrim","number":7}"#,
+        br#"{"method":"isPrime","number":"7"}"#,
+        br#"{"method":"isPrime","number":true}"#,
+        br#"["isPrime",7]"#,
+        // Not UTF-8
+        b"{\"method\":\"isPrime\",\"number\":\xff7}",
+        b"\xc3\x28",
+    ];
+
+    for request in requests {
+        let mut client = connect(&server).await;
+        // Earlier requests are still answered
+        client
+            .write_all(b"{\"method\":\"isPrime\",\"number\":5}\n")
+            .await
+            .unwrap();
+        client.write_all(request).await.unwrap();
+        client.write_all(b"\n").await.unwrap();
+
+        expect(&mut client, TRUE).await;
+        expect(&mut client, b"malformed\n").await;
+        expect_closed(&mut client).await;
+    }
+
+    server.shutdown().await.unwrap();
+}
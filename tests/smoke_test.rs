@@ -0,0 +1,294 @@
+use common::{
+    connect, connect_tls, expect, expect_closed, expect_nothing, self_signed, socket_path,
+    LOCALHOST,
+};
+use protohackers_rs::access::AccessList;
+use protohackers_rs::limiter::{Action, Limiter, Limits};
+use protohackers_rs::service::{ListenOptions, ServerHandle};
+use protohackers_rs::smoke_test::{self, SmokeTest};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+mod common;
+
+#[tokio::test]
+async fn echoes_binary_data_until_closed() {
+    let server = smoke_test::spawn(LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+
+    let data: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+    let (mut reader, mut writer) = client.split();
+    let (_, ()) = tokio::join!(
+        async {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        },
+        expect(&mut reader, &data),
+    );
+    expect_closed(&mut reader).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn serves_clients_at_once() {
+    let server = smoke_test::spawn(LOCALHOST).await.unwrap();
+
+    let mut clients = Vec::new();
+    for i in 0..5u8 {
+        let mut client = connect(&server).await;
+        client.write_all(&[i; 10]).await.unwrap();
+        clients.push(client);
+    }
+    for (i, client) in clients.iter_mut().enumerate() {
+        expect(client, &[i as u8; 10]).await;
+    }
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn closes_connections_over_the_cap() {
+    let limits = Limits {
+        max_connections: Some(2),
+        action: Action::Drop,
+        ..Limits::default()
+    };
+    let limiter = Limiter::new("smoke_test", limits, AccessList::default());
+    let server = ServerHandle::spawn_with(&SmokeTest, LOCALHOST, ListenOptions::default(), limiter)
+        .await
+        .unwrap();
+
+    let mut first = connect(&server).await;
+    let mut second = connect(&server).await;
+    first.write_all(b"first").await.unwrap();
+    expect(&mut first, b"first").await;
+    second.write_all(b"second").await.unwrap();
+    expect(&mut second, b"second").await;
+
+    let mut third = connect(&server).await;
+    expect_closed(&mut third).await;
+
+    // Closing one makes room for another, once the server has noticed
+    drop(first);
+    let mut fourth = loop {
+        let mut client = connect(&server).await;
+        client.write_all(b"fourth").await.unwrap();
+        if client.read_exact(&mut [0; 6]).await.is_ok() {
+            break client;
+        }
+    };
+    fourth.shutdown().await.unwrap();
+    expect_closed(&mut fourth).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn delays_connections_over_the_cap_and_closes_the_rest() {
+    let limits = Limits {
+        max_connections: Some(1),
+        ..Limits::default()
+    };
+    let limiter = Limiter::new("smoke_test", limits, AccessList::default());
+    let server = ServerHandle::spawn_with(&SmokeTest, LOCALHOST, ListenOptions::default(), limiter)
+        .await
+        .unwrap();
+
+    let mut first = connect(&server).await;
+    first.write_all(b"first").await.unwrap();
+    expect(&mut first, b"first").await;
+
+    let mut second = connect(&server).await;
+    second.write_all(b"second").await.unwrap();
+    expect_nothing(&mut second).await;
+
+    // Only as many wait as may be open
+    let mut third = connect(&server).await;
+    expect_closed(&mut third).await;
+
+    drop(first);
+    expect(&mut second, b"second").await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn takes_client_addresses_from_proxy_headers() {
+    let options = ListenOptions {
+        proxy_protocol: true,
+        ..ListenOptions::default()
+    };
+    let access = AccessList {
+        allow: vec!["192.0.2.0/24".parse().unwrap()],
+        deny: Vec::new(),
+    };
+    let limiter = Limiter::new("smoke_test", Limits::default(), access);
+    let server = ServerHandle::spawn_with(&SmokeTest, LOCALHOST, options, limiter)
+        .await
+        .unwrap();
+
+    let mut allowed = connect(&server).await;
+    allowed
+        .write_all(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 10000\r\nhello")
+        .await
+        .unwrap();
+    expect(&mut allowed, b"hello").await;
+
+    // The balancer itself isn't on the allow list, only its clients are
+    let mut denied = connect(&server).await;
+    denied
+        .write_all(b"PROXY TCP4 203.0.113.9 198.51.100.1 56324 10000\r\nhello")
+        .await
+        .unwrap();
+    expect_closed(&mut denied).await;
+
+    let mut missing = connect(&server).await;
+    missing
+        .write_all(b"hello, no header here\r\n")
+        .await
+        .unwrap();
+    expect_closed(&mut missing).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn echoes_over_tls() {
+    let (options, connector) = self_signed();
+    let server = ServerHandle::spawn_with(&SmokeTest, LOCALHOST, options, Limiter::unlimited())
+        .await
+        .unwrap();
+
+    let mut client = connect_tls(&server, &connector).await;
+    client.write_all(b"hello over tls").await.unwrap();
+    expect(&mut client, b"hello over tls").await;
+    client.shutdown().await.unwrap();
+    expect_closed(&mut client).await;
+
+    // Plain text gets no further than the handshake
+    let mut plain = connect(&server).await;
+    plain.write_all(b"hello\n").await.unwrap();
+    let mut alert = Vec::new();
+    let _ = plain.read_to_end(&mut alert).await;
+    assert!(!alert.starts_with(b"hello"));
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_denied_peers_before_the_tls_handshake() {
+    let (options, _) = self_signed();
+    let access = AccessList {
+        allow: Vec::new(),
+        deny: vec!["127.0.0.1/32".parse().unwrap()],
+    };
+    let limiter = Limiter::new("smoke_test", Limits::default(), access);
+    let server = ServerHandle::spawn_with(&SmokeTest, LOCALHOST, options, limiter)
+        .await
+        .unwrap();
+
+    // Closed straight away rather than once the handshake times out
+    let mut client = connect(&server).await;
+    expect_closed(&mut client).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn echoes_over_a_unix_socket() {
+    let path = socket_path("echo");
+    let options = ListenOptions {
+        unix_socket: Some(path.clone()),
+        ..ListenOptions::default()
+    };
+    let server =
+        ServerHandle::spawn_with(&SmokeTest, LOCALHOST, options.clone(), Limiter::unlimited())
+            .await
+            .unwrap();
+
+    let mut client = UnixStream::connect(&path).await.unwrap();
+    client.write_all(b"hello over unix").await.unwrap();
+    expect(&mut client, b"hello over unix").await;
+
+    // TCP is still served alongside
+    let mut tcp = connect(&server).await;
+    tcp.write_all(b"hello").await.unwrap();
+    expect(&mut tcp, b"hello").await;
+
+    client.shutdown().await.unwrap();
+    expect_closed(&mut client).await;
+    server.shutdown().await.unwrap();
+
+    // The socket left behind doesn't stop the path being bound again
+    let server = ServerHandle::spawn_with(&SmokeTest, LOCALHOST, options, Limiter::unlimited())
+        .await
+        .unwrap();
+    let mut client = UnixStream::connect(&path).await.unwrap();
+    client.write_all(b"again").await.unwrap();
+    expect(&mut client, b"again").await;
+    server.shutdown().await.unwrap();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn unix_peers_skip_the_access_list_and_limits() {
+    let path = socket_path("echo-unlimited");
+    let options = ListenOptions {
+        unix_socket: Some(path.clone()),
+        ..ListenOptions::default()
+    };
+    let limits = Limits {
+        max_connections: Some(1),
+        action: Action::Drop,
+        ..Limits::default()
+    };
+    let access = AccessList {
+        allow: vec!["192.0.2.0/24".parse().unwrap()],
+        deny: vec!["127.0.0.1/32".parse().unwrap()],
+    };
+    let limiter = Limiter::new("smoke_test", limits, access);
+    let server = ServerHandle::spawn_with(&SmokeTest, LOCALHOST, options, limiter)
+        .await
+        .unwrap();
+
+    // Neither shut out as 127.0.0.1 nor sharing its one connection
+    let mut first = UnixStream::connect(&path).await.unwrap();
+    let mut second = UnixStream::connect(&path).await.unwrap();
+    first.write_all(b"first").await.unwrap();
+    expect(&mut first, b"first").await;
+    second.write_all(b"second").await.unwrap();
+    expect(&mut second, b"second").await;
+
+    // TCP clients from 127.0.0.1 still are
+    let mut tcp = connect(&server).await;
+    expect_closed(&mut tcp).await;
+
+    server.shutdown().await.unwrap();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn leaves_a_unix_socket_still_in_use() {
+    let path = socket_path("echo-in-use");
+    let options = ListenOptions {
+        unix_socket: Some(path.clone()),
+        ..ListenOptions::default()
+    };
+    let server =
+        ServerHandle::spawn_with(&SmokeTest, LOCALHOST, options.clone(), Limiter::unlimited())
+            .await
+            .unwrap();
+
+    let second =
+        ServerHandle::spawn_with(&SmokeTest, LOCALHOST, options, Limiter::unlimited()).await;
+    assert!(second.is_err());
+
+    // The first server is still reachable at the path
+    let mut client = UnixStream::connect(&path).await.unwrap();
+    client.write_all(b"still here").await.unwrap();
+    expect(&mut client, b"still here").await;
+
+    server.shutdown().await.unwrap();
+    std::fs::remove_file(path).unwrap();
+}
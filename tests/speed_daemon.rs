@@ -0,0 +1,96 @@
+use common::{connect, expect, expect_closed, LOCALHOST};
+use protohackers_rs::speed_daemon;
+use tokio::io::AsyncWriteExt;
+
+mod common;
+
+#[tokio::test]
+async fn example_session() {
+    let server = speed_daemon::spawn(LOCALHOST).await.unwrap();
+
+    let mut camera1 = connect(&server).await;
+    // IAmCamera{road: 123, mile: 8, limit: 60}, Plate{plate: "UN1X", timestamp: 0}
+    camera1
+        .write_all(&[0x80, 0x00, 0x7b, 0x00, 0x08, 0x00, 0x3c])
+        .await
+        .unwrap();
+    camera1
+        .write_all(&[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x00])
+        .await
+        .unwrap();
+
+    let mut camera2 = connect(&server).await;
+    // IAmCamera{road: 123, mile: 9, limit: 60}, Plate{plate: "UN1X", timestamp: 45}
+    camera2
+        .write_all(&[0x80, 0x00, 0x7b, 0x00, 0x09, 0x00, 0x3c])
+        .await
+        .unwrap();
+    camera2
+        .write_all(&[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x2d])
+        .await
+        .unwrap();
+
+    let mut dispatcher = connect(&server).await;
+    // IAmDispatcher{roads: [123]}
+    dispatcher
+        .write_all(&[0x81, 0x01, 0x00, 0x7b])
+        .await
+        .unwrap();
+
+    // Ticket{plate: "UN1X", road: 123, mile1: 8, timestamp1: 0, mile2: 9, timestamp2: 45, speed: 8000}
+    expect(
+        &mut dispatcher,
+        &[
+            0x21, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x7b, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x09, 0x00, 0x00, 0x00, 0x2d, 0x1f, 0x40,
+        ],
+    )
+    .await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn heartbeats() {
+    let server = speed_daemon::spawn(LOCALHOST).await.unwrap();
+    let mut client = connect(&server).await;
+
+    // WantHeartbeat{interval: 1}, every tenth of a second
+    client
+        .write_all(&[0x40, 0x00, 0x00, 0x00, 0x01])
+        .await
+        .unwrap();
+    expect(&mut client, &[0x41, 0x41, 0x41]).await;
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn errors_disconnect_the_client() {
+    let server = speed_daemon::spawn(LOCALHOST).await.unwrap();
+
+    let mut client = connect(&server).await;
+    client.write_all(&[0xff]).await.unwrap();
+    expect(&mut client, b"\x10\x0billegal msg").await;
+    expect_closed(&mut client).await;
+
+    // Only cameras may report plates
+    let mut client = connect(&server).await;
+    client
+        .write_all(&[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x00])
+        .await
+        .unwrap();
+    expect(&mut client, b"\x10\x0cnot a camera").await;
+    expect_closed(&mut client).await;
+
+    // Heartbeats may only be requested once
+    let mut client = connect(&server).await;
+    client
+        .write_all(&[0x40, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00])
+        .await
+        .unwrap();
+    expect(&mut client, b"\x10\x1bheartbeat already requested").await;
+    expect_closed(&mut client).await;
+
+    server.shutdown().await.unwrap();
+}
@@ -0,0 +1,57 @@
+use common::{exchange, expect_no_packet, udp_client, LOCALHOST};
+use protohackers_rs::unusual_database;
+use std::time::Duration;
+
+mod common;
+
+#[tokio::test]
+async fn insert_and_retrieve() {
+    let server = unusual_database::spawn(LOCALHOST).await.unwrap();
+    let client = udp_client(&server).await;
+
+    // Inserts get no reply
+    client.send(b"foo=bar").await.unwrap();
+    expect_no_packet(&client, Duration::from_millis(100)).await;
+    assert_eq!(exchange(&client, "foo").await, "foo=bar");
+
+    client.send(b"foo=bar=baz").await.unwrap();
+    assert_eq!(exchange(&client, "foo").await, "foo=bar=baz");
+
+    client.send(b"foo=").await.unwrap();
+    assert_eq!(exchange(&client, "foo").await, "foo=");
+
+    client.send(b"=empty key").await.unwrap();
+    assert_eq!(exchange(&client, "").await, "=empty key");
+
+    client.send(b"===").await.unwrap();
+    assert_eq!(exchange(&client, "").await, "===");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn version_cannot_be_changed() {
+    let server = unusual_database::spawn(LOCALHOST).await.unwrap();
+    let client = udp_client(&server).await;
+
+    client.send(b"version=hacked").await.unwrap();
+    assert_eq!(
+        exchange(&client, "version").await,
+        "version=Ken's Key-Value Store 1.0"
+    );
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn values_are_shared_between_clients() {
+    let server = unusual_database::spawn(LOCALHOST).await.unwrap();
+    let first = udp_client(&server).await;
+    let second = udp_client(&server).await;
+
+    first.send(b"shared=value").await.unwrap();
+    expect_no_packet(&first, Duration::from_millis(100)).await;
+    assert_eq!(exchange(&second, "shared").await, "shared=value");
+
+    server.shutdown().await.unwrap();
+}